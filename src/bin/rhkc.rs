@@ -5,8 +5,8 @@ use std::os::unix::net::UnixStream;
 use std::time::Duration;
 
 use rhkd::rhkc::ipc::{
-    self, BindCommand, Commands, IpcCommand, SubscribeCommand, SubscribeEventMask, Subscription,
-    UnbindCommand,
+    self, BindCommand, Commands, CyclesCommand, IpcCommand, OutputCommand, SubscribeCommand,
+    SubscribeEventMask, Subscription, UnbindCommand,
 };
 
 #[derive(Parser, Debug)]
@@ -106,6 +106,38 @@ fn unbind(u: UnbindCommand, quiet: bool) -> Result<(), std::io::Error> {
     Ok(())
 }
 
+fn dump() -> Result<(), std::io::Error> {
+    let mut conn = connect()?;
+    let bytes: Vec<u8> = IpcCommand::Dump.into();
+    conn.write_all(&bytes)?;
+    std::io::copy(&mut conn, &mut std::io::stdout())?;
+    Ok(())
+}
+
+fn running() -> Result<(), std::io::Error> {
+    let mut conn = connect()?;
+    let bytes: Vec<u8> = IpcCommand::Running.into();
+    conn.write_all(&bytes)?;
+    std::io::copy(&mut conn, &mut std::io::stdout())?;
+    Ok(())
+}
+
+fn output(o: OutputCommand) -> Result<(), std::io::Error> {
+    let mut conn = connect()?;
+    let bytes: Vec<u8> = IpcCommand::Output(o).into();
+    conn.write_all(&bytes)?;
+    std::io::copy(&mut conn, &mut std::io::stdout())?;
+    Ok(())
+}
+
+fn cycles(c: CyclesCommand) -> Result<(), std::io::Error> {
+    let mut conn = connect()?;
+    let bytes: Vec<u8> = IpcCommand::Cycles(c).into();
+    conn.write_all(&bytes)?;
+    std::io::copy(&mut conn, &mut std::io::stdout())?;
+    Ok(())
+}
+
 fn main() -> Result<(), std::io::Error> {
     let cli = Cli::parse();
     match cli.command {
@@ -117,5 +149,9 @@ fn main() -> Result<(), std::io::Error> {
         }
         Commands::Bind(b) => bind(b, cli.quiet),
         Commands::Unbind(c) => unbind(c, cli.quiet),
+        Commands::Dump => dump(),
+        Commands::Cycles(c) => cycles(c),
+        Commands::Running => running(),
+        Commands::Output(o) => output(o),
     }
 }
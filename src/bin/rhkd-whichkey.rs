@@ -4,8 +4,10 @@ use std::os::unix::net::UnixStream;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 
+use rhkd::keyboard;
 use rhkd::parser::config::load_config;
-use rhkd::parser::{self, Chord, Hotkey};
+use rhkd::parser::diagnostic::{print_diagnostics, DiagnosticFormat};
+use rhkd::parser::{self, Chord, Hotkey, Mode, Prefix, DEFAULT_MODE};
 use rhkd::CliArguments;
 
 use gtk::{
@@ -42,6 +44,11 @@ where
     result
 }
 
+// The tag a group of continuations is shown under
+fn group_tag(group: &[&Hotkey]) -> Option<Arc<str>> {
+    group.iter().find_map(|hk| hk.tags.first()).cloned()
+}
+
 fn build_grid(event: &KeyEvent) -> gtk::Grid {
     let main_grid = gtk::Grid::builder().build();
 
@@ -50,10 +57,19 @@ fn build_grid(event: &KeyEvent) -> gtk::Grid {
     let lock = "";
     let arrow = "";
 
+    let prefix_title = |chain: &[Chord]| {
+        event
+            .prefixes
+            .iter()
+            .find(|p| p.titles(chain))
+            .map(|p| p.title.clone())
+    };
+
     let window_title = &event
-        .config
-        .iter()
-        .find_map(|hk| hk.title.clone())
+        .title
+        .clone()
+        .or_else(|| prefix_title(&event.keys))
+        .or_else(|| event.config.iter().find_map(|hk| hk.title.clone()))
         .unwrap_or_else(|| {
             let mut title = String::new();
             let locking_source = &event.config[0];
@@ -69,12 +85,18 @@ fn build_grid(event: &KeyEvent) -> gtk::Grid {
             title.into()
         });
 
-    let current_hotkey = gtk::Label::new(Some(window_title));
+    let window_title = match event.count {
+        Some(count) => format!("{} × {}", count, window_title),
+        None => window_title.to_string(),
+    };
+    let current_hotkey = gtk::Label::new(Some(&window_title));
     current_hotkey.set_widget_name("path");
 
-    let grouped = group_by(&event.config, |hk: &Hotkey| {
+    let mut grouped = group_by(&event.config, |hk: &Hotkey| {
         hk.chain[event.current_index].repr.trim()
     });
+    // Continuations with the same tag are shown together, below a header with the tag
+    grouped.sort_by_key(|group| group_tag(group));
     let chunks = grouped.chunks(10);
     let n_chunks = chunks.len() as i32;
 
@@ -88,8 +110,18 @@ fn build_grid(event: &KeyEvent) -> gtk::Grid {
             main_grid.attach(&sep, 1 + column * 2, 2, 1, 1);
         }
 
-        for (row, group) in chunk.iter().enumerate() {
-            let row = row as i32;
+        let mut row = 0;
+        let mut previous_tag = None;
+        for group in chunk.iter() {
+            let tag = group_tag(group);
+            if tag.is_some() && tag != previous_tag {
+                let header = gtk::Label::new(tag.as_deref());
+                header.set_widget_name("tag");
+                header.set_halign(gtk::Align::Start);
+                completion_grid.attach(&header, 0, row, 3, 1);
+                row += 1;
+            }
+            previous_tag = tag;
 
             let (keys, desc) = if group.len() == 1 {
                 // There is exactly one continuation -- Show the expanded command
@@ -101,7 +133,10 @@ fn build_grid(event: &KeyEvent) -> gtk::Grid {
                     .map(|ch| ch.repr.trim())
                     .collect::<Vec<_>>()
                     .join(&format!(" {} ", arrow));
-                let command = hotkey.description();
+                let command = match hotkey.icon {
+                    Some(ref icon) => format!("{} {}", icon, hotkey.description()),
+                    None => hotkey.description().to_string(),
+                };
 
                 let continuation = gtk::Label::new(Some(&continuation));
                 continuation.set_widget_name("path");
@@ -116,9 +151,9 @@ fn build_grid(event: &KeyEvent) -> gtk::Grid {
                     .iter()
                     .filter(|g| g.chain.get(event.current_index + 1).is_some())
                     .collect::<Vec<_>>();
-                let command = relevant
-                    .iter()
-                    .find_map(|g| g.title.as_deref().map(|s| s.to_string()))
+                let command = prefix_title(&group[0].chain[..=event.current_index])
+                    .or_else(|| relevant.iter().find_map(|g| g.title.clone()))
+                    .map(|s| s.to_string())
                     .unwrap_or_else(|| {
                         relevant
                             .iter()
@@ -144,6 +179,7 @@ fn build_grid(event: &KeyEvent) -> gtk::Grid {
             completion_grid.attach(&triangle, 1, row, 1, 1);
             desc.set_halign(gtk::Align::Start);
             completion_grid.attach(&desc, 2, row, 1, 1);
+            row += 1;
         }
     }
 
@@ -189,25 +225,63 @@ fn build_ui(application: &gtk::Application) {
         }
         let args = CliArguments::default();
         let config_path = args.config_path.as_deref();
-        let mut config = load_config(config_path).expect("Failed to load config.");
+        let mut config =
+            load_config(config_path, keyboard::keymap()).expect("Failed to load config.");
+        print_diagnostics(config.diagnostics(), args.diagnostic_format);
         let fifo = args.status_fifo.clone();
 
-        fn do_reload(config: &mut parser::config::Config) {
+        fn do_reload(config: &mut parser::config::Config, format: DiagnosticFormat) {
             match config.reload() {
-                Ok(c) => *config = c,
+                Ok(c) => {
+                    print_diagnostics(c.diagnostics(), format);
+                    *config = c
+                }
                 Err(e) => eprintln!("Error reloading config: {}", e),
             }
         }
 
+        // The prefix titles of a mode, or of the bindings outside of any mode
+        fn mode_prefixes(config: &parser::config::Config, mode: Option<&Arc<str>>) -> Vec<Prefix> {
+            config
+                .prefixes()
+                .iter()
+                .filter(|p| p.mode.as_ref() == mode)
+                .cloned()
+                .collect()
+        }
+
+        // An overview of the bindings in a mode, or nothing if the mode has no bindings
+        fn mode_event(config: &parser::config::Config, mode: &Arc<str>) -> Event {
+            let hotkeys: Vec<Hotkey> = config
+                .get_hotkeys()
+                .iter()
+                .filter(|hk| hk.mode.as_ref() == Some(mode) && !hk.hidden)
+                .cloned()
+                .collect();
+            if hotkeys.is_empty() {
+                return Event::ChainEnded;
+            }
+            Event::KeyEvent(KeyEvent {
+                config: hotkeys,
+                keys: vec![],
+                current_index: 0,
+                title: Some(mode.clone()),
+                prefixes: mode_prefixes(config, Some(mode)),
+                count: None,
+            })
+        }
+
         fn read_lines<R: Read>(
             reader: BufReader<R>,
             config: &mut parser::config::Config,
+            mode: &mut Option<Arc<str>>,
             sender: glib::Sender<Event>,
             reload: Arc<AtomicBool>,
+            format: DiagnosticFormat,
         ) {
             for mut line in reader.lines().flatten() {
                 if reload.swap(false, std::sync::atomic::Ordering::Relaxed) {
-                    do_reload(config);
+                    do_reload(config, format);
                 }
 
                 match line.as_bytes()[0..2] {
@@ -238,6 +312,11 @@ fn build_ui(application: &gtk::Application) {
                     'H' => Stroke::Hotkey(line),
                     'C' => Stroke::Command(line),
                     'R' => Stroke::Reload,
+                    'M' => Stroke::Mode(line),
+                    'P' => Stroke::PrefixAdded(line),
+                    'Q' => Stroke::PrefixRemoved(line),
+                    'O' => Stroke::ModeAdded(line),
+                    'K' => Stroke::ModeRemoved(line),
                     x => {
                         eprintln!("Failed to parse line {}{}", x, line);
                         continue;
@@ -245,18 +324,72 @@ fn build_ui(application: &gtk::Application) {
                 };
 
                 let err = match stroke {
-                    Stroke::Reload => {
-                        do_reload(config);
-                        reload.swap(false, std::sync::atomic::Ordering::Relaxed);
+                    // rhkd sends the changed modes, bindings and prefix titles before the reload
+                    // event, and they were applied when they arrived
+                    Stroke::Reload => continue,
+                    Stroke::ModeAdded(ref message) | Stroke::ModeRemoved(ref message) => {
+                        let event = rhkd::rhkd::ModeEvent::parse(message);
+                        let modes = config.modes_mut();
+                        modes.retain(|m| m.name != event.name);
+                        if let Stroke::ModeAdded(_) = stroke {
+                            modes.push(Mode {
+                                name: event.name,
+                                abort: event.abort,
+                                source: None,
+                            });
+                        }
                         continue;
                     }
+                    Stroke::PrefixAdded(ref message) | Stroke::PrefixRemoved(ref message) => {
+                        let event = rhkd::rhkd::PrefixEvent::parse(message);
+                        let chain = match parser::parse_chord_chain(&event.chain, config.keymap()) {
+                            Ok(chain) => chain,
+                            Err(e) => {
+                                eprintln!("Failed to parse keys from {}: {}", event.chain, e);
+                                continue;
+                            }
+                        };
+                        let prefix = Prefix {
+                            chain: chain.into(),
+                            title: event.title,
+                            mode: Some(event.mode).filter(|m| &**m != DEFAULT_MODE),
+                            source: None,
+                        };
+                        let prefixes = config.prefixes_mut();
+                        if let Stroke::PrefixAdded(_) = stroke {
+                            prefixes.push(prefix);
+                        } else if let Some(i) = prefixes.iter().position(|p| {
+                            p.mode == prefix.mode
+                                && p.title == prefix.title
+                                && p.titles(&prefix.chain)
+                        }) {
+                            prefixes.remove(i);
+                        }
+                        continue;
+                    }
+                    Stroke::Mode(name) => {
+                        *mode = Some(name.into()).filter(|m: &Arc<str>| &**m != DEFAULT_MODE);
+                        match mode {
+                            Some(m) => sender.send(mode_event(config, m)),
+                            None => sender.send(Event::ChainEnded),
+                        }
+                    }
                     Stroke::BeginChain(_) => sender.send(Event::ChainStarted),
-                    Stroke::EndChain(_) => sender.send(Event::ChainEnded),
+                    // While a mode is active, its bindings are shown between chains
+                    Stroke::EndChain(_) => match mode {
+                        Some(m) => sender.send(mode_event(config, m)),
+                        None => sender.send(Event::ChainEnded),
+                    },
                     Stroke::Hotkey(ref hotkey_string) => {
-                        match parser::parse_chord_chain(hotkey_string) {
+                        let event = rhkd::rhkd::HotkeyEvent::parse(hotkey_string);
+                        let (hotkey_string, count) = (&*event.chain, event.count);
+                        match parser::parse_chord_chain(hotkey_string, config.keymap()) {
                             Ok(chords) => {
-                                let hotkeys =
-                                    find_hotkeys_for_chords(config.get_hotkeys(), &chords);
+                                let hotkeys: Vec<_> =
+                                    find_hotkeys_for_chords(config.get_hotkeys(), &chords)
+                                        .into_iter()
+                                        .filter(|hk| hk.mode == *mode)
+                                        .collect();
                                 if hotkeys.is_empty() {
                                     continue;
                                 }
@@ -264,6 +397,9 @@ fn build_ui(application: &gtk::Application) {
                                     config: hotkeys.into_iter().cloned().collect(),
                                     keys: chords.clone(),
                                     current_index: chords.len(),
+                                    title: mode.clone(),
+                                    prefixes: mode_prefixes(config, mode.as_ref()),
+                                    count,
                                 });
                                 sender.send(event)
                             }
@@ -283,6 +419,7 @@ fn build_ui(application: &gtk::Application) {
             }
         }
 
+        let mut mode = None;
         loop {
             if let Some(ref fifo) = fifo {
                 let f = std::fs::File::open(fifo);
@@ -293,7 +430,14 @@ fn build_ui(application: &gtk::Application) {
                 };
                 let reader = BufReader::new(f);
                 println!("Fifo connected!");
-                read_lines(reader, &mut config, sender.clone(), reload_config.clone());
+                read_lines(
+                    reader,
+                    &mut config,
+                    &mut mode,
+                    sender.clone(),
+                    reload_config.clone(),
+                    args.diagnostic_format,
+                );
             } else {
                 use ipc::SubscribeEventMask;
                 let socket = UnixStream::connect(get_socket_path());
@@ -319,7 +463,14 @@ fn build_ui(application: &gtk::Application) {
                 }
                 let reader = BufReader::new(socket);
                 println!("Socket connected!");
-                read_lines(reader, &mut config, sender.clone(), reload_config.clone());
+                read_lines(
+                    reader,
+                    &mut config,
+                    &mut mode,
+                    sender.clone(),
+                    reload_config.clone(),
+                    args.diagnostic_format,
+                );
             };
             std::thread::sleep(std::time::Duration::from_secs(1));
         }
@@ -375,7 +526,8 @@ fn find_hotkeys_for_chords<'a>(source: &'a [Hotkey], chain: &[Chord]) -> Vec<&'a
     source
         .iter()
         .filter(|hk| {
-            hk.chain.len() > chain.len()
+            !hk.hidden
+                && hk.chain.len() > chain.len()
                 && chain
                     .iter()
                     .zip(hk.chain.iter())
@@ -396,6 +548,12 @@ pub struct KeyEvent {
     pub config: Vec<Hotkey>,
     pub keys: Vec<Chord>,
     pub current_index: usize,
+    /// Shown instead of the active chain, e.g. the name of the active mode
+    pub title: Option<Arc<str>>,
+    /// Titles for prefixes of the chains in `config`
+    pub prefixes: Vec<Prefix>,
+    /// The count typed inside the chain, if any
+    pub count: Option<u32>,
 }
 
 enum Stroke {
@@ -404,5 +562,10 @@ enum Stroke {
     BeginChain(String),
     EndChain(String),
     Timeout(String),
+    Mode(String),
+    PrefixAdded(String),
+    PrefixRemoved(String),
+    ModeAdded(String),
+    ModeRemoved(String),
     Reload,
 }
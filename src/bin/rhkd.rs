@@ -1,10 +1,19 @@
-fn main() -> anyhow::Result<()> {
+use std::process::ExitCode;
+
+fn main() -> anyhow::Result<ExitCode> {
     let config = rhkd::CliArguments::default();
+    if config.check {
+        return Ok(if rhkd::rhkd::check(&config) {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        });
+    }
     match rhkd::rhkd::start(config) {
         Err(e) => {
             eprintln!("rhkd stopped due to error: {}", e);
             Err(e)
         }
-        _ => Ok(()),
+        _ => Ok(ExitCode::SUCCESS),
     }
 }
@@ -0,0 +1,123 @@
+//! Button grabs with a raw button number. The protocol accepts any button in GrabButton and
+//! UngrabButton, but xcb only names the first five, so these requests are sent through libxcb.
+
+use xcb::ffi::xcb_connection_t;
+use xcb::{x, Xid};
+
+#[repr(C)]
+struct VoidCookie {
+    sequence: u32,
+}
+
+#[link(name = "xcb")]
+extern "C" {
+    fn xcb_grab_button(
+        c: *mut xcb_connection_t,
+        owner_events: u8,
+        grab_window: u32,
+        event_mask: u16,
+        pointer_mode: u8,
+        keyboard_mode: u8,
+        confine_to: u32,
+        cursor: u32,
+        button: u8,
+        modifiers: u16,
+    ) -> VoidCookie;
+    fn xcb_grab_button_checked(
+        c: *mut xcb_connection_t,
+        owner_events: u8,
+        grab_window: u32,
+        event_mask: u16,
+        pointer_mode: u8,
+        keyboard_mode: u8,
+        confine_to: u32,
+        cursor: u32,
+        button: u8,
+        modifiers: u16,
+    ) -> VoidCookie;
+    fn xcb_ungrab_button(
+        c: *mut xcb_connection_t,
+        button: u8,
+        grab_window: u32,
+        modifiers: u16,
+    ) -> VoidCookie;
+    fn xcb_ungrab_button_checked(
+        c: *mut xcb_connection_t,
+        button: u8,
+        grab_window: u32,
+        modifiers: u16,
+    ) -> VoidCookie;
+}
+
+/// Grab presses and releases of a button with the pointer frozen until they are allowed. Button 0
+/// means any button.
+pub(super) struct GrabButton {
+    pub grab_window: x::Window,
+    pub button: u8,
+    pub modifiers: x::ModMask,
+}
+
+unsafe impl xcb::RawRequest for GrabButton {
+    fn raw_request(&self, conn: &xcb::Connection, checked: bool) -> u64 {
+        let request = if checked {
+            xcb_grab_button_checked
+        } else {
+            xcb_grab_button
+        };
+        let cookie = unsafe {
+            request(
+                conn.get_raw_conn(),
+                0,
+                self.grab_window.resource_id(),
+                (x::EventMask::BUTTON_PRESS | x::EventMask::BUTTON_RELEASE).bits() as u16,
+                x::GrabMode::Sync as u8,
+                x::GrabMode::Async as u8,
+                x::Window::none().resource_id(),
+                x::Cursor::none().resource_id(),
+                self.button,
+                self.modifiers.bits() as u16,
+            )
+        };
+        cookie.sequence as u64
+    }
+}
+
+impl xcb::Request for GrabButton {
+    type Cookie = xcb::VoidCookie;
+    const IS_VOID: bool = true;
+}
+
+impl xcb::RequestWithoutReply for GrabButton {}
+
+/// Release a grab made with [GrabButton]
+pub(super) struct UngrabButton {
+    pub grab_window: x::Window,
+    pub button: u8,
+    pub modifiers: x::ModMask,
+}
+
+unsafe impl xcb::RawRequest for UngrabButton {
+    fn raw_request(&self, conn: &xcb::Connection, checked: bool) -> u64 {
+        let request = if checked {
+            xcb_ungrab_button_checked
+        } else {
+            xcb_ungrab_button
+        };
+        let cookie = unsafe {
+            request(
+                conn.get_raw_conn(),
+                self.button,
+                self.grab_window.resource_id(),
+                self.modifiers.bits() as u16,
+            )
+        };
+        cookie.sequence as u64
+    }
+}
+
+impl xcb::Request for UngrabButton {
+    type Cookie = xcb::VoidCookie;
+    const IS_VOID: bool = true;
+}
+
+impl xcb::RequestWithoutReply for UngrabButton {}
@@ -1,21 +1,55 @@
 use std::collections::HashMap;
 pub use xcb::x::ModMask;
-use xcb::{x, ProtocolResult};
+use xcb::{x, xkb, xtest, BaseEvent, ProtocolResult, Xid};
 
+mod buttons;
 mod keysyms;
 use x::Allow::*;
 
 use anyhow::{Context, Result};
 
 lazy_static! {
-    static ref KEYBOARD: Keyboard = Keyboard::new().unwrap();
+    static ref KEYBOARD: Result<Keyboard> = Keyboard::new();
 }
 
+/// The highest pointer button which can be used in a binding, e.g. 'button24'
+pub const MAX_BUTTON: u8 = 24;
+
 pub struct Keyboard {
     root: xcb::x::Window,
     conn: xcb::Connection,
     keycode_lookup: HashMap<u32, Vec<u8>>,
+    /// The reverse of `keycode_lookup`
+    keysym_lookup: HashMap<u8, Vec<u32>>,
+    /// The key which produces each keysym with the fewest modifiers, and the index of the keysym
+    /// on that key. 0 needs no modifier and 1 needs shift, e.g. for 'A' or 'exclam'. Higher
+    /// indices need another group or level 3 (AltGr).
+    levels: HashMap<u32, (u8, usize)>,
     mods: x::GetModifierMappingReply,
+    net_active_window: x::Atom,
+}
+
+/// The WM_CLASS property of a window
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct WindowClass {
+    pub instance: String,
+    pub class: String,
+}
+
+impl WindowClass {
+    // WM_CLASS holds the instance and the class as two null-terminated strings
+    fn parse(value: &[u8]) -> Option<WindowClass> {
+        if value.is_empty() {
+            return None;
+        }
+        let mut parts = value
+            .split(|c| *c == 0)
+            .map(|s| String::from_utf8_lossy(s).to_string());
+        Some(WindowClass {
+            instance: parts.next()?,
+            class: parts.next().unwrap_or_default(),
+        })
+    }
 }
 
 impl Keyboard {
@@ -48,6 +82,42 @@ impl Keyboard {
         self.allow_events(ReplayPointer)
     }
 
+    /// The window which has focus, according to _NET_ACTIVE_WINDOW on the root window. If the
+    /// window manager does not set it, the input focus is used instead.
+    fn focused_window(&self) -> xcb::Result<x::Window> {
+        let active = self.conn.send_request(&x::GetProperty {
+            delete: false,
+            window: self.root,
+            property: self.net_active_window,
+            r#type: x::ATOM_WINDOW,
+            long_offset: 0,
+            long_length: 1,
+        });
+        let active = self.conn.wait_for_reply(active)?;
+        match active.value::<x::Window>().first() {
+            Some(window) if !window.is_none() => Ok(*window),
+            _ => {
+                let focus = self.conn.send_request(&x::GetInputFocus {});
+                Ok(self.conn.wait_for_reply(focus)?.focus())
+            }
+        }
+    }
+
+    /// The WM_CLASS of the focused window, or `None` if no window with a class has focus
+    pub fn focused_window_class(&self) -> Option<WindowClass> {
+        let window = self.focused_window().ok()?;
+        let class = self.conn.send_request(&x::GetProperty {
+            delete: false,
+            window,
+            property: x::ATOM_WM_CLASS,
+            r#type: x::ATOM_STRING,
+            long_offset: 0,
+            long_length: 256,
+        });
+        let class = self.conn.wait_for_reply(class).ok()?;
+        WindowClass::parse(class.value::<u8>())
+    }
+
     pub fn keysym_from_keycode(&self, keycode: u8) -> Option<&'static str> {
         self.keycode_lookup
             .iter()
@@ -77,74 +147,15 @@ impl Keyboard {
         }
         modfield
     }
-    fn modfield_from_keysym(&self, keysym: &str) -> u32 {
-        let mut modfield = 0;
-        if let Ok(keycodes) = self.get_keycodes_from_string(keysym) {
-            for keycode in keycodes {
-                modfield |= self.modfield_from_keycode(keycode);
-            }
-        }
-        modfield
-    }
-
-    fn modfield_from_mods(&self, modifiers: &[&str]) -> anyhow::Result<ModMask> {
-        let mut modmask = 0;
-        for modifier in modifiers {
-            modmask |= self
-                .modifier_from_string(modifier)
-                .with_context(|| {
-                    let similar = if let Some(similar) = keysyms::get_closest_modifier(modifier) {
-                        format!(", but '{}' is similar", similar)
-                    } else {
-                        "".into()
-                    };
-                    format!("Unknown modifier '{}'{}.", modifier, similar)
-                })?
-                .bits();
-        }
-        ModMask::from_bits(modmask).context(format!(
-            "Failed to create modmask from modfield 0x{:X}",
-            modmask
-        ))
-    }
-
-    fn modifier_from_string(&self, s: &str) -> Result<ModMask> {
-        let opt = match s {
-            "shift" => ModMask::SHIFT.into(),
-            "control" | "ctrl" => ModMask::CONTROL.into(),
-            "alt" => ModMask::from_bits(
-                self.modfield_from_keysym("Alt_L") | self.modfield_from_keysym("Alt_R"),
-            ),
-            "super" => ModMask::from_bits(
-                self.modfield_from_keysym("Super_L") | self.modfield_from_keysym("Super_R"),
-            ),
-            "hyper" => ModMask::from_bits(
-                self.modfield_from_keysym("Hyper_L") | self.modfield_from_keysym("Hyper_R"),
-            ),
-            "meta" => ModMask::from_bits(
-                self.modfield_from_keysym("Meta_L") | self.modfield_from_keysym("Meta_R"),
-            ),
-            "mode_switch" => ModMask::from_bits(self.modfield_from_keysym("Mode_switch")),
-            "mod1" => ModMask::N1.into(),
-            "mod2" => ModMask::N2.into(),
-            "mod3" => ModMask::N3.into(),
-            "mod4" => ModMask::N4.into(),
-            "mod5" => ModMask::N5.into(),
-            "lock" => ModMask::LOCK.into(),
-            "any" => ModMask::ANY.into(),
-            _ => Err(KeyError::UnknownModifier(
-                s.to_string(),
-                keysyms::get_closest_modifier(s).map(|t| t.to_string()),
-            ))?,
-        };
-        let mask = opt.with_context(|| format!("Failed to create modmask from '{}'", s))?;
-        Ok(mask)
-    }
-
     pub fn get_keycodes(&self, o: u32) -> Option<Vec<u8>> {
         self.keycode_lookup.get(&o).cloned()
     }
 
+    /// Every keysym produced by a key, at any shift level
+    pub fn keysyms_from_keycode(&self, keycode: u8) -> &[u32] {
+        self.keysym_lookup.get(&keycode).map_or(&[], |k| k)
+    }
+
     pub fn get_keycodes_from_string(&self, s: &str) -> Result<Vec<u8>> {
         keysyms::symbol_from_string(s)
             .and_then(|o| self.get_keycodes(o))
@@ -158,6 +169,113 @@ impl Keyboard {
             })
     }
 
+    /// The first key which activates each modifier of `modifiers`
+    fn modifier_keycodes(&self, modifiers: ModMask) -> Result<Vec<u8>> {
+        // The mapping lists the same number of keys for each of the eight modifiers
+        let mod_keycodes = self.mods.keycodes();
+        let keycodes_per_modifier = (mod_keycodes.len() / 8).max(1);
+        mod_keycodes
+            .chunks(keycodes_per_modifier)
+            .enumerate()
+            .map(|(i, keycodes)| (ModMask::from_bits_truncate(1 << i), keycodes))
+            .filter(|(modifier, _)| modifiers.contains(*modifier))
+            .map(|(modifier, keycodes)| {
+                keycodes
+                    .iter()
+                    .copied()
+                    .find(|kc| *kc != 0)
+                    .with_context(|| format!("No key sets the modifier {:?}", modifier))
+            })
+            .collect()
+    }
+
+    /// The keys which are held down
+    fn held_keycodes(&self) -> xcb::Result<Vec<u8>> {
+        let keymap = self.conn.send_request(&x::QueryKeymap {});
+        let keymap = self.conn.wait_for_reply(keymap)?;
+        let keys = keymap.keys();
+        Ok((0..=u8::MAX)
+            .filter(|kc| keys[*kc as usize / 8] & (1 << (kc % 8)) != 0)
+            .collect())
+    }
+
+    fn fake_key(&self, keycode: u8, press: bool) {
+        let r#type = if press {
+            x::KeyPressEvent::NUMBER
+        } else {
+            x::KeyReleaseEvent::NUMBER
+        };
+        self.conn.send_request(&xtest::FakeInput {
+            r#type: r#type as u8,
+            detail: keycode,
+            time: x::CURRENT_TIME,
+            root: self.root,
+            root_x: 0,
+            root_y: 0,
+            deviceid: 0,
+        });
+    }
+
+    /// Press and release each keysym with its modifiers through XTest. The keys which are held
+    /// down, like the chord of the binding which sends them, are released first so the focused
+    /// window does not see them along with the sent keys. The modifiers are pressed again
+    /// afterwards. Keys which are grabbed receive the sent keys like any others, so the caller
+    /// ungrabs them first. Keysyms which need AltGr or another layout group are an error.
+    pub fn send_keys(&self, keys: &[(u32, ModMask)]) -> Result<()> {
+        if !self
+            .conn
+            .active_extensions()
+            .any(|e| e == xcb::Extension::Test)
+        {
+            anyhow::bail!("The XTEST extension is not available");
+        }
+        // Every key is looked up first, so nothing is sent if one of them is missing
+        let keys = keys
+            .iter()
+            .map(|&(keysym, mut modifiers)| {
+                let name = || match keysyms::keycode_to_string(keysym) {
+                    Some(name) => format!("'{}'", name),
+                    None => format!("the keysym 0x{:x}", keysym),
+                };
+                let Some(&(keycode, level)) = self.levels.get(&keysym) else {
+                    anyhow::bail!("No key produces {}", name());
+                };
+                match level {
+                    0 => {}
+                    1 => modifiers |= ModMask::SHIFT,
+                    // The modifier which selects these depends on the layout
+                    _ => anyhow::bail!(
+                        "{} needs AltGr or another layout group, which cannot be sent",
+                        name()
+                    ),
+                }
+                Ok((keycode, self.modifier_keycodes(modifiers)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let held = self.held_keycodes()?;
+        for &keycode in &held {
+            self.fake_key(keycode, false);
+        }
+        for (keycode, modifiers) in keys {
+            for &modifier in &modifiers {
+                self.fake_key(modifier, true);
+            }
+            self.fake_key(keycode, true);
+            self.fake_key(keycode, false);
+            for &modifier in modifiers.iter().rev() {
+                self.fake_key(modifier, false);
+            }
+        }
+        for &keycode in &held {
+            if self.modfield_from_keycode(keycode) != 0 {
+                self.fake_key(keycode, true);
+            }
+        }
+        self.conn.flush()?;
+        Ok(())
+    }
+
     pub fn grab_many(&self, keys: &[(u8, xcb::x::ModMask)]) -> Vec<ProtocolResult<()>> {
         keys.iter()
             .copied()
@@ -191,6 +309,33 @@ impl Keyboard {
             .context("Key is already grabbed")
     }
 
+    pub fn grab_buttons(&self, buttons: &[(u8, xcb::x::ModMask)]) -> Vec<ProtocolResult<()>> {
+        buttons
+            .iter()
+            .copied()
+            .map(|(button, modifiers)| {
+                self.conn.send_request_checked(&buttons::GrabButton {
+                    grab_window: self.root,
+                    button,
+                    modifiers,
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|c| self.conn.check_request(c))
+            .collect()
+    }
+
+    pub fn ungrab_button(&self, button: u8, modifiers: xcb::x::ModMask) -> xcb::Result<()> {
+        let request = buttons::UngrabButton {
+            grab_window: self.root,
+            button,
+            modifiers,
+        };
+        self.conn.send_and_check_request(&request)?;
+        Ok(())
+    }
+
     pub fn ungrab(&self, key: u8, modifiers: xcb::x::ModMask) -> xcb::Result<()> {
         let request = xcb::x::UngrabKey {
             key,
@@ -202,16 +347,118 @@ impl Keyboard {
     }
     pub fn ungrab_all(&self) -> xcb::Result<()> {
         self.ungrab(xcb::x::GRAB_ANY, xcb::x::ModMask::ANY)?;
+        self.ungrab_button(0, xcb::x::ModMask::ANY)?;
         Ok(())
     }
 }
 
+/// Resolves modifier names such as 'super' to a modifier mask. Which modifier a key activates
+/// depends on the keymap of the X server, so configs are parsed against a `Keymap`.
+pub trait Keymap: Sync {
+    /// The modifiers activated by the keys which produce `keysym`
+    fn modfield_from_keysym(&self, keysym: &str) -> u32;
+
+    fn modfield_from_mods(&self, modifiers: &[&str]) -> anyhow::Result<ModMask> {
+        let mut modmask = 0;
+        for modifier in modifiers {
+            modmask |= self
+                .modifier_from_string(modifier)
+                .with_context(|| {
+                    let similar = if let Some(similar) = keysyms::get_closest_modifier(modifier) {
+                        format!(", but '{}' is similar", similar)
+                    } else {
+                        "".into()
+                    };
+                    format!("Unknown modifier '{}'{}.", modifier, similar)
+                })?
+                .bits();
+        }
+        ModMask::from_bits(modmask).context(format!(
+            "Failed to create modmask from modfield 0x{:X}",
+            modmask
+        ))
+    }
+
+    fn modifier_from_string(&self, s: &str) -> Result<ModMask> {
+        let opt = match s {
+            "shift" => ModMask::SHIFT.into(),
+            "control" | "ctrl" => ModMask::CONTROL.into(),
+            "alt" => ModMask::from_bits(
+                self.modfield_from_keysym("Alt_L") | self.modfield_from_keysym("Alt_R"),
+            ),
+            "super" => ModMask::from_bits(
+                self.modfield_from_keysym("Super_L") | self.modfield_from_keysym("Super_R"),
+            ),
+            "hyper" => ModMask::from_bits(
+                self.modfield_from_keysym("Hyper_L") | self.modfield_from_keysym("Hyper_R"),
+            ),
+            "meta" => ModMask::from_bits(
+                self.modfield_from_keysym("Meta_L") | self.modfield_from_keysym("Meta_R"),
+            ),
+            "mode_switch" => ModMask::from_bits(self.modfield_from_keysym("Mode_switch")),
+            "mod1" => ModMask::N1.into(),
+            "mod2" => ModMask::N2.into(),
+            "mod3" => ModMask::N3.into(),
+            "mod4" => ModMask::N4.into(),
+            "mod5" => ModMask::N5.into(),
+            "lock" => ModMask::LOCK.into(),
+            "any" => ModMask::ANY.into(),
+            _ => Err(KeyError::UnknownModifier(
+                s.to_string(),
+                keysyms::get_closest_modifier(s).map(|t| t.to_string()),
+            ))?,
+        };
+        let mask = opt.with_context(|| format!("Failed to create modmask from '{}'", s))?;
+        Ok(mask)
+    }
+}
+
+impl Keymap for Keyboard {
+    fn modfield_from_keysym(&self, keysym: &str) -> u32 {
+        let mut modfield = 0;
+        if let Ok(keycodes) = self.get_keycodes_from_string(keysym) {
+            for keycode in keycodes {
+                modfield |= self.modfield_from_keycode(keycode);
+            }
+        }
+        modfield
+    }
+}
+
+/// The modifier mapping of the default X keyboard layout. Used for parsing when there is no X
+/// server to ask, e.g. when checking a config on a headless machine.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultKeymap;
+
+impl Keymap for DefaultKeymap {
+    fn modfield_from_keysym(&self, keysym: &str) -> u32 {
+        let modmask = match keysym {
+            "Shift_L" | "Shift_R" => ModMask::SHIFT,
+            "Caps_Lock" => ModMask::LOCK,
+            "Control_L" | "Control_R" => ModMask::CONTROL,
+            "Alt_L" | "Alt_R" | "Meta_L" | "Meta_R" => ModMask::N1,
+            "Num_Lock" => ModMask::N2,
+            "Super_L" | "Super_R" | "Hyper_L" | "Hyper_R" => ModMask::N4,
+            "ISO_Level3_Shift" | "Mode_switch" => ModMask::N5,
+            _ => ModMask::empty(),
+        };
+        modmask.bits()
+    }
+}
+
 impl Keyboard {
     pub fn connection(&self) -> &xcb::Connection {
         &self.conn
     }
     pub fn new() -> anyhow::Result<Keyboard> {
-        let (conn, screen_num) = xcb::Connection::connect(None)?;
+        let (conn, screen_num) = xcb::Connection::connect_with_extensions(
+            None,
+            &[],
+            &[xcb::Extension::Xkb, xcb::Extension::Test],
+        )?;
+        if !enable_detectable_autorepeat(&conn) {
+            eprintln!("XKB detectable autorepeat is not available, key repeats are guessed");
+        }
         let setup = conn.get_setup();
         let root = setup.roots().nth(screen_num as usize).unwrap().root();
 
@@ -233,6 +480,7 @@ impl Keyboard {
         let n_keycodes = n_keysyms / kpk;
 
         let mut keycode_lookup: HashMap<u32, Vec<u8>> = Default::default();
+        let mut levels: HashMap<u32, (u8, usize)> = HashMap::new();
         for keycode_idx in 0..n_keycodes {
             let keycode = keycode_idx + (min_kc as usize);
             // print!("0x{:<3X} {}", keycode, keycode);
@@ -249,26 +497,85 @@ impl Keyboard {
                         let v: Vec<u8> = vec![keycode as u8];
                         keycode_lookup.insert(sym, v);
                     }
+                    let level = levels.entry(sym).or_insert((keycode as u8, keysym_idx));
+                    if keysym_idx < level.1 {
+                        *level = (keycode as u8, keysym_idx);
+                    }
                 }
             }
             // println!();
         }
 
+        let mut keysym_lookup: HashMap<u8, Vec<u32>> = Default::default();
+        for (&sym, keycodes) in keycode_lookup.iter() {
+            for &keycode in keycodes {
+                keysym_lookup.entry(keycode).or_default().push(sym);
+            }
+        }
+
         let mods = x::GetModifierMapping {};
         let mods = conn.send_request(&mods);
         let mods = conn.wait_for_reply(mods)?;
 
+        let net_active_window = conn.send_request(&x::InternAtom {
+            only_if_exists: false,
+            name: b"_NET_ACTIVE_WINDOW",
+        });
+        let net_active_window = conn.wait_for_reply(net_active_window)?.atom();
+
         Ok(Keyboard {
             conn,
             root,
             keycode_lookup,
+            keysym_lookup,
+            levels,
             mods,
+            net_active_window,
         })
     }
 }
 
+/// Make X send the repeats of a held key as presses without releases in between, so they can be
+/// told apart from the key being pressed again
+fn enable_detectable_autorepeat(conn: &xcb::Connection) -> bool {
+    if !conn.active_extensions().any(|e| e == xcb::Extension::Xkb) {
+        return false;
+    }
+    let cookie = conn.send_request(&xkb::UseExtension {
+        wanted_major: 1,
+        wanted_minor: 0,
+    });
+    if !conn.wait_for_reply(cookie).is_ok_and(|r| r.supported()) {
+        return false;
+    }
+    let cookie = conn.send_request(&xkb::PerClientFlags {
+        device_spec: xkb::Id::UseCoreKbd as xkb::DeviceSpec,
+        change: xkb::PerClientFlag::DETECTABLE_AUTO_REPEAT,
+        value: xkb::PerClientFlag::DETECTABLE_AUTO_REPEAT,
+        ctrls_to_change: xkb::BoolCtrl::empty(),
+        auto_ctrls: xkb::BoolCtrl::empty(),
+        auto_ctrls_values: xkb::BoolCtrl::empty(),
+    });
+    conn.wait_for_reply(cookie).is_ok_and(|r| {
+        r.value()
+            .contains(xkb::PerClientFlag::DETECTABLE_AUTO_REPEAT)
+    })
+}
+
 pub fn kbd() -> &'static Keyboard {
-    &KEYBOARD
+    match KEYBOARD.as_ref() {
+        Ok(keyboard) => keyboard,
+        Err(e) => panic!("Failed to connect to the X server: {:#}", e),
+    }
+}
+
+/// The keymap configs are parsed against: the keymap of the X server if one is available, and
+/// the default layout otherwise.
+pub fn keymap() -> &'static dyn Keymap {
+    match KEYBOARD.as_ref() {
+        Ok(keyboard) => keyboard,
+        Err(_) => &DefaultKeymap,
+    }
 }
 
 #[derive(Debug)]
@@ -307,9 +614,29 @@ pub fn symbol_from_string(s: &str) -> Result<u32> {
     }
 }
 
-pub fn modfield_from_mods(modifiers: &[&str]) -> anyhow::Result<ModMask> {
-    KEYBOARD.modfield_from_mods(modifiers)
+/// The name of a keysym, e.g. 'Return' for 0xff0d
+pub fn string_from_symbol(keysym: u32) -> Option<&'static str> {
+    keysyms::keycode_to_string(keysym)
 }
+
+/// The keysym which types a character. Latin-1 characters are their own keysym, and the rest
+/// of Unicode is offset by 0x1000000.
+pub fn keysym_from_char(c: char) -> u32 {
+    match c {
+        '\n' => 0xff0d,
+        '\t' => 0xff09,
+        ' '..='~' | '\u{a0}'..='\u{ff}' => c as u32,
+        c => 0x0100_0000 + c as u32,
+    }
+}
+
+/// Parse a pointer button name such as 'button3'
+pub fn button_from_string(s: &str) -> Option<u8> {
+    s.strip_prefix("button")
+        .and_then(|b| b.parse::<u8>().ok())
+        .filter(|b| (1..=MAX_BUTTON).contains(b))
+}
+
 pub fn modfield_from_keysym(keysym: &str) -> u32 {
-    KEYBOARD.modfield_from_keysym(keysym)
+    kbd().modfield_from_keysym(keysym)
 }
@@ -7,6 +7,7 @@ pub mod rhkc;
 pub mod rhkd;
 
 use clap::Parser;
+use parser::diagnostic::DiagnosticFormat;
 
 #[derive(Parser, Debug, Clone)]
 #[command(
@@ -24,7 +25,7 @@ pub struct CliArguments {
     #[arg(short = 'a', long = "abort-keysym", default_value = Some("Escape"))]
     pub abort_keysym: Option<String>,
     /// Redirect the commands output to the given file.
-    #[arg(short = 'r', long = "redir-file")]
+    #[arg(short = 'r', long = "redir-file", conflicts_with = "log_dir")]
     pub redir_file: Option<String>,
     /// Timeout in seconds for the recording of chord chains.
     #[arg(short = 't', long = "timeout", default_value_t = 3)]
@@ -40,6 +41,42 @@ pub struct CliArguments {
     /// with a bash script by using rhkc.
     #[arg(short = 'c', long = "config-path")]
     pub config_path: Option<String>,
+    /// Check the configuration for errors and exit, without grabbing any keys. The exit code is
+    /// non-zero if the configuration contains errors.
+    #[arg(long = "check", default_value_t = false)]
+    pub check: bool,
+    /// How errors in the configuration are printed
+    #[arg(long = "diagnostic-format", value_enum, default_value_t = DiagnosticFormat::Human)]
+    pub diagnostic_format: DiagnosticFormat,
+    /// Start a cycle over at its first command if it was not used for this many seconds
+    #[arg(long = "cycle-reset")]
+    pub cycle_reset: Option<u64>,
+    /// Run commands with this shell. Defaults to $SHELL, or bash if it is not set.
+    #[arg(long = "shell")]
+    pub shell: Option<String>,
+    /// The arguments passed to the shell before the command, split like a shell would split them
+    #[arg(
+        long = "shell-args",
+        default_value = "-c",
+        allow_hyphen_values = true,
+        value_parser = parse_shell_args
+    )]
+    pub shell_args: String,
+    /// Write the output of each command to a log in this directory, tagged with the time and the
+    /// hotkey which started the command
+    #[arg(long = "log-dir")]
+    pub log_dir: Option<String>,
+    /// Start a new log once it grows past this many KiB. A few old logs are kept.
+    #[arg(long = "log-size", default_value_t = 1024)]
+    pub log_size: u64,
+    /// How many lines of output are kept for commands which fail, see 'rhkc output'
+    #[arg(long = "output-lines", default_value_t = 20)]
+    pub output_lines: usize,
+}
+
+fn parse_shell_args(args: &str) -> anyhow::Result<String> {
+    rhkd::split_words(args)?;
+    Ok(args.to_string())
 }
 
 impl Default for CliArguments {
@@ -47,3 +84,34 @@ impl Default for CliArguments {
         CliArguments::parse()
     }
 }
+
+/// A directory for the files of a test, which is removed when the test ends, even if it fails
+#[cfg(test)]
+pub(crate) struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub(crate) fn new(name: &str) -> std::io::Result<TempDir> {
+        let dir = std::env::temp_dir().join(format!("rhkd_{}_{}", name, std::process::id()));
+        // Left over by an earlier run which was killed
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        Ok(TempDir(dir))
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
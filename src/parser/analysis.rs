@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+
+use super::diagnostic::{Diagnostic, Severity};
+use super::{chain_repr, Chord, Condition, Hotkey};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictKind {
+    /// Two hotkeys have the same chain. Only the first one is triggered.
+    Duplicate,
+    /// A shorter hotkey is a prefix of this one, and is triggered before the chain can continue
+    Unreachable,
+    /// Two hotkeys share a prefix, but only one of them locks the chain after it. Since the
+    /// chain locks if any matching hotkey locks it, both hotkeys lock.
+    LockMismatch,
+    /// The same key is bound both with and without '~'. Since the key is replayed if any matching
+    /// hotkey replays it, both hotkeys replay it.
+    ReplayMismatch,
+}
+
+/// A problem with a hotkey, which is caused by another hotkey
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub kind: ConflictKind,
+    /// The hotkey which does not behave as written
+    pub hotkey: Hotkey,
+    /// The hotkey which causes the problem
+    pub other: Hotkey,
+    /// The number of chords in the chain of `hotkey` which are involved in the conflict
+    pub prefix: usize,
+}
+
+fn location(hotkey: &Hotkey) -> String {
+    hotkey
+        .source
+        .as_ref()
+        .map_or("a binding added at runtime".into(), |s| s.to_string())
+}
+
+impl Conflict {
+    /// A warning pointing at the binding of `hotkey`
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let chain = self.hotkey.chain_repr();
+        let other = self.other.chain_repr();
+        let prefix = chain_repr(&self.hotkey.chain[..self.prefix]);
+        let (message, hint) = match self.kind {
+            ConflictKind::Duplicate => (
+                format!("'{}' is bound more than once", chain),
+                "only the first binding is triggered",
+            ),
+            ConflictKind::Unreachable => (
+                format!(
+                    "'{}' can never be triggered, because '{}' is triggered first",
+                    chain, other
+                ),
+                "a hotkey cannot be both a complete binding and the start of a chain",
+            ),
+            ConflictKind::LockMismatch => (
+                format!(
+                    "'{}' locks the chain in only one of '{}' and '{}'",
+                    prefix, chain, other
+                ),
+                "the chain is locked for both bindings",
+            ),
+            ConflictKind::ReplayMismatch => (
+                format!(
+                    "'{}' is replayed with '~' in only one of '{}' and '{}'",
+                    prefix, chain, other
+                ),
+                "the key is replayed for both bindings",
+            ),
+        };
+        Diagnostic {
+            hint: Some(format!(
+                "{}. '{}' is bound at {}",
+                hint,
+                other,
+                location(&self.other)
+            )),
+            ..Diagnostic::at(Severity::Warning, message, self.hotkey.source.as_deref())
+        }
+    }
+}
+
+// The parts of a chord which decide which key events it matches
+type ChordKey = (u32, u8, u32, bool);
+
+fn chord_key(chord: &Chord) -> ChordKey {
+    (
+        chord.keysym,
+        chord.button,
+        chord.modfield.bits(),
+        chord.event_type.is_key_press(),
+    )
+}
+
+fn chain_key(chain: &[Chord]) -> Vec<ChordKey> {
+    chain.iter().map(chord_key).collect()
+}
+
+// Hotkeys from the same cycle share a chain, but are not duplicates
+fn same_cycle(a: &Hotkey, b: &Hotkey) -> bool {
+    match (&a.cycle, &b.cycle) {
+        (Some(x), Some(y)) => x.period == y.period && a.source == b.source,
+        _ => false,
+    }
+}
+
+/// Find hotkeys which do not behave the way they are written, because of another hotkey.
+pub fn analyze(hotkeys: &[Hotkey]) -> Vec<Conflict> {
+    // Only the hotkeys of one mode are active at a time, so each mode is checked on its own
+    let mut modes = vec![];
+    for hotkey in hotkeys {
+        if !modes.contains(&&hotkey.mode) {
+            modes.push(&hotkey.mode);
+        }
+    }
+    modes
+        .into_iter()
+        .flat_map(|mode| {
+            let table: Vec<_> = hotkeys
+                .iter()
+                .filter(|hk| hk.mode == *mode)
+                .cloned()
+                .collect();
+            analyze_table(&table)
+        })
+        .collect()
+}
+
+fn analyze_table(hotkeys: &[Hotkey]) -> Vec<Conflict> {
+    let mut result = vec![];
+    let conflict = |kind, hotkey: &Hotkey, other: &Hotkey, prefix| Conflict {
+        kind,
+        hotkey: hotkey.clone(),
+        other: other.clone(),
+        prefix,
+    };
+
+    // The first hotkey with each chain
+    let mut terminals: HashMap<Vec<ChordKey>, &Hotkey> = HashMap::new();
+    // The first hotkey with each chain and set of conditions. Hotkeys with the same chain are
+    // only duplicates if they apply to the same windows.
+    let mut duplicates: HashMap<(Vec<ChordKey>, &[Condition]), &Hotkey> = HashMap::new();
+    // The first hotkey with each prefix
+    let mut prefixes: HashMap<Vec<ChordKey>, &Hotkey> = HashMap::new();
+    // The first hotkey which continues after each prefix. The lock of the last chord in a chain
+    // has no effect, so only these are compared for locks.
+    let mut locks: HashMap<Vec<ChordKey>, &Hotkey> = HashMap::new();
+
+    for hotkey in hotkeys {
+        let key = chain_key(&hotkey.chain);
+        match duplicates.get(&(key.clone(), &hotkey.conditions[..])) {
+            Some(first) if !same_cycle(first, hotkey) => {
+                result.push(conflict(ConflictKind::Duplicate, hotkey, first, key.len()));
+            }
+            Some(_) => {}
+            None => {
+                duplicates.insert((key.clone(), &hotkey.conditions), hotkey);
+            }
+        }
+        terminals.entry(key.clone()).or_insert(hotkey);
+
+        for len in 1..=key.len() {
+            let prefix = &key[..len];
+            let chord = &hotkey.chain[len - 1];
+            match prefixes.get(prefix) {
+                Some(first) if !same_cycle(first, hotkey) => {
+                    if chord.replay_event != first.chain[len - 1].replay_event {
+                        result.push(conflict(ConflictKind::ReplayMismatch, hotkey, first, len));
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    prefixes.insert(prefix.to_vec(), hotkey);
+                }
+            }
+            if len == key.len() {
+                continue;
+            }
+            match locks.get(prefix) {
+                Some(first) => {
+                    if chord.lock_chain != first.chain[len - 1].lock_chain {
+                        result.push(conflict(ConflictKind::LockMismatch, hotkey, first, len));
+                    }
+                }
+                None => {
+                    locks.insert(prefix.to_vec(), hotkey);
+                }
+            }
+        }
+    }
+
+    // A chain which continues past a complete hotkey can never be reached. This is checked
+    // after all terminals are known, since the order of the bindings does not matter.
+    for hotkey in hotkeys {
+        let key = chain_key(&hotkey.chain);
+        let shortest = (1..key.len()).find_map(|len| terminals.get(&key[..len]).map(|t| (len, t)));
+        if let Some((len, terminal)) = shortest {
+            result.push(conflict(ConflictKind::Unreachable, hotkey, terminal, len));
+        }
+    }
+    result
+}
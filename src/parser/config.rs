@@ -1,27 +1,42 @@
 use crate::{
-    parser::*,
+    keyboard::Keymap,
+    parser::{
+        cycles::CycleState,
+        diagnostic::{Diagnostic, Severity},
+        index::HotkeyIndex,
+        variables::Variables,
+        *,
+    },
     rhkc::ipc::{BindCommand, UnbindCommand},
 };
 use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use thiserror::Error;
 
 pub struct Config {
     path: Option<String>,
+    /// The directory of drop-in files which were loaded after the main config
+    drop_in_dir: Option<PathBuf>,
+    files: Vec<PathBuf>,
     hotkeys: Vec<Hotkey>,
-}
-
-#[derive(Error, Debug)]
-pub enum CycleError {
-    #[error("Provided hotkey is not a cycle.")]
-    NotACycle,
-    #[error("Provided cycle was not found.")]
-    CycleNotFound,
+    modes: Vec<Mode>,
+    prefixes: Vec<Prefix>,
+    /// A trie of the chains of `hotkeys`, which has to be updated whenever they change
+    index: HotkeyIndex,
+    /// Which command of each cycle runs next. It is carried over to the reloaded config.
+    cycles: CycleState,
+    diagnostics: Vec<Diagnostic>,
+    /// The keymap the config was parsed against. Bindings added later are parsed against it too.
+    keymap: &'static dyn Keymap,
 }
 
 #[derive(Debug, Error)]
 pub enum AddBindingError {
     #[error("Hotkey not added because it would interfere with an existing hotkey. Current: {current}, new: {new}")]
     WouldInterfere { current: String, new: String },
+    #[error("{0}")]
+    Invalid(Diagnostic),
 }
 
 pub struct AddBindingsResult {
@@ -31,34 +46,70 @@ pub struct AddBindingsResult {
 }
 
 impl Config {
-    fn get_first_interfering(new: &Hotkey, set: &[Hotkey]) -> Option<usize> {
-        set.iter().position(|hk| {
-            hk.chain
-                .iter()
-                .zip(new.chain.iter())
-                .all(|(a, b)| a.eq_relaxed(b))
-        })
+    fn reindex(&mut self) {
+        self.index = HotkeyIndex::build(&self.hotkeys);
     }
-    fn is_prefix_of(short: &[Chord], long: &[Chord]) -> bool {
-        if short.len() > long.len() {
-            return false;
-        }
-        for (s, l) in short.iter().zip(long) {
-            if !s.eq_relaxed(l) {
-                return false;
-            }
-        }
-        true
+
+    // The hotkeys `new` would interfere with, i.e. hotkeys in the same mode and for the same
+    // windows, where one chain is a prefix of the other
+    fn get_interfering(&self, new: &Hotkey) -> Vec<usize> {
+        let path = self.index.path(new.mode.as_deref(), &new.chain);
+        // Chains which are not longer end on the path, and longer chains continue from its end
+        let shorter = path.iter().flat_map(|node| node.terminals.iter());
+        let longer = path
+            .last()
+            .filter(|_| path.len() == new.chain.len())
+            .map_or(&[][..], |node| &node.hotkeys[..]);
+        let mut interfering: Vec<usize> = shorter
+            .chain(longer)
+            .copied()
+            .filter(|&i| self.hotkeys[i].conditions == new.conditions)
+            .collect();
+        interfering.sort_unstable();
+        interfering.dedup();
+        interfering
     }
-    pub fn delete_bindings(&mut self, unbind: &UnbindCommand) -> anyhow::Result<Vec<Hotkey>> {
-        let chords = crate::parser::parse_chord_chain(&unbind.hotkey)?;
-        let (remove, keep): (Vec<_>, Vec<_>) = self
-            .hotkeys
-            .clone()
+
+    // Remove the hotkeys at the given sorted positions
+    fn remove_hotkeys(&mut self, positions: &[usize]) -> Vec<Hotkey> {
+        let (remove, keep): (Vec<_>, Vec<_>) = std::mem::take(&mut self.hotkeys)
             .into_iter()
-            .partition(|e| Self::is_prefix_of(&chords, &e.chain));
-        self.hotkeys = keep;
-        Ok(remove)
+            .enumerate()
+            .partition(|(i, _)| positions.binary_search(i).is_ok());
+        self.hotkeys = keep.into_iter().map(|(_, hk)| hk).collect();
+        self.reindex();
+        self.cycles.retain(&self.hotkeys);
+        remove.into_iter().map(|(_, hk)| hk).collect()
+    }
+
+    pub fn delete_bindings(&mut self, unbind: &UnbindCommand) -> anyhow::Result<Vec<Hotkey>> {
+        let chords = crate::parser::parse_chord_chain(&unbind.hotkey, self.keymap)?;
+        let modes: Vec<Option<&str>> = match unbind.mode.as_deref() {
+            Some(DEFAULT_MODE) => vec![None],
+            Some(mode) => vec![Some(mode)],
+            None => std::iter::once(None)
+                .chain(self.modes.iter().map(|m| Some(&*m.name)))
+                .collect(),
+        };
+        let mut positions: Vec<usize> = vec![];
+        for node in modes.iter().filter_map(|m| self.index.find(*m, &chords)) {
+            positions.extend(match unbind.command {
+                // Only the binding with exactly this chain and these conditions
+                Some(ref command) => node
+                    .terminals
+                    .iter()
+                    .filter(|&&i| &*self.hotkeys[i].command == command)
+                    .filter(|&&i| {
+                        let conditions = self.hotkeys[i].conditions.iter().map(|c| c.to_string());
+                        conditions.eq(unbind.conditions.iter().cloned())
+                    })
+                    .copied()
+                    .collect(),
+                None => node.hotkeys.clone(),
+            });
+        }
+        positions.sort_unstable();
+        Ok(self.remove_hotkeys(&positions))
     }
 
     pub fn add_bindings(&mut self, bind: &BindCommand) -> anyhow::Result<AddBindingsResult> {
@@ -68,32 +119,78 @@ impl Config {
             errors: vec![],
         };
         let mut binding_text = String::new();
-        if let Some(ref title) = bind.title {
-            binding_text.push_str(&format!("# {}\n", title));
-        }
         if let Some(ref description) = bind.description {
             binding_text.push_str(&format!("# {}\n", description));
         }
+        if let Some(ref title) = bind.title {
+            binding_text.push_str(&format!("#@ title: {}\n", title));
+        }
+        if !bind.conditions.is_empty() {
+            binding_text.push_str(&format!("#@ when {}\n", bind.conditions.join(" ")));
+        }
+        if !bind.tags.is_empty() {
+            binding_text.push_str(&format!("#@ tags: {}\n", bind.tags.join(", ")));
+        }
+        if let Some(ref icon) = bind.icon {
+            binding_text.push_str(&format!("#@ icon: {}\n", icon));
+        }
+        if bind.hidden {
+            binding_text.push_str("#@ hidden\n");
+        }
+        match bind.count {
+            Some(CountMode::Pass) => binding_text.push_str("#@ count\n"),
+            Some(CountMode::Repeat) => binding_text.push_str("#@ count: repeat\n"),
+            None => {}
+        }
+        if let Some(timeout) = bind.timeout {
+            binding_text.push_str(&format!("#@ timeout: {}\n", timeout));
+        }
+        if bind.exec {
+            binding_text.push_str("#@ exec\n");
+        }
+        if let Some(repeat) = bind.repeat {
+            binding_text.push_str(&format!("#@ repeat: {}\n", repeat));
+        }
         binding_text.push_str(&format!("{}\n", bind.hotkey));
         binding_text.push_str(&format!("  {}\n", bind.command));
 
-        let new = load_config_from_bytes(binding_text.as_bytes())?;
-        let new_hotkeys = new.hotkeys;
+        // The new binding can switch to any mode of this config
+        let mut loader = ConfigLoader::new(self.keymap);
+        loader.modes = self.modes.clone();
+        loader.load_bytes(binding_text.as_bytes(), None, Variables::default())?;
+        let new = loader.into_config(None);
+        result
+            .errors
+            .extend(new.diagnostics.into_iter().map(AddBindingError::Invalid));
+        let mut new_hotkeys = new.hotkeys;
+
+        let mode: Option<Arc<str>> = bind
+            .mode
+            .as_deref()
+            .filter(|m| *m != DEFAULT_MODE)
+            .map(|m| m.into());
+        if let Some(ref name) = mode {
+            if self.get_mode(name).is_none() {
+                self.modes.push(Mode {
+                    name: name.clone(),
+                    abort: None,
+                    source: None,
+                });
+            }
+        }
+        for hk in new_hotkeys.iter_mut() {
+            hk.mode = mode.clone();
+        }
 
         // If overwrite is set, remove all interfering keys
         if bind.overwrite {
-            self.hotkeys.retain(|this| {
-                let retain = !new_hotkeys.iter().any(|hk| {
-                    this.chain
-                        .iter()
-                        .zip(hk.chain.iter())
-                        .all(|(a, b)| a.eq_relaxed(b))
-                });
-                if !retain {
-                    result.removed.push(this.clone());
-                }
-                retain
-            });
+            let mut positions: Vec<usize> = new_hotkeys
+                .iter()
+                .flat_map(|hk| self.get_interfering(hk))
+                .collect();
+            positions.sort_unstable();
+            positions.dedup();
+            result.removed = self.remove_hotkeys(&positions);
         }
 
         for hk in new_hotkeys.into_iter() {
@@ -107,9 +204,8 @@ impl Config {
                     })
                 )
             {
-                let current = &self.hotkeys;
-                if let Some(idx) = Self::get_first_interfering(&hk, current) {
-                    let current = &current[idx];
+                if let Some(&idx) = self.get_interfering(&hk).first() {
+                    let current = &self.hotkeys[idx];
                     result.errors.push(AddBindingError::WouldInterfere {
                         current: current.chain_repr(),
                         new: hk.chain_repr(),
@@ -118,14 +214,11 @@ impl Config {
                 }
             }
             result.added.push(hk.clone());
+            self.index.insert(self.hotkeys.len(), &hk);
             self.hotkeys.push(hk);
         }
         Ok(result)
     }
-    pub fn get_hotkeys_mut(&mut self) -> &mut Vec<Hotkey> {
-        &mut self.hotkeys
-    }
-
     pub fn into_hotkeys(self) -> Vec<Hotkey> {
         self.hotkeys
     }
@@ -133,90 +226,401 @@ impl Config {
         &self.hotkeys
     }
 
-    pub fn reload(&mut self) -> Result<Config> {
-        if self.path.is_none() {
-            Ok(Config {
-                path: None,
-                hotkeys: vec![],
-            })
-        } else {
-            load_config(self.path.as_deref())
-        }
+    /// A trie of the chains of the hotkeys, whose nodes refer to positions in `get_hotkeys`
+    pub fn index(&self) -> &HotkeyIndex {
+        &self.index
     }
 
-    pub fn cycle_hotkey(&mut self, hk: &Hotkey) -> Result<(), CycleError> {
-        if hk.cycle.is_none() {
-            return Err(CycleError::NotACycle);
-        };
+    /// Every file which contributed to this config, including the main file
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// Errors in the config. The offending bindings are skipped, and the rest of the config is
+    /// still loaded.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// The directory whose '*.rc' files are loaded after the main config file
+    pub fn drop_in_dir(&self) -> Option<PathBuf> {
+        self.drop_in_dir.clone()
+    }
+
+    pub fn keymap(&self) -> &'static dyn Keymap {
+        self.keymap
+    }
+
+    pub fn modes(&self) -> &[Mode] {
+        &self.modes
+    }
 
-        let start = self
+    pub fn modes_mut(&mut self) -> &mut Vec<Mode> {
+        &mut self.modes
+    }
+
+    pub fn get_mode(&self, name: &str) -> Option<&Mode> {
+        self.modes.iter().find(|m| &*m.name == name)
+    }
+
+    /// Titles for the start of chains, set on binding lines without a command
+    pub fn prefixes(&self) -> &[Prefix] {
+        &self.prefixes
+    }
+
+    pub fn prefixes_mut(&mut self) -> &mut Vec<Prefix> {
+        &mut self.prefixes
+    }
+
+    /// The title of the prefix `chain` in `mode`. The first prefix wins if there are several.
+    pub fn prefix_title(&self, mode: Option<&str>, chain: &[Chord]) -> Option<Arc<str>> {
+        self.prefixes
+            .iter()
+            .find(|p| p.mode.as_deref() == mode && p.titles(chain))
+            .map(|p| p.title.clone())
+    }
+
+    /// Find hotkeys which are shadowed by, or change the behavior of, other hotkeys
+    pub fn analyze(&self) -> Vec<analysis::Conflict> {
+        analysis::analyze(&self.hotkeys)
+    }
+
+    /// Write the hotkeys back as rhkdrc text, collapsing variants into brace groups
+    pub fn to_rhkdrc(&self) -> String {
+        serializer::to_rhkdrc(&self.hotkeys, &self.modes, &self.prefixes, self.keymap)
+    }
+
+    /// The hotkeys of every cycle whose chain starts with `hotkey`, in `mode` or in any mode
+    pub fn find_cycles(&self, hotkey: Option<&str>, mode: Option<&str>) -> Result<Vec<&Hotkey>> {
+        let chords = match hotkey {
+            Some(hotkey) => crate::parser::parse_chord_chain(hotkey, self.keymap)?,
+            None => vec![],
+        };
+        let mode = mode.map(|m| Some(m).filter(|m| *m != DEFAULT_MODE));
+        Ok(self
             .hotkeys
             .iter()
-            .position(|h| h == hk)
-            .ok_or(CycleError::CycleNotFound)?;
-        let hk = &self.hotkeys[start];
-        let cycle_period = hk
-            .cycle
-            .clone()
-            .map(|c| c.period)
-            .ok_or(CycleError::NotACycle)? as usize;
-
-        // This should cycle the hotkeys so e.g.:
-        // [ 1, 2, 3 ] -> [ 2, 3, 1 ] -> [ 3, 1, 2 ] when this method is called in succession
-        for idx in start..start + cycle_period - 1 {
-            self.hotkeys.swap(idx, idx + 1);
-        }
-        Ok(())
+            .filter(|hk| hk.cycle.is_some())
+            .filter(|hk| mode.is_none_or(|m| hk.mode.as_deref() == m))
+            .filter(|hk| {
+                hk.chain.len() >= chords.len()
+                    && chords
+                        .iter()
+                        .zip(hk.chain.iter())
+                        .all(|(a, b)| a.eq_relaxed(b))
+            })
+            .collect())
+    }
+
+    pub fn cycles(&self) -> &CycleState {
+        &self.cycles
+    }
+
+    pub fn cycles_mut(&mut self) -> &mut CycleState {
+        &mut self.cycles
+    }
+
+    /// Load the config again from its files. Cycles which are still in the config keep their
+    /// position.
+    pub fn reload(&mut self) -> Result<Config> {
+        let mut config = if self.path.is_none() {
+            ConfigLoader::new(self.keymap).into_config(None)
+        } else {
+            let drop_ins = self.drop_in_dir.clone();
+            load_config_with_drop_ins(self.path.as_deref(), drop_ins, self.keymap)?
+        };
+        config.cycles = self.cycles.clone();
+        config.cycles.retain(&config.hotkeys);
+        Ok(config)
     }
 }
 
-pub fn load_config(file: Option<&str>) -> Result<Config> {
+pub fn load_config(file: Option<&str>, keymap: &'static dyn Keymap) -> Result<Config> {
+    load_config_with_drop_ins(file, default_drop_in_dir(), keymap)
+}
+
+/// Load the config from `file`, followed by the `*.rc` files in `drop_in_dir`
+pub(crate) fn load_config_with_drop_ins(
+    file: Option<&str>,
+    drop_in_dir: Option<PathBuf>,
+    keymap: &'static dyn Keymap,
+) -> Result<Config> {
     let path = file
         .map(|s| s.to_string())
         .or_else(|| guess_config_path().ok());
     let Some(path) = path else {
         println!("No config file found. Using empty default config.");
-        return Ok(Config {
-            path: None,
+        return Ok(ConfigLoader::new(keymap).into_config(None));
+    };
+
+    let mut loader = ConfigLoader::new(keymap);
+    loader.load_file(Path::new(&path), Variables::default())?;
+
+    if let Some(dir) = drop_in_dir.as_ref().filter(|d| d.is_dir()) {
+        for file in resolve_include("*.rc", Some(dir))? {
+            loader.load_file(&file, Variables::default())?;
+        }
+    }
+
+    let mut config = loader.into_config(Some(path));
+    config.drop_in_dir = drop_in_dir;
+    Ok(config)
+}
+
+// Drop-in files are always read from ~/.config/rhkd/rhkdrc.d, even if the main config is an
+// sxhkdrc or was given on the command line.
+fn default_drop_in_dir() -> Option<PathBuf> {
+    config_home()
+        .ok()
+        .map(|home| home.join("rhkd").join("rhkdrc.d"))
+}
+
+pub fn load_config_from_bytes(content: &[u8], keymap: &'static dyn Keymap) -> Result<Config> {
+    let mut loader = ConfigLoader::new(keymap);
+    loader.load_bytes(content, None, Variables::default())?;
+    Ok(loader.into_config(None))
+}
+
+/// Loads a config file and everything it includes, in the order the hotkeys appear.
+struct ConfigLoader {
+    /// Files currently being loaded. Used for detecting include cycles.
+    stack: Vec<PathBuf>,
+    files: Vec<PathBuf>,
+    hotkeys: Vec<Hotkey>,
+    modes: Vec<Mode>,
+    prefixes: Vec<Prefix>,
+    diagnostics: Vec<Diagnostic>,
+    keymap: &'static dyn Keymap,
+}
+
+impl ConfigLoader {
+    fn new(keymap: &'static dyn Keymap) -> Self {
+        ConfigLoader {
+            stack: vec![],
+            files: vec![],
             hotkeys: vec![],
+            modes: vec![],
+            prefixes: vec![],
+            diagnostics: vec![],
+            keymap,
+        }
+    }
+
+    // Actions can switch to modes defined in any file, so they are checked once every file is
+    // loaded. Bindings which switch to an unknown mode are skipped.
+    fn into_config(mut self, path: Option<String>) -> Config {
+        let modes = &self.modes;
+        let diagnostics = &mut self.diagnostics;
+        self.hotkeys.retain(|hk| match hk.action {
+            Some(Action::Mode(ref name))
+                if &**name != DEFAULT_MODE && !modes.iter().any(|m| m.name == *name) =>
+            {
+                diagnostics.push(Diagnostic::at(
+                    Severity::Error,
+                    format!("Unknown mode '{}'", name),
+                    hk.source.as_deref(),
+                ));
+                false
+            }
+            _ => true,
         });
-    };
+        let index = HotkeyIndex::build(&self.hotkeys);
+        Config {
+            path,
+            drop_in_dir: None,
+            files: self.files,
+            hotkeys: self.hotkeys,
+            modes: self.modes,
+            prefixes: self.prefixes,
+            index,
+            cycles: CycleState::default(),
+            diagnostics: self.diagnostics,
+            keymap: self.keymap,
+        }
+    }
 
-    let content = std::fs::read(&path).context(format!("Failed to read file '{}'", path))?;
-    load_config_from_bytes(&content)
-        .context(format!("Error while parsing config '{}'", path))
-        .map(|f| Config {
-            path: Some(path),
-            hotkeys: f.hotkeys,
-        })
+    fn load_file(&mut self, path: &Path, variables: Variables) -> Result<()> {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("Failed to read file '{}'", path.display()))?;
+        if let Some(start) = self.stack.iter().position(|p| *p == canonical) {
+            let cycle = self.stack[start..]
+                .iter()
+                .chain(std::iter::once(&canonical))
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            Err(ConfigParseError::IncludeCycle(cycle))?;
+        }
+        // A file which is included from several places is only loaded once
+        if self.files.contains(&canonical) {
+            return Ok(());
+        }
+
+        let content = std::fs::read(path)
+            .with_context(|| format!("Failed to read file '{}'", path.display()))?;
+        self.stack.push(canonical.clone());
+        self.files.push(canonical);
+        let result = self
+            .load_bytes(&content, Some(path), variables)
+            .with_context(|| format!("Error while parsing config '{}'", path.display()));
+        self.stack.pop();
+        result
+    }
+
+    fn load_bytes(
+        &mut self,
+        content: &[u8],
+        path: Option<&Path>,
+        variables: Variables,
+    ) -> Result<()> {
+        let tokens = Scanner::scan(content).map_err(|e| with_diagnostic(e, content, path))?;
+        let tree =
+            token_parser::Parser::build_with_variables(content, &tokens, variables, self.keymap)
+                .map_err(|e| with_diagnostic(e, content, path))?;
+        let (hotkeys, errors) = tree.get_hotkeys();
+        let hotkeys: Vec<Hotkey> = hotkeys
+            .iter()
+            .cloned()
+            .map(|mut hk| {
+                if let (Some(source), Some(path)) = (hk.source.as_mut(), path) {
+                    Arc::make_mut(source).file = Some(path.to_path_buf());
+                }
+                hk
+            })
+            .collect();
+        self.diagnostics.extend(
+            errors
+                .iter()
+                .map(|e| Diagnostic::from_error(e, content, path)),
+        );
+        for mode in tree.get_modes() {
+            let mut mode = mode.clone();
+            if let (Some(source), Some(path)) = (mode.source.as_mut(), path) {
+                Arc::make_mut(source).file = Some(path.to_path_buf());
+            }
+            match self.modes.iter().find(|m| m.name == mode.name) {
+                Some(first) => self.diagnostics.push(Diagnostic {
+                    hint: first
+                        .source
+                        .as_ref()
+                        .map(|s| format!("'{}' is first defined at {}", mode.name, s)),
+                    ..Diagnostic::at(
+                        Severity::Error,
+                        format!("Mode '{}' is already defined", mode.name),
+                        mode.source.as_deref(),
+                    )
+                }),
+                None => self.modes.push(mode),
+            }
+        }
+
+        self.prefixes
+            .extend(tree.get_prefixes().iter().cloned().map(|mut prefix| {
+                if let (Some(source), Some(path)) = (prefix.source.as_mut(), path) {
+                    Arc::make_mut(source).file = Some(path.to_path_buf());
+                }
+                prefix
+            }));
+
+        // Relative includes are resolved from the directory of the including file
+        let base = path.and_then(|p| p.parent());
+        let mut start = 0;
+        for include in tree.get_includes() {
+            self.hotkeys
+                .extend_from_slice(&hotkeys[start..include.index]);
+            start = include.index;
+            // Errors in included files point at the include directive, unless they already
+            // point at something in the included file
+            let include_error = |e: anyhow::Error| {
+                if e.downcast_ref::<Diagnostic>().is_some() {
+                    return e;
+                }
+                let error =
+                    ConfigParseError::InvalidInclude(include.token.clone(), format!("{:#}", e));
+                let diagnostic = Diagnostic::from_parse_error(&error, content, path);
+                e.context(diagnostic)
+            };
+            let files = resolve_include(&include.pattern, base).map_err(include_error)?;
+            for file in files {
+                self.load_file(&file, include.variables.clone())
+                    .map_err(include_error)?;
+            }
+        }
+        self.hotkeys.extend_from_slice(&hotkeys[start..]);
+        Ok(())
+    }
 }
 
-pub fn load_config_from_bytes(content: &[u8]) -> Result<Config> {
-    let tokens = Scanner::scan(content)?;
-    let tree = token_parser::Parser::build(content, &tokens)?;
-    let (hotkeys, errors) = tree.get_hotkeys();
-    for error in errors {
-        if let Some(err) = error.downcast_ref::<ConfigParseError>() {
-            println!("{}", err.contextualize(content));
-        } else {
-            println!("WARNING: {}", error);
+// Attach a diagnostic to an error which stops a file from loading
+fn with_diagnostic(error: anyhow::Error, content: &[u8], path: Option<&Path>) -> anyhow::Error {
+    let diagnostic = Diagnostic::from_error(&error, content, path);
+    error.context(diagnostic)
+}
+
+/// Resolve an include pattern to a sorted list of files. A leading '~' is expanded to the home
+/// directory, and the file name may contain the wildcards '*' and '?'.
+fn resolve_include(pattern: &str, base: Option<&Path>) -> Result<Vec<PathBuf>> {
+    let path = match pattern.strip_prefix("~/") {
+        Some(rest) => {
+            let home = std::env::var("HOME").context("HOME is not set")?;
+            Path::new(&home).join(rest)
         }
+        None => PathBuf::from(pattern),
+    };
+    let path = match base {
+        Some(base) if path.is_relative() => base.join(path),
+        _ => path,
+    };
+
+    let file_name = path
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .context("Include path has no file name")?;
+    if !file_name.contains(['*', '?']) {
+        return Ok(vec![path]);
     }
 
-    Ok(Config {
-        path: None,
-        hotkeys: hotkeys.to_vec(),
-    })
+    let mut glob = String::from("^");
+    for ch in file_name.chars() {
+        match ch {
+            '*' => glob.push_str(".*"),
+            '?' => glob.push('.'),
+            ch => glob.push_str(&regex::escape(&ch.to_string())),
+        }
+    }
+    glob.push('$');
+    let glob = regex::Regex::new(&glob)?;
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let mut files: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .with_context(|| format!("Failed to read directory '{}'", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.is_file()
+                && p.file_name()
+                    .is_some_and(|f| glob.is_match(&f.to_string_lossy()))
+        })
+        .collect();
+    files.sort();
+    Ok(files)
 }
 
-fn guess_config_path() -> anyhow::Result<String> {
-    let config_home = if let Ok(config_home) = std::env::var("XDG_CONFIG_HOME") {
-        std::path::PathBuf::from(config_home)
+fn config_home() -> anyhow::Result<PathBuf> {
+    if let Ok(config_home) = std::env::var("XDG_CONFIG_HOME") {
+        Ok(PathBuf::from(config_home))
     } else if let Ok(home) = std::env::var("HOME") {
-        std::path::Path::new(&home).join(".config")
+        Ok(Path::new(&home).join(".config"))
     } else {
         bail!("Unable to find config file. Neither HOME or XDG_CONFIG_HOME is set.")
-    };
+    }
+}
+
+fn guess_config_path() -> anyhow::Result<String> {
+    let config_home = config_home()?;
     let candidates = [("rhkd", "rhkdrc"), ("sxhkd", "sxhkdrc")];
     let path = candidates.iter().find_map(move |(dir, filename)| {
         let path = config_home.join(dir).join(filename);
@@ -0,0 +1,95 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::index::ChordKey;
+use super::{Condition, Hotkey};
+
+// The commands of a cycle are the hotkeys with the same keys, mode and conditions, so its
+// position outlives the hotkeys themselves
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CycleKey {
+    mode: Option<Arc<str>>,
+    chain: Vec<ChordKey>,
+    conditions: Arc<[Condition]>,
+}
+
+impl From<&Hotkey> for CycleKey {
+    fn from(hotkey: &Hotkey) -> Self {
+        CycleKey {
+            mode: hotkey.mode.clone(),
+            chain: hotkey.chain.iter().map(ChordKey::from).collect(),
+            conditions: hotkey.conditions.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    next: i32,
+    used: Instant,
+}
+
+/// Which command of each cycle runs next. Cycles which were never used start at their first
+/// command.
+#[derive(Debug, Clone, Default)]
+pub struct CycleState {
+    positions: HashMap<CycleKey, Position>,
+    /// Cycles which were not used for this long start over at their first command
+    idle_reset: Option<Duration>,
+}
+
+impl CycleState {
+    pub fn set_idle_reset(&mut self, idle_reset: Option<Duration>) {
+        self.idle_reset = idle_reset;
+    }
+
+    /// The position of the command which runs next in the cycle of `hotkey`, i.e. the `delay`
+    /// of that command. Always 0 for hotkeys which are not part of a cycle.
+    pub fn position(&self, hotkey: &Hotkey) -> i32 {
+        let Some(ref cycle) = hotkey.cycle else {
+            return 0;
+        };
+        self.positions
+            .get(&CycleKey::from(hotkey))
+            .filter(|p| self.idle_reset.is_none_or(|idle| p.used.elapsed() < idle))
+            // The cycle may have fewer commands since it was last used
+            .map_or(0, |p| p.next % cycle.period.max(1))
+    }
+
+    /// Whether `hotkey` is the command of its cycle which runs next
+    pub fn is_next(&self, hotkey: &Hotkey) -> bool {
+        hotkey
+            .cycle
+            .as_ref()
+            .is_none_or(|c| c.delay == self.position(hotkey))
+    }
+
+    /// Move the cycle of `hotkey` on to the command after it
+    pub fn advance(&mut self, hotkey: &Hotkey) {
+        if let Some(ref cycle) = hotkey.cycle {
+            self.positions.insert(
+                CycleKey::from(hotkey),
+                Position {
+                    next: (cycle.delay + 1) % cycle.period.max(1),
+                    used: Instant::now(),
+                },
+            );
+        }
+    }
+
+    /// Start the cycle of `hotkey` over at its first command
+    pub fn reset(&mut self, hotkey: &Hotkey) {
+        self.positions.remove(&CycleKey::from(hotkey));
+    }
+
+    /// Forget the cycles which none of `hotkeys` belongs to
+    pub fn retain(&mut self, hotkeys: &[Hotkey]) {
+        let keys: HashSet<CycleKey> = hotkeys
+            .iter()
+            .filter(|hk| hk.cycle.is_some())
+            .map(CycleKey::from)
+            .collect();
+        self.positions.retain(|key, _| keys.contains(key));
+    }
+}
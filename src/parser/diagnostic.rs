@@ -0,0 +1,236 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+
+use super::{ConfigParseError, Source, TokenRange};
+
+/// How diagnostics are printed
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum DiagnosticFormat {
+    /// An excerpt of the config with the offending text underlined
+    #[default]
+    Human,
+    /// One JSON object per line, for editors and CI
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => f.write_str("error"),
+            Severity::Warning => f.write_str("warning"),
+        }
+    }
+}
+
+/// The location of a diagnostic in a config file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    /// Byte range in the file
+    pub range: TokenRange,
+    /// 1-based line of the start of the span
+    pub line: usize,
+    /// 1-based column of the start of the span, counted in characters
+    pub column: usize,
+    /// The line containing the start of the span
+    pub source_line: String,
+    /// The number of characters to underline. Spans covering several lines are cut off at the
+    /// end of the first line.
+    pub width: usize,
+}
+
+impl Span {
+    pub fn new(range: TokenRange, content: &[u8]) -> Self {
+        let start = range.start.min(content.len());
+        let line_start = content[..start]
+            .iter()
+            .rposition(|c| *c == b'\n')
+            .map_or(0, |i| i + 1);
+        let line_end = content[start..]
+            .iter()
+            .position(|c| *c == b'\n')
+            .map_or(content.len(), |i| start + i);
+        let end = range.end.clamp(start, line_end);
+        let chars = |bytes: &[u8]| String::from_utf8_lossy(bytes).chars().count();
+        Span {
+            line: content[..start].iter().filter(|c| **c == b'\n').count() + 1,
+            column: chars(&content[line_start..start]) + 1,
+            source_line: String::from_utf8_lossy(&content[line_start..line_end]).to_string(),
+            width: chars(&content[start..end]).max(1),
+            range,
+        }
+    }
+}
+
+/// An error or warning about a config, with enough context to point at the offending text
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// A suggestion for fixing the problem, e.g. "did you mean 'Return'?"
+    pub hint: Option<String>,
+    pub file: Option<PathBuf>,
+    pub span: Option<Span>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: String) -> Self {
+        Diagnostic {
+            severity,
+            message,
+            hint: None,
+            file: None,
+            span: None,
+        }
+    }
+
+    pub fn from_parse_error(error: &ConfigParseError, content: &[u8], file: Option<&Path>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message: error.to_string(),
+            hint: error.hint(),
+            file: file.map(|f| f.to_path_buf()),
+            span: error.get_range().map(|r| Span::new(r, content)),
+        }
+    }
+
+    /// Create a diagnostic pointing at the place a hotkey or mode was defined
+    pub fn at(severity: Severity, message: String, source: Option<&Source>) -> Self {
+        Diagnostic {
+            file: source.and_then(|s| s.file.clone()),
+            span: source.map(|s| s.span.clone()),
+            ..Self::new(severity, message)
+        }
+    }
+
+    /// Create a diagnostic from any error. A span is only available if the error is, or was
+    /// caused by, a `ConfigParseError`.
+    pub fn from_error(error: &anyhow::Error, content: &[u8], file: Option<&Path>) -> Self {
+        if let Some(e) = error
+            .chain()
+            .find_map(|e| e.downcast_ref::<ConfigParseError>())
+        {
+            return Self::from_parse_error(e, content, file);
+        }
+        Diagnostic {
+            file: file.map(|f| f.to_path_buf()),
+            ..Self::new(Severity::Error, error.to_string())
+        }
+    }
+
+    /// Create a diagnostic from an error which stopped a config from loading
+    pub fn from_fatal(error: &anyhow::Error) -> Self {
+        match error.downcast_ref::<Diagnostic>() {
+            Some(diagnostic) => diagnostic.clone(),
+            None => Self::new(Severity::Error, format!("{:#}", error)),
+        }
+    }
+
+    /// Format the diagnostic as a single line, e.g. 'rhkdrc:4:9: error: Unrecognized key'
+    pub fn to_line(&self) -> String {
+        let mut location = self
+            .file
+            .as_ref()
+            .map_or("<input>".to_string(), |f| f.display().to_string());
+        if let Some(ref span) = self.span {
+            location.push_str(&format!(":{}:{}", span.line, span.column));
+        }
+        format!("{}: {}: {}", location, self.severity, self.message)
+    }
+
+    /// Format the diagnostic as a single line JSON object
+    pub fn to_json(&self) -> String {
+        let mut fields = vec![
+            format!("\"severity\":{}", json_string(&self.severity.to_string())),
+            format!("\"message\":{}", json_string(&self.message)),
+        ];
+        if let Some(ref hint) = self.hint {
+            fields.push(format!("\"hint\":{}", json_string(hint)));
+        }
+        if let Some(ref file) = self.file {
+            fields.push(format!(
+                "\"file\":{}",
+                json_string(&file.display().to_string())
+            ));
+        }
+        if let Some(ref span) = self.span {
+            fields.push(format!("\"line\":{}", span.line));
+            fields.push(format!("\"column\":{}", span.column));
+            fields.push(format!("\"start\":{}", span.range.start));
+            fields.push(format!("\"end\":{}", span.range.end));
+        }
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}: {}", self.severity, self.message)?;
+        let file = self
+            .file
+            .as_ref()
+            .map_or("<input>".to_string(), |f| f.display().to_string());
+        if let Some(ref span) = self.span {
+            let gutter = " ".repeat(span.line.to_string().len());
+            writeln!(f, "{}--> {}:{}:{}", gutter, file, span.line, span.column)?;
+            writeln!(f, "{} |", gutter)?;
+            writeln!(f, "{} | {}", span.line, span.source_line)?;
+            // Keep tabs so the carets line up with the source line
+            let indent: String = span
+                .source_line
+                .chars()
+                .take(span.column - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            writeln!(f, "{} | {}{}", gutter, indent, "^".repeat(span.width))?;
+            if let Some(ref hint) = self.hint {
+                writeln!(f, "{} = help: {}", gutter, hint)?;
+            }
+        } else {
+            if self.file.is_some() {
+                writeln!(f, " --> {}", file)?;
+            }
+            if let Some(ref hint) = self.hint {
+                writeln!(f, " = help: {}", hint)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+fn json_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            '\r' => result.push_str("\\r"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+/// Print diagnostics to stderr, so they do not mix with the output of commands such as `--check`
+pub fn print_diagnostics(diagnostics: &[Diagnostic], format: DiagnosticFormat) {
+    for diagnostic in diagnostics {
+        match format {
+            DiagnosticFormat::Human => eprintln!("{}", diagnostic),
+            DiagnosticFormat::Json => eprintln!("{}", diagnostic.to_json()),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use super::serializer::{command_text, escape};
+use super::{Hotkey, Mode, Prefix, DEFAULT_MODE};
+use crate::rhkc::ipc::{BindCommand, UnbindCommand};
+
+/// The changes between the hotkeys of two configs, used to apply a reload without starting over
+#[derive(Debug, Default)]
+pub struct HotkeyDiff {
+    /// Hotkeys which are only in the new config
+    pub added: Vec<Hotkey>,
+    /// Hotkeys which are only in the old config
+    pub removed: Vec<Hotkey>,
+    /// Hotkeys which run the same command for the same chain in both configs, but whose other
+    /// properties changed, as (old, new) pairs
+    pub changed: Vec<(Hotkey, Hotkey)>,
+}
+
+// Two hotkeys are the same binding if the same keys run the same command for the same windows
+fn same_binding(a: &Hotkey, b: &Hotkey) -> bool {
+    a.mode == b.mode
+        && a.command == b.command
+        && a.conditions == b.conditions
+        && a.chain.len() == b.chain.len()
+        && a.chain
+            .iter()
+            .zip(b.chain.iter())
+            .all(|(a, b)| a.eq_relaxed(b))
+}
+
+// Moving a binding around in a file does not change it
+fn same_properties(a: &Hotkey, b: &Hotkey) -> bool {
+    let a = Hotkey {
+        source: None,
+        ..a.clone()
+    };
+    let b = Hotkey {
+        source: None,
+        ..b.clone()
+    };
+    a == b
+}
+
+pub fn diff(old: &[Hotkey], new: &[Hotkey]) -> HotkeyDiff {
+    let mut result = HotkeyDiff::default();
+    let mut matched = vec![false; new.len()];
+    for hk in old {
+        let found = (0..new.len()).find(|&i| !matched[i] && same_binding(hk, &new[i]));
+        match found {
+            Some(i) => {
+                matched[i] = true;
+                if !same_properties(hk, &new[i]) {
+                    result.changed.push((hk.clone(), new[i].clone()));
+                }
+            }
+            None => result.removed.push(hk.clone()),
+        }
+    }
+    result.added = new
+        .iter()
+        .zip(matched)
+        .filter(|(_, matched)| !matched)
+        .map(|(hk, _)| hk.clone())
+        .collect();
+    result
+}
+
+/// The prefix titles which differ between two configs. A prefix whose title changes is removed
+/// and added again.
+#[derive(Debug, Default)]
+pub struct PrefixDiff {
+    pub added: Vec<Prefix>,
+    pub removed: Vec<Prefix>,
+}
+
+fn same_prefix(a: &Prefix, b: &Prefix) -> bool {
+    a.mode == b.mode && a.title == b.title && a.titles(&b.chain)
+}
+
+pub fn diff_prefixes(old: &[Prefix], new: &[Prefix]) -> PrefixDiff {
+    let (added, removed) = diff_items(old, new, same_prefix);
+    PrefixDiff { added, removed }
+}
+
+/// The modes which differ between two configs. A mode whose abort keysym changes is removed and
+/// added again.
+#[derive(Debug, Default)]
+pub struct ModeDiff {
+    pub added: Vec<Mode>,
+    pub removed: Vec<Mode>,
+}
+
+pub fn diff_modes(old: &[Mode], new: &[Mode]) -> ModeDiff {
+    let (added, removed) = diff_items(old, new, |a, b| a.name == b.name && a.abort == b.abort);
+    ModeDiff { added, removed }
+}
+
+// The items which are only in `new` and the items which are only in `old`
+fn diff_items<T: Clone>(old: &[T], new: &[T], same: impl Fn(&T, &T) -> bool) -> (Vec<T>, Vec<T>) {
+    let mut removed = vec![];
+    let mut matched = vec![false; new.len()];
+    for item in old {
+        match (0..new.len()).find(|&i| !matched[i] && same(item, &new[i])) {
+            Some(i) => matched[i] = true,
+            None => removed.push(item.clone()),
+        }
+    }
+    let added = new
+        .iter()
+        .zip(matched)
+        .filter(|(_, matched)| !matched)
+        .map(|(item, _)| item.clone())
+        .collect();
+    (added, removed)
+}
+
+impl HotkeyDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Commands which remove the old version of every removed or changed hotkey. They have to
+    /// be applied before the commands from `bind_commands`.
+    pub fn unbind_commands(&self) -> Vec<UnbindCommand> {
+        let old = self.changed.iter().map(|(old, _)| old);
+        self.removed.iter().chain(old).map(unbind_command).collect()
+    }
+
+    /// Commands which add the new version of every added or changed hotkey
+    pub fn bind_commands(&self) -> Vec<BindCommand> {
+        let new = self.changed.iter().map(|(_, new)| new);
+        self.added.iter().chain(new).map(bind_command).collect()
+    }
+}
+
+fn mode_name(hotkey: &Hotkey) -> String {
+    hotkey.mode.as_deref().unwrap_or(DEFAULT_MODE).to_string()
+}
+
+fn unbind_command(hotkey: &Hotkey) -> UnbindCommand {
+    UnbindCommand {
+        hotkey: hotkey.chain_repr(),
+        mode: Some(mode_name(hotkey)),
+        command: Some(hotkey.command.to_string()),
+        conditions: hotkey.conditions.iter().map(|c| c.to_string()).collect(),
+    }
+}
+
+fn bind_command(hotkey: &Hotkey) -> BindCommand {
+    BindCommand {
+        overwrite: false,
+        hotkey: hotkey.chain_repr(),
+        command: command_text(escape(&hotkey.command, false), hotkey.sync),
+        title: hotkey.title.as_ref().map(|t| t.to_string()),
+        description: hotkey.description.as_ref().map(|d| escape(d, false)),
+        tags: hotkey.tags.iter().map(|t| t.to_string()).collect(),
+        icon: hotkey.icon.as_ref().map(|i| i.to_string()),
+        hidden: hotkey.hidden,
+        mode: Some(mode_name(hotkey)),
+        conditions: hotkey.conditions.iter().map(|c| c.to_string()).collect(),
+        count: hotkey.count,
+        timeout: hotkey.timeout.map(|t| t.as_secs_f64()),
+        exec: hotkey.exec,
+        repeat: hotkey.repeat,
+    }
+}
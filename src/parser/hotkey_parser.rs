@@ -1,6 +1,13 @@
 use std::sync::Arc;
 
-use crate::{keyboard, parser::permutator::Permute};
+use crate::{
+    keyboard::{self, KeyError, Keymap},
+    parser::{
+        diagnostic::{Diagnostic, Span},
+        permutator::Permute,
+        variables::Variables,
+    },
+};
 use thiserror::Error;
 pub use xcb::x::ModMask;
 
@@ -32,6 +39,8 @@ pub struct HotkeyParser {
     errors: Vec<anyhow::Error>,
     hotkeys: Vec<Hotkey>,
     is_cycle: bool,
+    source: Option<Arc<Source>>,
+    command_source: Option<Token>,
 }
 
 #[derive(Debug, Clone)]
@@ -144,7 +153,7 @@ impl GroupableToken {
 }
 
 impl HotkeyParser {
-    fn parse_chord(&self, tokens: &[GroupableToken]) -> Result<Chord> {
+    fn parse_chord(&self, tokens: &[GroupableToken], keymap: &dyn Keymap) -> Result<Chord> {
         fn is_junk(t: &GroupableToken) -> bool {
             matches!(t, GroupableToken::Single(_, GroupToken::EmptySequence))
         }
@@ -189,8 +198,20 @@ impl HotkeyParser {
                         break;
                     }
                 }
-                let key = keyboard::symbol_from_string(t_slice)?;
-                chord.keysym = key;
+                if let Some(button) = keyboard::button_from_string(t_slice) {
+                    chord.button = button;
+                } else if t_slice.starts_with("button") && t_slice[6..].parse::<u32>().is_ok() {
+                    return Err(ConfigParseError::InvalidBinding(
+                        source,
+                        format!(
+                            "Buttons must be in the range button1-button{}",
+                            keyboard::MAX_BUTTON
+                        ),
+                    ))?;
+                } else {
+                    chord.keysym = keyboard::symbol_from_string(t_slice)
+                        .map_err(|e| locate_key_error(e, &source))?;
+                }
                 t
             }
             _ => Err(ConfigParseError::InvalidBinding(
@@ -200,6 +221,7 @@ impl HotkeyParser {
         };
 
         let mut mod_tokens = vec![];
+        let mut mod_sources = vec![];
         for pair in tokens.chunks(2) {
             let mod_token = &pair[0];
             if let Some(plus_token) = pair.get(1) {
@@ -227,6 +249,7 @@ impl HotkeyParser {
                     ))?;
                 }
                 mod_tokens.push(mod_text);
+                mod_sources.push(mod_token.source_token());
             } else {
                 Err(ConfigParseError::InvalidBinding(
                     mod_token.source_token(),
@@ -234,7 +257,20 @@ impl HotkeyParser {
                 ))?;
             }
         }
-        let modfield = keyboard::modfield_from_mods(&mod_tokens)?;
+        let modfield = keymap.modfield_from_mods(&mod_tokens).map_err(|e| {
+            // Point at the modifier which could not be resolved
+            let source = match e.root_cause().downcast_ref::<KeyError>() {
+                Some(KeyError::UnknownModifier(name, _)) => mod_tokens
+                    .iter()
+                    .position(|m| m == name)
+                    .map(|i| &mod_sources[i]),
+                _ => None,
+            };
+            match source {
+                Some(source) => locate_key_error(e, source),
+                None => e,
+            }
+        })?;
         chord.modfield = modfield.bits().into();
         mod_tokens.push(&keysym_repr);
         chord.repr = mod_tokens.join(" + ").into();
@@ -327,6 +363,86 @@ impl HotkeyParser {
         result
     }
 
+    // Split substituted binding text into keys, modifiers and chain separators, so a variable can
+    // hold e.g. 'super + shift'
+    fn split_binding_text(text: &str) -> Vec<GroupToken> {
+        fn flush(word: &mut String, result: &mut Vec<GroupToken>) {
+            match word.as_str() {
+                "" => {}
+                "_" => result.push(GroupToken::EmptySequence),
+                _ => result.push(GroupToken::Text(word.clone())),
+            }
+            word.clear();
+        }
+
+        let mut result = vec![];
+        let mut word = String::new();
+        for ch in text.chars() {
+            match ch {
+                '+' | ';' | ':' => {
+                    flush(&mut word, &mut result);
+                    result.push(match ch {
+                        '+' => GroupToken::Plus,
+                        ';' => GroupToken::Chain(ChainMode::Once),
+                        _ => GroupToken::Chain(ChainMode::Locking),
+                    });
+                }
+                ch if ch.is_whitespace() => flush(&mut word, &mut result),
+                ch => word.push(ch),
+            }
+        }
+        flush(&mut word, &mut result);
+        result
+    }
+
+    // Substitute variables in every text token. References in bindings must resolve, while
+    // commands and descriptions only substitute defined variables.
+    fn substitute(
+        tokens: Vec<GroupableToken>,
+        variables: &Variables,
+        is_binding: bool,
+    ) -> Result<Vec<GroupableToken>> {
+        let expand = |source: &Token, token: GroupToken| -> Result<Vec<GroupToken>> {
+            let GroupToken::Text(text) = token else {
+                return Ok(vec![token]);
+            };
+            if !text.contains('$') {
+                return Ok(vec![GroupToken::Text(text)]);
+            }
+            let expanded = variables
+                .expand(&text, is_binding)
+                .map_err(|e| ConfigParseError::InvalidVariable(source.clone(), e.to_string()))?;
+            if is_binding {
+                Ok(Self::split_binding_text(&expanded))
+            } else {
+                Ok(vec![GroupToken::Text(expanded)])
+            }
+        };
+
+        let mut result = vec![];
+        for token in tokens {
+            match token {
+                GroupableToken::Single(source, token) => {
+                    for token in expand(&source, token)? {
+                        result.push(GroupableToken::Single(source.clone(), token));
+                    }
+                }
+                GroupableToken::Group(source, variants) => {
+                    let mut expanded = vec![];
+                    for variant in variants {
+                        let mut tokens = vec![];
+                        for token in variant {
+                            tokens.extend(expand(&source, token)?);
+                        }
+                        expanded.push(tokens);
+                    }
+                    result.push(GroupableToken::Group(source, expanded));
+                }
+            }
+        }
+        Ok(result)
+    }
+
     fn group_counts(v: &[GroupableToken]) -> Vec<usize> {
         v.iter()
             .filter_map(|g| g.as_group().map(|g| g.len()))
@@ -362,7 +478,7 @@ impl HotkeyParser {
         }
     }
 
-    fn get_group_mapping_errors(&self, context: &[u8]) -> Option<String> {
+    fn get_group_mapping_errors(&self, context: &[u8]) -> Option<ConfigParseError> {
         let count_shortcuts = Self::group_counts(&self.shortcuts);
         let count_commands = Self::group_counts(&self.commands);
 
@@ -394,8 +510,10 @@ impl HotkeyParser {
         }
 
         if !err_string.is_empty() {
-            let line_info = self.shortcuts[0].source_token().get_line_info(context);
-            Some(format!("{}\n  Starting at line {}", err_string, line_info))
+            Some(ConfigParseError::GroupMappingMismatch(
+                self.shortcuts[0].source_token(),
+                err_string,
+            ))
         } else {
             None
         }
@@ -484,13 +602,17 @@ impl HotkeyParser {
         s
     }
 
-    fn make_chain(&mut self, shortcut: &[GroupableToken]) -> Result<Vec<Chord>> {
+    fn make_chain(
+        &mut self,
+        shortcut: &[GroupableToken],
+        keymap: &dyn Keymap,
+    ) -> Result<Vec<Chord>> {
         let mut chain = vec![];
         let chords: Vec<_> = shortcut
             .split_inclusive(|t| matches!(t, GroupableToken::Single(_, GroupToken::Chain(_))))
             .collect();
         for chord_tokens in chords {
-            chain.push(self.parse_chord(chord_tokens)?);
+            chain.push(self.parse_chord(chord_tokens, keymap)?);
         }
         Ok(chain)
     }
@@ -500,12 +622,11 @@ impl HotkeyParser {
     }
 
     // Populate errors and hotkeys
-    fn populate_errors_and_hotkeys(mut self, context: &[u8]) -> Self {
+    fn populate_errors_and_hotkeys(mut self, context: &[u8], keymap: &dyn Keymap) -> Self {
         // If this is a cycle, the mapping check is not needed
         if !self.is_cycle {
-            if let Some(err_string) = self.get_group_mapping_errors(context) {
-                self.errors
-                    .push(ConfigParseError::GroupMappingMismatch(err_string).into());
+            if let Some(err) = self.get_group_mapping_errors(context) {
+                self.errors.push(err.into());
             }
         }
 
@@ -548,13 +669,24 @@ impl HotkeyParser {
             let command = &unit.command;
             let mut command_string = Self::string_variant(command).trim().to_string();
             let sync = command_string.starts_with(';');
-            if sync {
+            if sync || command_string.starts_with("\\;") {
+                // An escaped ';' is part of the command
                 command_string.remove(0);
             }
 
             let command_string = command_string.trim().to_string();
+            let action = match Action::parse(&command_string, keymap) {
+                Ok(action) => action,
+                Err(e) => {
+                    if let Some(ref token) = self.command_source {
+                        self.errors
+                            .push(ConfigParseError::InvalidAction(token.clone(), e).into());
+                    }
+                    continue;
+                }
+            };
 
-            let chain = match self.make_chain(shortcut) {
+            let chain = match self.make_chain(shortcut, keymap) {
                 Ok(chain) => chain,
                 Err(e) => {
                     self.errors.push(e);
@@ -576,6 +708,17 @@ impl HotkeyParser {
                 cycle,
                 title: self.title.clone(),
                 description: unit.description.map(|d| Self::string_variant(&d).into()),
+                source: self.source.clone(),
+                mode: None,
+                action,
+                conditions: Arc::new([]),
+                tags: Arc::new([]),
+                icon: None,
+                hidden: false,
+                count: None,
+                timeout: None,
+                exec: false,
+                repeat: None,
             };
 
             self.hotkeys.push(hotkey);
@@ -588,11 +731,22 @@ impl HotkeyParser {
         shortcut: ShortcutNode,
         command: CommandNode,
         comment: Option<CommentNode>,
+        variables: &Variables,
+        keymap: &dyn Keymap,
         context: &[u8],
     ) -> (Vec<Hotkey>, Vec<anyhow::Error>) {
-        let command_groups = Self::group(&command.tokens, context);
         let (title, comment_groups) = Self::split_comment(comment, context);
-        let shortcut_groups = Self::group(&shortcut.tokens, context);
+        let substituted = Self::substitute(Self::group(&command.tokens, context), variables, false)
+            .and_then(|commands| {
+                let descriptions = Self::substitute(comment_groups, variables, false)?;
+                let shortcuts =
+                    Self::substitute(Self::group(&shortcut.tokens, context), variables, true)?;
+                Ok((commands, descriptions, shortcuts))
+            });
+        let (command_groups, comment_groups, shortcut_groups) = match substituted {
+            Ok(groups) => groups,
+            Err(e) => return (vec![], vec![e]),
+        };
         let is_cycle = Self::is_cycle(
             &Self::group_counts(&shortcut_groups),
             &Self::group_counts(&command_groups),
@@ -605,34 +759,108 @@ impl HotkeyParser {
             hotkeys: vec![],
             errors: vec![],
             is_cycle,
+            source: shortcut.tokens.first().map(|t| {
+                Arc::new(Source {
+                    file: None,
+                    span: Span::new(t.get_range(), context),
+                })
+            }),
+            command_source: command.tokens.first().cloned(),
         };
-        let result = instance.populate_errors_and_hotkeys(context);
+        let result = instance.populate_errors_and_hotkeys(context, keymap);
         (result.hotkeys, result.errors)
     }
+
+    /// Expand a binding without a command into a prefix for each variant of the binding. The
+    /// title of each variant is `title` if it is set, then its description, then the title of the
+    /// comment. Variants without any of them are skipped.
+    pub fn expand_prefix(
+        shortcut: ShortcutNode,
+        comment: Option<CommentNode>,
+        title: Option<Arc<str>>,
+        variables: &Variables,
+        keymap: &dyn Keymap,
+        context: &[u8],
+    ) -> (Vec<Prefix>, Vec<anyhow::Error>) {
+        let (title_token, comment_groups) = Self::split_comment(comment, context);
+        let substituted = Self::substitute(comment_groups, variables, false).and_then(|d| {
+            let shortcuts =
+                Self::substitute(Self::group(&shortcut.tokens, context), variables, true)?;
+            Ok((d, shortcuts))
+        });
+        let (descriptions, shortcuts) = match substituted {
+            Ok(groups) => groups,
+            Err(e) => return (vec![], vec![e]),
+        };
+        let comment_title: Option<Arc<str>> = title_token.map(|t| t.get_string(context).into());
+        let source = shortcut.tokens.first().map(|t| {
+            Arc::new(Source {
+                file: None,
+                span: Span::new(t.get_range(), context),
+            })
+        });
+
+        let mut parser = Self::default();
+        let mut prefixes = vec![];
+        let variants = Permute::back_first(&Self::group_counts(&shortcuts));
+        // The shortcut stands in for the command, so every variant of it is kept
+        for unit in Unit::make(&shortcuts, &shortcuts, &descriptions, &variants, false) {
+            let description = unit.description.map(|d| Self::string_variant(&d).into());
+            let Some(title) = title
+                .clone()
+                .or(description)
+                .or_else(|| comment_title.clone())
+            else {
+                continue;
+            };
+            match parser.make_chain(&unit.shortcut, keymap) {
+                Ok(chain) => prefixes.push(Prefix {
+                    chain: chain.into(),
+                    title,
+                    mode: None,
+                    source: source.clone(),
+                }),
+                Err(e) => parser.errors.push(e),
+            }
+        }
+        (prefixes, parser.errors)
+    }
+}
+
+// Attach the token a key or modifier came from to errors about unknown symbols
+fn locate_key_error(error: anyhow::Error, source: &Token) -> anyhow::Error {
+    match error.root_cause().downcast_ref::<KeyError>() {
+        Some(KeyError::UnknownKey(key, similar)) => {
+            ConfigParseError::UnknownKey(source.clone(), key.clone(), similar.clone()).into()
+        }
+        Some(KeyError::UnknownModifier(modifier, similar)) => {
+            ConfigParseError::UnknownModifier(source.clone(), modifier.clone(), similar.clone())
+                .into()
+        }
+        None => error,
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum ChordParseError {
     #[error("{0}")]
-    Contextual(String),
+    Contextual(Diagnostic),
 }
 
-pub fn chord_from_tokens(tokens: &[Token], context: &[u8]) -> Result<Vec<Chord>> {
+pub fn chord_from_tokens(
+    tokens: &[Token],
+    context: &[u8],
+    keymap: &dyn Keymap,
+) -> Result<Vec<Chord>> {
     let mut parser = HotkeyParser::default();
     let groups = HotkeyParser::group(tokens, context);
-    match parser.make_chain(&groups) {
+    match parser.make_chain(&groups, keymap) {
         Ok(e) => Ok(e),
         Err(err) => match err.downcast_ref::<ConfigParseError>() {
-            Some(e) => {
-                if let Some(t) = e.get_token() {
-                    let s = t.get_string(context);
-                    return Err(ChordParseError::Contextual(format!(
-                        "{} - in text '{}'",
-                        err, s
-                    )))?;
-                }
-                Err(err)
-            }
+            Some(e) if e.get_range().is_some() => Err(ChordParseError::Contextual(
+                Diagnostic::from_parse_error(e, context, None),
+            ))?,
+            Some(_) => Err(err),
             None => Err(err),
         },
     }
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::{Chord, Hotkey};
+
+/// The parts of a chord which decide whether an event matches it, i.e. everything
+/// `Chord::eq_relaxed` compares
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChordKey {
+    /// The keysym of a key, or 0 for pointer buttons
+    pub keysym: u32,
+    pub button: u8,
+    pub modfield: u32,
+    pub is_press: bool,
+}
+
+impl From<&Chord> for ChordKey {
+    fn from(chord: &Chord) -> Self {
+        ChordKey {
+            keysym: chord.keysym,
+            button: chord.button,
+            modfield: chord.modfield.bits(),
+            is_press: chord.event_type.is_key_press(),
+        }
+    }
+}
+
+/// A prefix of one or more chains. Hotkeys are stored as indices into the hotkey list they were
+/// indexed from, in ascending order.
+#[derive(Debug, Clone, Default)]
+pub struct Node {
+    /// The chord leading to this node, as written by the first hotkey through it
+    pub chord: Chord,
+    /// Set if any hotkey through this node locks the chain after its chord
+    pub locking: bool,
+    /// Every hotkey whose chain starts with this prefix
+    pub hotkeys: Vec<usize>,
+    /// The hotkeys whose chain is exactly this prefix
+    pub terminals: Vec<usize>,
+    children: HashMap<ChordKey, Node>,
+}
+
+impl Node {
+    pub fn child(&self, key: &ChordKey) -> Option<&Node> {
+        self.children.get(key)
+    }
+
+    /// The chords which continue this prefix
+    pub fn children(&self) -> impl Iterator<Item = &Node> {
+        self.children.values()
+    }
+}
+
+/// A trie of the chains of each mode, so the hotkeys continuing a chain are found without
+/// scanning every hotkey. Configs with many brace groups expand to thousands of hotkeys.
+#[derive(Debug, Clone, Default)]
+pub struct HotkeyIndex {
+    modes: HashMap<Option<Arc<str>>, Node>,
+}
+
+impl HotkeyIndex {
+    pub fn build(hotkeys: &[Hotkey]) -> Self {
+        let mut index = HotkeyIndex::default();
+        for (i, hotkey) in hotkeys.iter().enumerate() {
+            index.insert(i, hotkey);
+        }
+        index
+    }
+
+    /// Add the hotkey at position `index` of the hotkey list. It must come after every hotkey
+    /// which is already indexed.
+    pub fn insert(&mut self, index: usize, hotkey: &Hotkey) {
+        let mut node = self.modes.entry(hotkey.mode.clone()).or_default();
+        node.hotkeys.push(index);
+        for chord in hotkey.chain.iter() {
+            node = node
+                .children
+                .entry(ChordKey::from(chord))
+                .or_insert_with(|| Node {
+                    chord: chord.clone(),
+                    ..Default::default()
+                });
+            node.locking |= chord.is_locking();
+            node.hotkeys.push(index);
+        }
+        node.terminals.push(index);
+    }
+
+    /// The root of the trie of a mode, whose children are the first chords of its chains
+    pub fn root(&self, mode: Option<&str>) -> Option<&Node> {
+        self.modes.get(&mode.map(Arc::from))
+    }
+
+    /// The node of `chain` in `mode`, if any hotkey starts with it
+    pub fn find(&self, mode: Option<&str>, chain: &[Chord]) -> Option<&Node> {
+        chain.iter().try_fold(self.root(mode)?, |node, chord| {
+            node.child(&ChordKey::from(chord))
+        })
+    }
+
+    /// The nodes along `chain` in `mode`, starting with the node of its first chord. Stops at
+    /// the first chord which no hotkey continues with.
+    pub fn path(&self, mode: Option<&str>, chain: &[Chord]) -> Vec<&Node> {
+        let mut path = vec![];
+        let mut node = self.root(mode);
+        for chord in chain {
+            node = node.and_then(|n| n.child(&ChordKey::from(chord)));
+            match node {
+                Some(n) => path.push(n),
+                None => break,
+            }
+        }
+        path
+    }
+}
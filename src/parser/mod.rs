@@ -1,4 +1,9 @@
+pub mod analysis;
 pub mod config;
+pub mod cycles;
+pub mod diagnostic;
+pub mod diff;
+pub mod index;
 pub use types::Hotkey;
 pub mod types;
 
@@ -7,14 +12,20 @@ use std::ops::Range;
 mod hotkey_parser;
 mod permutator;
 mod scanner;
+mod serializer;
 mod token_parser;
+pub mod variables;
 
 pub use types::*;
 
 pub use scanner::Scanner;
 pub use token_parser::Parser;
 
+use self::diagnostic::Diagnostic;
+#[cfg(test)]
+use self::diagnostic::Severity;
 use self::hotkey_parser::ChordParseError;
+use crate::keyboard::Keymap;
 
 #[derive(Debug, Clone)]
 pub enum RangeError {
@@ -27,9 +38,9 @@ pub enum RangeError {
 pub enum ConfigParseError {
     PermissionError(String, String),
     ParseError(String),
-    SyntaxError(String),
+    SyntaxError(Token, String),
     InvalidRange(RangeError),
-    GroupMappingMismatch(String),
+    GroupMappingMismatch(Token, String),
     InvalidEscape(usize, u8),
     InvalidToken(Token, String, String),
     UnterminatedBinding(Token),
@@ -37,6 +48,16 @@ pub enum ConfigParseError {
     UnterminatedComment(Token),
     UnterminatedGroup(Token),
     InvalidBinding(Token, String),
+    InvalidInclude(Token, String),
+    IncludeCycle(String),
+    InvalidVariable(Token, String),
+    /// An unknown key, and the most similar known key
+    UnknownKey(Token, String, Option<String>),
+    /// An unknown modifier, and the most similar known modifier
+    UnknownModifier(Token, String, Option<String>),
+    InvalidMode(Token, String),
+    InvalidAction(Token, String),
+    InvalidAnnotation(Token, String),
 }
 
 pub type TokenRange = Range<usize>;
@@ -58,33 +79,32 @@ pub enum Token {
     EndGroup(TokenRange),
     Range(TokenRange, u8, u8),
     EmptyLine(TokenRange),
+    Include(TokenRange),
+    Define(TokenRange, TokenRange),
+    /// The header of a mode block, i.e. everything after the 'mode' keyword
+    StartMode(TokenRange),
+    /// The '}' closing a mode block
+    EndMode(usize),
+    /// A comment starting with '#@', which applies to the next binding. The range excludes '#@'.
+    Annotation(TokenRange),
 }
 
 impl Token {
     fn get_string(&self, context: &[u8]) -> String {
         String::from_utf8_lossy(&context[self.get_range()]).to_string()
     }
-    fn get_line_info(&self, context: &[u8]) -> LineInfo {
-        let start = self.get_range().start;
-        let mut line = 1;
-        let mut column = 0;
-        for ch in &context[0..start] {
-            if *ch == b'\n' {
-                line += 1;
-                column = 1;
-            } else {
-                column += 1;
-            }
-        }
-        // This would happen in the case where the token starts on the first line
-        LineInfo { line, column }
-    }
 }
 
 impl Token {
     pub fn get_range(&self) -> TokenRange {
         match self {
-            Token::Range(r, _, _) | Token::Text(r) | Token::EmptyLine(r) => r.clone(),
+            Token::Range(r, _, _)
+            | Token::Text(r)
+            | Token::EmptyLine(r)
+            | Token::Include(r)
+            | Token::StartMode(r)
+            | Token::Annotation(r) => r.clone(),
+            Token::Define(name, value) => name.start..value.end,
             Token::StartCommand(r)
             | Token::EndCommand(r)
             | Token::StartBinding(r)
@@ -96,6 +116,7 @@ impl Token {
             Token::ContinueComment(r)
             | Token::Separator(r)
             | Token::Chain(r, _)
+            | Token::EndMode(r)
             | Token::Plus(r) => *r..*r + 1,
         }
     }
@@ -109,17 +130,6 @@ impl Token {
     }
 }
 
-struct LineInfo {
-    line: usize,
-    column: usize,
-}
-
-impl Display for LineInfo {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&format!("{}:{}", self.line, self.column))
-    }
-}
-
 impl ConfigParseError {
     fn get_token(&self) -> Option<&Token> {
         use ConfigParseError::*;
@@ -129,23 +139,51 @@ impl ConfigParseError {
             | UnterminatedCommand(t)
             | UnterminatedComment(t)
             | UnterminatedGroup(t)
-            | InvalidBinding(t, _) => Some(t),
+            | InvalidBinding(t, _)
+            | InvalidInclude(t, _)
+            | InvalidVariable(t, _)
+            | SyntaxError(t, _)
+            | GroupMappingMismatch(t, _)
+            | UnknownKey(t, _, _)
+            | UnknownModifier(t, _, _)
+            | InvalidMode(t, _)
+            | InvalidAction(t, _)
+            | InvalidAnnotation(t, _) => Some(t),
             _ => None,
         }
     }
 
-    fn contextualize(&self, context: &[u8]) -> String {
-        if let Some(token) = self.get_token() {
-            let token_string = token.get_string(context);
-            let line_info = token.get_line_info(context);
-            format!(
-                "{}\n In token '{}' (line {})",
-                self, token_string, line_info
-            )
-        } else {
-            self.to_string()
+    fn get_range(&self) -> Option<TokenRange> {
+        match self {
+            ConfigParseError::InvalidEscape(pos, _) => Some(*pos..*pos + 1),
+            _ => self.get_token().map(|t| t.get_range()),
         }
     }
+
+    fn hint(&self) -> Option<String> {
+        match self {
+            ConfigParseError::UnknownKey(_, _, similar)
+            | ConfigParseError::UnknownModifier(_, _, similar) => {
+                similar.as_ref().map(|s| format!("did you mean '{}'?", s))
+            }
+            ConfigParseError::InvalidVariable(..) => {
+                Some("variables are defined with 'define NAME = value'".into())
+            }
+            ConfigParseError::InvalidMode(..) => {
+                Some("modes are defined with 'mode NAME [ABORT_KEYSYM] {', and end with '}'".into())
+            }
+            ConfigParseError::InvalidAnnotation(..) => Some(
+                "the annotations are 'when CONDITION...', 'tags: TAG, ...', 'icon: ICON', \
+                 'title: TITLE' and 'hidden'"
+                    .into(),
+            ),
+            _ => None,
+        }
+    }
+
+    fn contextualize(&self, context: &[u8]) -> String {
+        Diagnostic::from_parse_error(self, context, None).to_string()
+    }
 }
 
 impl Display for ConfigParseError {
@@ -162,33 +200,55 @@ impl Display for ConfigParseError {
             ConfigParseError::InvalidToken(token, slice, desc) => {
                 f.write_fmt(format_args!("{}: {:?}, ({})", desc, token, slice))?;
             }
-            ConfigParseError::UnterminatedBinding(_)
-            | ConfigParseError::UnterminatedGroup(_)
-            | ConfigParseError::UnterminatedCommand(_)
-            | ConfigParseError::UnterminatedComment(_) => {
-                f.write_str(&format!("Unterminated item: {:?}", self))?
+            ConfigParseError::UnterminatedBinding(_) => f.write_str("Unterminated binding")?,
+            ConfigParseError::UnterminatedGroup(_) => {
+                f.write_str("Unterminated group, expected '}'")?
             }
+            ConfigParseError::UnterminatedCommand(_) => f.write_str("Unterminated command")?,
+            ConfigParseError::UnterminatedComment(_) => f.write_str("Unterminated comment")?,
             ConfigParseError::InvalidRange(r) => f.write_str(&format!("{:?}", r))?,
-            ConfigParseError::SyntaxError(s) => f.write_str(s)?,
+            ConfigParseError::SyntaxError(_, s) => f.write_str(s)?,
             ConfigParseError::InvalidBinding(_, s) => {
                 f.write_str(&format!("Invalid Binding: {}", s))?
             }
-            ConfigParseError::GroupMappingMismatch(s) => {
+            ConfigParseError::GroupMappingMismatch(_, s) => {
                 f.write_str(&format!("Group Mapping Mismatch:\n{}", s))?
             }
+            ConfigParseError::InvalidInclude(_, s) => {
+                f.write_str(&format!("Invalid include: {}", s))?
+            }
+            ConfigParseError::IncludeCycle(s) => {
+                f.write_str(&format!("Include cycle detected: {}", s))?
+            }
+            ConfigParseError::InvalidVariable(_, s) => f.write_str(s)?,
+            ConfigParseError::UnknownKey(_, key, _) => {
+                f.write_str(&format!("Unrecognized key '{}'.", key))?
+            }
+            ConfigParseError::UnknownModifier(_, modifier, _) => {
+                f.write_str(&format!("Unrecognized modifier '{}'.", modifier))?
+            }
+            ConfigParseError::InvalidMode(_, s) => f.write_str(&format!("Invalid mode: {}", s))?,
+            ConfigParseError::InvalidAction(_, s) => {
+                f.write_str(&format!("Invalid action: {}", s))?
+            }
+            ConfigParseError::InvalidAnnotation(_, s) => {
+                f.write_str(&format!("Invalid annotation: {}", s))?
+            }
             _ => f.write_str(&format!("{:?}", self))?,
         };
         Ok(())
     }
 }
 
-pub fn parse_chord_chain(chords: &str) -> anyhow::Result<Vec<Chord>> {
+pub fn parse_chord_chain(chords: &str, keymap: &dyn Keymap) -> anyhow::Result<Vec<Chord>> {
     let bytes = chords.as_bytes();
     match scanner::Scanner::scan(bytes) {
-        Ok(tokens) => hotkey_parser::chord_from_tokens(&tokens, bytes),
+        Ok(tokens) => hotkey_parser::chord_from_tokens(&tokens, bytes, keymap),
         Err(e) => match e.downcast_ref::<ConfigParseError>() {
             None => Err(e),
-            Some(c) => Err(ChordParseError::Contextual(c.contextualize(bytes)))?,
+            Some(c) => Err(ChordParseError::Contextual(Diagnostic::from_parse_error(
+                c, bytes, None,
+            )))?,
         },
     }
 }
@@ -198,6 +258,8 @@ impl std::error::Error for ConfigParseError {}
 #[allow(unused)]
 mod scanner_test {
     use super::*;
+    use crate::keyboard::DefaultKeymap;
+    use crate::rhkc::ipc::BindCommand;
     use anyhow::Result;
 
     #[test]
@@ -318,7 +380,7 @@ super + {a,b} ; {1-3}
                 Token::EmptyLine(_),
             ]
         ));
-        let tree = super::token_parser::Parser::build(rule, &tokens)?;
+        let tree = super::token_parser::Parser::build(rule, &tokens, &DefaultKeymap)?;
         let (hotkeys, errors) = tree.get_hotkeys();
         assert_eq!(0, errors.len());
         assert_eq!(6, hotkeys.len());
@@ -384,6 +446,25 @@ super + {a,b} ; {1-3}
             println!("{:?}: '{}'", token, slice);
         }
     }
+    // A binding added over IPC, without any of the optional properties
+    fn bind_command(hotkey: &str, command: &str) -> BindCommand {
+        BindCommand {
+            overwrite: false,
+            hotkey: hotkey.into(),
+            command: command.into(),
+            title: None,
+            description: None,
+            tags: vec![],
+            icon: None,
+            hidden: false,
+            mode: None,
+            conditions: vec![],
+            count: None,
+            timeout: None,
+            exec: false,
+            repeat: None,
+        }
+    }
     fn print_errors(errors: &[anyhow::Error], context: &[u8]) {
         for error in errors {
             if let Some(e) = error.downcast_ref::<ConfigParseError>() {
@@ -400,7 +481,7 @@ super + {_,shift +}c
 	bspc node -f {next,prev}.local.!hidden.window
 ";
         let tokens = Scanner::scan(rule)?;
-        let tree = super::token_parser::Parser::build(rule, &tokens)?;
+        let tree = super::token_parser::Parser::build(rule, &tokens, &DefaultKeymap)?;
         let (hotkeys, errors) = tree.get_hotkeys();
         print_errors(errors, rule);
         assert_eq!(0, errors.len());
@@ -434,7 +515,7 @@ super + {_,shift +}c
   bspc {desktop -f, node -d} '^{1-9,10}'
 ";
         let tokens = Scanner::scan(rule)?;
-        let tree = super::token_parser::Parser::build(rule, &tokens)?;
+        let tree = super::token_parser::Parser::build(rule, &tokens, &DefaultKeymap)?;
         let (hotkeys, errors) = tree.get_hotkeys();
         print_errors(errors, rule);
         assert_eq!(20, hotkeys.len());
@@ -458,7 +539,7 @@ super + {_,shift +}c
         let rule = b"super + Tab : bracket{left,right}
 	bspc desktop -f {prev,next}.local";
         let tokens = Scanner::scan(rule)?;
-        let tree = super::token_parser::Parser::build(rule, &tokens)?;
+        let tree = super::token_parser::Parser::build(rule, &tokens, &DefaultKeymap)?;
         let (hotkeys, errors) = tree.get_hotkeys();
         print_errors(errors, rule);
 
@@ -490,7 +571,7 @@ super + {_,shift +}c
   echo {shift,super}
 ";
         let tokens = Scanner::scan(rule)?;
-        let tree = super::token_parser::Parser::build(rule, &tokens)?;
+        let tree = super::token_parser::Parser::build(rule, &tokens, &DefaultKeymap)?;
         let (hotkeys, errors) = tree.get_hotkeys();
         print_errors(errors, rule);
         assert_eq!(2, hotkeys.len());
@@ -515,7 +596,7 @@ super + {_,shift +}c
         let rule = b"a
   echo {1,2} {3,4} {5,6}";
         let tokens = Scanner::scan(rule)?;
-        let tree = super::token_parser::Parser::build(rule, &tokens)?;
+        let tree = super::token_parser::Parser::build(rule, &tokens, &DefaultKeymap)?;
         let (hotkeys, errors) = tree.get_hotkeys();
         print_errors(errors, rule);
 
@@ -568,7 +649,7 @@ super + shift + e ; { 1, 2, 3, 4, 5}
 "
         .as_bytes();
         let tokens = Scanner::scan(rule)?;
-        let tree = super::token_parser::Parser::build(rule, &tokens)?;
+        let tree = super::token_parser::Parser::build(rule, &tokens, &DefaultKeymap)?;
         let (hotkeys, errors) = tree.get_hotkeys();
         print_errors(errors, rule);
         assert_eq!(0, errors.len());
@@ -583,7 +664,7 @@ super + t : t
   bsp-layout set tiled ; bspc node -t tiled
 ";
         let tokens = Scanner::scan(rule)?;
-        let tree = super::token_parser::Parser::build(rule, &tokens)?;
+        let tree = super::token_parser::Parser::build(rule, &tokens, &DefaultKeymap)?;
         let (hotkeys, errors) = tree.get_hotkeys();
         print_errors(errors, rule);
         assert_eq!(0, errors.len());
@@ -597,6 +678,1208 @@ super + t : t
         Ok(())
     }
 
+    #[test]
+    fn test_buttons() -> Result<()> {
+        let rule = b"super + {button1,@button3,button24}
+  echo {left,right,extra}
+";
+        let tokens = Scanner::scan(rule)?;
+        let tree = super::token_parser::Parser::build(rule, &tokens, &DefaultKeymap)?;
+        let (hotkeys, errors) = tree.get_hotkeys();
+        print_errors(errors, rule);
+        assert_eq!(0, errors.len());
+        assert_eq!(3, hotkeys.len());
+        assert_eq!(hotkeys[0].chain[0].button, 1);
+        assert_eq!(hotkeys[0].chain[0].keysym, 0);
+        assert_eq!(hotkeys[1].chain[0].button, 3);
+        assert!(!hotkeys[1].chain[0].event_type.is_key_press());
+        assert_eq!(hotkeys[2].chain[0].button, 24);
+        assert_eq!(hotkeys[2].chain[0].repr.to_string(), "super + button24");
+
+        let rule = b"super + button25
+  echo invalid
+";
+        let tokens = Scanner::scan(rule)?;
+        let tree = super::token_parser::Parser::build(rule, &tokens, &DefaultKeymap)?;
+        let (hotkeys, errors) = tree.get_hotkeys();
+        assert_eq!(0, hotkeys.len());
+        assert_eq!(1, errors.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_include_token() -> Result<()> {
+        let rule = b"include ~/.config/rhkd/*.rc  \n  include\tnot_an_include\ninclude\n";
+        let tokens = Scanner::scan(rule)?;
+        assert!(matches!(
+            &tokens[..],
+            [
+                Token::Include(_),
+                Token::StartCommand(_),
+                Token::Text(_),
+                Token::EndCommand(_),
+                Token::Include(_),
+            ]
+        ));
+        assert_eq!(tokens[0].get_string(rule), "~/.config/rhkd/*.rc");
+        assert_eq!(tokens[4].get_string(rule), "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_include_files() -> Result<()> {
+        let dir = crate::TempDir::new("include_test")?;
+        let drop_ins = dir.join("rhkdrc.d");
+        std::fs::create_dir_all(&drop_ins)?;
+        let main = dir.join("rhkdrc");
+        std::fs::write(&main, "a\n  echo a\ninclude extra/*.rc\nb\n  echo b\n")?;
+        std::fs::create_dir_all(dir.join("extra"))?;
+        std::fs::write(dir.join("extra/2.rc"), "d\n  echo d\n")?;
+        std::fs::write(
+            dir.join("extra/1.rc"),
+            "c\n  echo c\ninclude ../rhkdrc.d/z.rc\n",
+        )?;
+        std::fs::write(dir.join("extra/ignored.txt"), "x\n  echo x\n")?;
+        std::fs::write(dir.join("rhkdrc.d/z.rc"), "z\n  echo z\n")?;
+
+        let config = config::load_config_with_drop_ins(
+            main.to_str(),
+            Some(drop_ins.clone()),
+            &DefaultKeymap,
+        )?;
+        let commands: Vec<_> = config
+            .get_hotkeys()
+            .iter()
+            .map(|hk| hk.command.to_string())
+            .collect();
+        // z.rc is only loaded once, even though it is also a drop-in file
+        assert_eq!(commands, ["echo a", "echo c", "echo z", "echo d", "echo b"]);
+        assert_eq!(config.files().len(), 4);
+
+        std::fs::write(dir.join("rhkdrc.d/z.rc"), "include ../rhkdrc\n")?;
+        let err = config::load_config_with_drop_ins(
+            main.to_str(),
+            Some(drop_ins.clone()),
+            &DefaultKeymap,
+        )
+        .err()
+        .unwrap();
+        assert!(err
+            .chain()
+            .any(|e| matches!(e.downcast_ref(), Some(ConfigParseError::IncludeCycle(_)))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_variables() -> Result<()> {
+        let rule = b"define MOD = super + shift
+define TERM = alacritty
+# Open {htop,top} in $TERM
+$MOD + {h,t}
+  $TERM -e {htop,top} $HOME
+
+$UNDEFINED_RHKD_VARIABLE + a
+  echo a
+
+define BROKEN
+";
+        let tokens = Scanner::scan(rule)?;
+        let tree = super::token_parser::Parser::build(rule, &tokens, &DefaultKeymap)?;
+        let (hotkeys, errors) = tree.get_hotkeys();
+        print_errors(errors, rule);
+        assert_eq!(2, hotkeys.len());
+        assert_eq!(hotkeys[0].chain[0].repr.to_string(), "super + shift + h");
+        assert_eq!(hotkeys[1].chain[0].repr.to_string(), "super + shift + t");
+        assert_eq!(hotkeys[0].command.to_string(), "alacritty -e htop $HOME");
+        assert_eq!(hotkeys[1].description, Some("Open top in alacritty".into()));
+        assert_eq!(2, errors.len());
+        for error in errors {
+            assert!(matches!(
+                error.downcast_ref(),
+                Some(ConfigParseError::InvalidVariable(_, _))
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialize() -> Result<()> {
+        let rule = b"# Windows
+# Focus the window {left,down,up,right}
+super + {h,j,k,l}
+  bspc node -f {west,south,north,east}
+
+super + {_,shift + }Return
+  {alacritty,alacritty --class floating}
+
+super + c
+  {xdotool key a,xdotool key b,xdotool key c}
+
+super + x : {1-3}
+  ;echo \\{\\} {x,y,z}
+
+super + button1
+  echo 1
+
+super + e
+  \\;echo not sync
+";
+        let tokens = Scanner::scan(rule)?;
+        let tree = super::token_parser::Parser::build(rule, &tokens, &DefaultKeymap)?;
+        let (hotkeys, errors) = tree.get_hotkeys();
+        print_errors(errors, rule);
+        assert!(errors.is_empty());
+        let last = hotkeys.last().unwrap();
+        assert!(!last.sync);
+        assert_eq!(";echo not sync", &*last.command);
+
+        let text = super::serializer::to_rhkdrc(hotkeys, &[], &[], &DefaultKeymap);
+        println!("{}", text);
+        assert!(text.contains("super + {h,j,k,l}\n  bspc node -f {west,south,north,east}"));
+        assert!(text.contains("# Focus the window {left,down,up,right}"));
+        assert!(text.contains("super + e\n  \\;echo not sync"));
+
+        let tokens = Scanner::scan(text.as_bytes())?;
+        let tree = super::token_parser::Parser::build(text.as_bytes(), &tokens, &DefaultKeymap)?;
+        let (reparsed, errors) = tree.get_hotkeys();
+        assert!(errors.is_empty());
+        assert_eq!(hotkeys.len(), reparsed.len());
+        for (a, b) in hotkeys.iter().zip(reparsed) {
+            assert_eq!(a.chain, b.chain);
+            assert_eq!(a.command, b.command);
+            assert_eq!(a.sync, b.sync);
+            assert_eq!(a.title, b.title);
+            assert_eq!(a.description, b.description);
+            assert_eq!(
+                a.cycle.as_ref().map(|c| c.period),
+                b.cycle.as_ref().map(|c| c.period)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_diagnostics() -> Result<()> {
+        let rule = b"super + a
+  echo a
+
+super + retrun
+  echo \"b\"
+
+supr + b
+  echo c
+";
+        let tokens = Scanner::scan(rule)?;
+        let tree = super::token_parser::Parser::build(rule, &tokens, &DefaultKeymap)?;
+        let (hotkeys, errors) = tree.get_hotkeys();
+        assert_eq!(1, hotkeys.len());
+        assert_eq!(2, errors.len());
+        let diagnostics: Vec<_> = errors
+            .iter()
+            .map(|e| Diagnostic::from_error(e, rule, None))
+            .collect();
+
+        let key = &diagnostics[0];
+        let span = key.span.as_ref().unwrap();
+        assert_eq!((4, 9), (span.line, span.column));
+        assert_eq!("super + retrun", span.source_line);
+        assert_eq!(Some("did you mean 'Return'?"), key.hint.as_deref());
+        let rendered = key.to_string();
+        println!("{}", rendered);
+        assert!(rendered.contains("--> <input>:4:9"));
+        assert!(rendered.contains("4 | super + retrun\n  |         ^^^^^^\n"));
+        assert_eq!(
+            key.to_json(),
+            r#"{"severity":"error","message":"Unrecognized key 'retrun'.","hint":"did you mean 'Return'?","line":4,"column":9,"start":28,"end":34}"#
+        );
+        assert_eq!(
+            key.to_line(),
+            "<input>:4:9: error: Unrecognized key 'retrun'."
+        );
+
+        let modifier = diagnostics[1].span.as_ref().unwrap();
+        assert_eq!((7, 1), (modifier.line, modifier.column));
+        assert_eq!(4, modifier.width);
+
+        let err = parse_chord_chain("super + {a,retrun}", &DefaultKeymap)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("^^^^^^^^^^"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fatal_diagnostics() -> Result<()> {
+        let dir = crate::TempDir::new("fatal_test")?;
+        let main = dir.join("rhkdrc");
+        std::fs::write(&main, "a\n  echo a\n\ninclude missing.rc\n")?;
+        let err = config::load_config_with_drop_ins(main.to_str(), None, &DefaultKeymap)
+            .err()
+            .unwrap();
+        let diagnostic = Diagnostic::from_fatal(&err);
+        assert_eq!(diagnostic.file.as_deref(), Some(main.as_path()));
+        assert_eq!(diagnostic.span.map(|s| (s.line, s.column)), Some((4, 9)));
+
+        std::fs::write(dir.join("unterminated.rc"), "super + {a\n  echo a\n")?;
+        std::fs::write(&main, "include unterminated.rc\n")?;
+        let err = config::load_config_with_drop_ins(main.to_str(), None, &DefaultKeymap)
+            .err()
+            .unwrap();
+        let diagnostic = Diagnostic::from_fatal(&err);
+        assert_eq!(diagnostic.file, Some(dir.join("unterminated.rc")));
+        assert_eq!(diagnostic.span.map(|s| (s.line, s.column)), Some((1, 10)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_analyze() -> Result<()> {
+        use super::analysis::ConflictKind::*;
+        let rule = b"super + a
+  echo a
+
+super + {b,a}
+  echo {b,c}
+
+super + x
+  echo x
+
+super + x ; y
+  echo y
+
+super + l : a
+  echo a
+
+super + l ; b
+  echo b
+
+super + ~r ; a
+  echo a
+
+super + r ; b
+  echo b
+
+super + c
+  echo {1,2,3}
+";
+        let config = config::load_config_from_bytes(rule, &DefaultKeymap)?;
+        let conflicts = config.analyze();
+        let found: Vec<_> = conflicts
+            .iter()
+            .map(|c| (c.kind, c.hotkey.chain_repr(), c.other.chain_repr()))
+            .collect();
+        let expected = [
+            (Duplicate, "super + a", "super + a"),
+            (LockMismatch, "super + l ; b", "super + l : a"),
+            (ReplayMismatch, "super + r ; b", "super + ~r ; a"),
+            (Unreachable, "super + x ; y", "super + x"),
+        ];
+        assert_eq!(expected.len(), found.len());
+        for (kind, hotkey, other) in expected {
+            assert!(found.contains(&(kind, hotkey.into(), other.into())));
+        }
+
+        let diagnostic = conflicts[0].to_diagnostic();
+        assert_eq!(Severity::Warning, diagnostic.severity);
+        assert_eq!(Some((4, 1)), diagnostic.span.map(|s| (s.line, s.column)));
+        assert!(diagnostic
+            .hint
+            .unwrap()
+            .ends_with("is bound at <input>:1:1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_keymap() -> Result<()> {
+        use crate::keyboard::Keymap;
+        use xcb::x::ModMask;
+
+        // A layout where super is on mod3 instead of mod4
+        struct Mod3Super;
+        impl Keymap for Mod3Super {
+            fn modfield_from_keysym(&self, keysym: &str) -> u32 {
+                match keysym {
+                    "Super_L" | "Super_R" => ModMask::N3.bits(),
+                    _ => DefaultKeymap.modfield_from_keysym(keysym),
+                }
+            }
+        }
+
+        let chain = parse_chord_chain("super + alt + a", &DefaultKeymap)?;
+        assert_eq!((ModMask::N4 | ModMask::N1).bits(), chain[0].modfield.bits());
+        let chain = parse_chord_chain("super + alt + a", &Mod3Super)?;
+        assert_eq!((ModMask::N3 | ModMask::N1).bits(), chain[0].modfield.bits());
+
+        // Bindings removed at runtime are parsed against the keymap of the config
+        let mut config = config::load_config_from_bytes(b"super + a\n  echo a", &Mod3Super)?;
+        assert_eq!(
+            ModMask::N3.bits(),
+            config.get_hotkeys()[0].chain[0].modfield.bits()
+        );
+        let removed = config.delete_bindings(&crate::rhkc::ipc::UnbindCommand {
+            hotkey: "super + a".into(),
+            mode: None,
+            command: None,
+            conditions: vec![],
+        })?;
+        assert_eq!(1, removed.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_modes() -> Result<()> {
+        let rule = b"super + r
+  @rhkd mode resize
+
+mode resize Return {
+{h,l}
+  bspc node -z {left,right} -20 0
+
+super + r
+  @rhkd mode default
+}
+";
+        let config = config::load_config_from_bytes(rule, &DefaultKeymap)?;
+        assert!(config.diagnostics().is_empty());
+        assert!(config.analyze().is_empty());
+        let resize = config.get_mode("resize").unwrap();
+        assert_eq!(Some("Return"), resize.abort.as_deref());
+        let hotkeys = config.get_hotkeys();
+        assert_eq!(4, hotkeys.len());
+        assert_eq!(None, hotkeys[0].mode);
+        assert_eq!(Some(Action::Mode("resize".into())), hotkeys[0].action);
+        assert!(hotkeys[1..]
+            .iter()
+            .all(|hk| hk.mode == Some("resize".into())));
+        assert_eq!(None, hotkeys[1].action);
+        assert_eq!(Some(Action::Mode("default".into())), hotkeys[3].action);
+
+        // Modes are written back as blocks
+        let text = config.to_rhkdrc();
+        let reloaded = config::load_config_from_bytes(text.as_bytes(), &DefaultKeymap)?;
+        assert_eq!(text, reloaded.to_rhkdrc());
+        assert_eq!(config.modes(), reloaded.modes());
+        assert_eq!(hotkeys.len(), reloaded.get_hotkeys().len());
+
+        let rule = b"a
+  @rhkd mode missing
+
+b
+  @rhkd frobnicate
+
+mode {
+}
+
+mode default {
+}
+
+mode outer {
+include other.rc
+mode inner {
+}
+
+}
+
+mode unterminated Escpe {
+";
+        let config = config::load_config_from_bytes(rule, &DefaultKeymap)?;
+        let messages: Vec<_> = config
+            .diagnostics()
+            .iter()
+            .map(|d| d.message.as_str())
+            .collect();
+        assert_eq!(
+            vec![
+                "Invalid action: Unknown action 'frobnicate'",
+                "Invalid mode: Expected 'mode NAME [ABORT_KEYSYM] {'",
+                "Invalid mode: 'default' is reserved for leaving a mode",
+                "Invalid include: Files cannot be included inside a mode",
+                "Invalid mode: Modes cannot be nested, 'outer' is not closed",
+                "Invalid mode: Unexpected '}' outside of a mode",
+                "Invalid mode: Unrecognized key 'Escpe', but 'Escape' is similar.",
+                "Invalid mode: Unterminated mode, expected '}'",
+                "Unknown mode 'missing'",
+            ],
+            messages
+        );
+        assert!(config.get_hotkeys().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_conditions() -> Result<()> {
+        use crate::keyboard::WindowClass;
+        let rule = b"# Close tab
+#@ when class=firefox
+ctrl + w
+  xdotool key ctrl+F4
+
+ctrl + w
+  echo fallback
+
+#@ when class!=Alacritty instance=main
+super + {a,b}
+  echo {a,b}
+";
+        let config = config::load_config_from_bytes(rule, &DefaultKeymap)?;
+        assert!(config.diagnostics().is_empty());
+        // A binding for other windows is not a duplicate
+        assert!(config.analyze().is_empty());
+        let hotkeys = config.get_hotkeys();
+        assert_eq!(4, hotkeys.len());
+        assert_eq!(Some("Close tab".into()), hotkeys[0].description);
+        assert_eq!(
+            vec![Condition::parse("class=firefox").unwrap()],
+            hotkeys[0].conditions.to_vec()
+        );
+        assert!(hotkeys[1].conditions.is_empty());
+        assert_eq!(2, hotkeys[3].conditions.len());
+        assert!(hotkeys[3].conditions[0].negated);
+
+        let window = |instance: &str, class: &str| WindowClass {
+            instance: instance.into(),
+            class: class.into(),
+        };
+        let matches = |hk: &Hotkey, window: Option<&WindowClass>| {
+            hk.conditions.iter().all(|c| c.matches(window))
+        };
+        assert!(matches(&hotkeys[0], Some(&window("Navigator", "firefox"))));
+        assert!(!matches(&hotkeys[0], Some(&window("main", "Alacritty"))));
+        assert!(!matches(&hotkeys[0], None));
+        assert!(matches(&hotkeys[3], Some(&window("main", "kitty"))));
+        assert!(!matches(&hotkeys[3], Some(&window("main", "Alacritty"))));
+
+        // Conditions are written back as annotations
+        let text = config.to_rhkdrc();
+        assert!(text.contains("#@ when class=firefox\nctrl + w\n"));
+        let reloaded = config::load_config_from_bytes(text.as_bytes(), &DefaultKeymap)?;
+        assert_eq!(text, reloaded.to_rhkdrc());
+
+        let rule = b"#@ when title=foo
+a
+  echo a
+
+#@ when
+b
+  echo b
+
+#@ unless class=foo
+c
+  echo c
+
+#@ when class=foo
+
+d
+  echo d
+";
+        let config = config::load_config_from_bytes(rule, &DefaultKeymap)?;
+        let messages: Vec<_> = config
+            .diagnostics()
+            .iter()
+            .map(|d| d.message.as_str())
+            .collect();
+        assert_eq!(
+            vec![
+                "Invalid annotation: Unknown window property 'title', expected 'class' or 'instance'",
+                "Invalid annotation: Expected a condition after 'when'",
+                "Invalid annotation: Unknown annotation 'unless'",
+                "Invalid annotation: Expected a binding after the annotation",
+            ],
+            messages
+        );
+        assert_eq!(4, config.get_hotkeys().len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_annotations() -> Result<()> {
+        use std::sync::Arc;
+        let rule = "# Media
+# Raise volume
+#@ tags: media, audio
+#@ icon: 󰕾
+XF86AudioRaiseVolume
+  pamixer -i 5
+
+# Echo {a,b}
+#@title: Launch
+#@ hidden
+super + {a,b}
+  echo {a,b}
+";
+        let mut config = config::load_config_from_bytes(rule.as_bytes(), &DefaultKeymap)?;
+        assert!(config.diagnostics().is_empty());
+        let hotkeys = config.get_hotkeys();
+        assert_eq!(3, hotkeys.len());
+        assert_eq!(Some("Media".into()), hotkeys[0].title);
+        assert_eq!(Some("Raise volume".into()), hotkeys[0].description);
+        assert_eq!(
+            vec![Arc::<str>::from("media"), "audio".into()],
+            hotkeys[0].tags.to_vec()
+        );
+        assert_eq!(Some("󰕾".into()), hotkeys[0].icon);
+        assert!(!hotkeys[0].hidden);
+        assert!(hotkeys[1..].iter().all(|hk| hk.hidden));
+        assert_eq!(Some("Launch".into()), hotkeys[1].title);
+        assert_eq!(Some("Echo a".into()), hotkeys[1].description);
+
+        // Annotations are written back, and bindings added over IPC can have them
+        let text = config.to_rhkdrc();
+        assert!(text.contains("#@ tags: media, audio\n#@ icon: 󰕾\nXF86AudioRaiseVolume"));
+        let reloaded = config::load_config_from_bytes(text.as_bytes(), &DefaultKeymap)?;
+        assert_eq!(text, reloaded.to_rhkdrc());
+        let result = config.add_bindings(&BindCommand {
+            tags: vec!["launch".into()],
+            hidden: true,
+            ..bind_command("super + c", "echo c")
+        })?;
+        assert_eq!(1, result.added.len());
+        assert_eq!(
+            vec![Arc::<str>::from("launch")],
+            result.added[0].tags.to_vec()
+        );
+        assert!(result.added[0].hidden);
+
+        let rule = b"#@ hidden yes
+#@ tags: ,
+#@ icon
+a
+  echo a
+";
+        let config = config::load_config_from_bytes(rule, &DefaultKeymap)?;
+        let messages: Vec<_> = config
+            .diagnostics()
+            .iter()
+            .map(|d| d.message.as_str())
+            .collect();
+        assert_eq!(
+            vec![
+                "Invalid annotation: 'hidden' does not take a value",
+                "Invalid annotation: Expected a list of tags after 'tags'",
+                "Invalid annotation: Expected a value after 'icon'",
+            ],
+            messages
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff() -> Result<()> {
+        let old = b"# Echo {a,b}
+super + {a,b}
+  echo {a,b}
+
+# Volume
+super + v
+
+# Brightness
+super + n
+
+# Terminal
+super + Return
+  alacritty
+
+#@ when class=firefox
+ctrl + w
+  xdotool key ctrl+F4 \\{\\}
+
+#@ when class=kitty
+ctrl + w
+  xdotool key ctrl+F4 \\{\\}
+
+mode resize {
+h
+  bspc node -z left -20 0
+}
+";
+        let new = b"# Echo {a,c}
+super + {a,c}
+  echo {a,c}
+
+# Sound
+super + v
+
+# Brightness
+super + n
+
+# Open a terminal
+super + Return
+  alacritty
+
+#@ title: Close tab
+#@ when class=firefox
+ctrl + w
+  xdotool key ctrl+F4 \\{\\}
+
+mode resize {
+h
+  ;bspc node -z left -20 0
+}
+";
+        let mut config = config::load_config_from_bytes(old, &DefaultKeymap)?;
+        let reloaded = config::load_config_from_bytes(new, &DefaultKeymap)?;
+        let diff = diff::diff(config.get_hotkeys(), reloaded.get_hotkeys());
+        let chains = |hotkeys: &[Hotkey]| -> Vec<String> {
+            hotkeys.iter().map(|hk| hk.chain_repr()).collect()
+        };
+        assert_eq!(vec!["super + b", "ctrl + w"], chains(&diff.removed));
+        assert_eq!(vec!["super + c"], chains(&diff.added));
+        // Hotkeys which run the same command with the same keys for the same windows are changed
+        // in place
+        assert_eq!(3, diff.changed.len());
+        assert_eq!(
+            Some("Open a terminal".into()),
+            diff.changed[0].1.description
+        );
+        assert_eq!(Some("Close tab".into()), diff.changed[1].1.title);
+        assert!(diff.changed[2].1.sync);
+        // Only the binding for the other windows is removed
+        assert_eq!(
+            vec!["class=kitty".to_string()],
+            diff.unbind_commands()[1].conditions
+        );
+
+        // Applying the diff as IPC commands gives the new hotkeys
+        for unbind in diff.unbind_commands() {
+            assert_eq!(1, config.delete_bindings(&unbind)?.len());
+        }
+        for bind in diff.bind_commands() {
+            let result = config.add_bindings(&bind)?;
+            assert!(result.errors.is_empty());
+        }
+        let without_source = |config: &config::Config| -> Vec<Hotkey> {
+            let mut hotkeys: Vec<_> = config
+                .get_hotkeys()
+                .iter()
+                .map(|hk| Hotkey {
+                    source: None,
+                    ..hk.clone()
+                })
+                .collect();
+            hotkeys.sort_by_key(|hk| (hk.chain_repr(), hk.command.clone()));
+            hotkeys
+        };
+        assert_eq!(without_source(&reloaded), without_source(&config));
+        assert!(diff::diff(config.get_hotkeys(), reloaded.get_hotkeys()).is_empty());
+
+        // A prefix whose title changes is removed and added again
+        let prefixes = diff::diff_prefixes(config.prefixes(), reloaded.prefixes());
+        let titles = |prefixes: &[Prefix]| -> Vec<String> {
+            prefixes.iter().map(|p| p.title.to_string()).collect()
+        };
+        assert_eq!(vec!["Volume"], titles(&prefixes.removed));
+        assert_eq!(vec!["Sound"], titles(&prefixes.added));
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_new_mode() -> Result<()> {
+        let old = b"super + a
+  echo a
+";
+        let new = b"super + a
+  echo a
+
+super + r
+  @rhkd mode resize
+
+mode resize Escape {
+h
+  bspc node -z left -20 0
+}
+";
+        let mut config = config::load_config_from_bytes(old, &DefaultKeymap)?;
+        let reloaded = config::load_config_from_bytes(new, &DefaultKeymap)?;
+        let diff = diff::diff(config.get_hotkeys(), reloaded.get_hotkeys());
+        let modes = diff::diff_modes(config.modes(), reloaded.modes());
+        assert!(modes.removed.is_empty());
+        assert_eq!(1, modes.added.len());
+
+        // The mode is added first, so the binding which switches to it is kept
+        config.modes_mut().extend(modes.added);
+        for bind in diff.bind_commands() {
+            let result = config.add_bindings(&bind)?;
+            assert!(result.errors.is_empty());
+        }
+        assert!(diff::diff(config.get_hotkeys(), reloaded.get_hotkeys()).is_empty());
+        let mode = config.get_mode("resize").unwrap();
+        assert_eq!(Some("Escape".into()), mode.abort);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefixes() -> Result<()> {
+        let rule = b"# Brightness
+super + b
+
+# {Volume,Media}
+super + {v,m}
+# Increase
+super + b : l
+  backlight --inc 5
+
+#@ title: Layout
+super + l
+super + l ; {h,v}
+  layout {h,v}
+
+super + x
+
+mode resize {
+# Grow
+g
+}
+";
+        let config = config::load_config_from_bytes(rule, &DefaultKeymap)?;
+        assert!(config.diagnostics().is_empty());
+        let titles: Vec<_> = config
+            .prefixes()
+            .iter()
+            .map(|p| (p.chain_repr(), p.title.to_string()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("super + b".to_string(), "Brightness".to_string()),
+                ("super + v".to_string(), "Volume".to_string()),
+                ("super + m".to_string(), "Media".to_string()),
+                ("super + l".to_string(), "Layout".to_string()),
+                ("g".to_string(), "Grow".to_string()),
+            ],
+            titles
+        );
+        // The comment of a prefix does not leak into the next binding
+        assert_eq!(Some("Increase".into()), config.get_hotkeys()[0].description);
+        assert_eq!(3, config.get_hotkeys().len());
+
+        let chain = parse_chord_chain("super + b", &DefaultKeymap)?;
+        assert_eq!(Some("Brightness".into()), config.prefix_title(None, &chain));
+        assert_eq!(None, config.prefix_title(Some("resize"), &chain));
+        let chain = parse_chord_chain("g", &DefaultKeymap)?;
+        assert_eq!(
+            Some("Grow".into()),
+            config.prefix_title(Some("resize"), &chain)
+        );
+
+        // Prefixes are written back
+        let text = config.to_rhkdrc();
+        assert!(text.contains("# Brightness\nsuper + b\n"));
+        let reloaded = config::load_config_from_bytes(text.as_bytes(), &DefaultKeymap)?;
+        assert_eq!(config.prefixes().len(), reloaded.prefixes().len());
+
+        let rule = b"#@ icon: x
+super + b
+";
+        let config = config::load_config_from_bytes(rule, &DefaultKeymap)?;
+        let messages: Vec<_> = config.diagnostics().iter().map(|d| &*d.message).collect();
+        assert_eq!(
+            vec!["Invalid annotation: Only 'title' applies to a binding without a command"],
+            messages
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_index() -> Result<()> {
+        use crate::rhkc::ipc::UnbindCommand;
+        let rule = b"super + {a,b}
+  echo {a,b}
+super + c : {h,l}
+  echo {h,l}
+super + c ; x
+  echo x
+
+mode resize {
+super + a
+  echo resize
+}
+";
+        let mut config = config::load_config_from_bytes(rule, &DefaultKeymap)?;
+        let index = config.index();
+        let chords = |chain: &str| parse_chord_chain(chain, &DefaultKeymap);
+        let root = index.root(None).expect("the default mode is indexed");
+        assert_eq!(5, root.hotkeys.len());
+        let mut first: Vec<_> = root.children().map(|n| n.chord.repr.to_string()).collect();
+        first.sort();
+        assert_eq!(vec!["super + a", "super + b", "super + c"], first);
+
+        let node = index.find(None, &chords("super + c")?).expect("prefix");
+        assert_eq!(vec![2, 3, 4], node.hotkeys);
+        assert!(node.terminals.is_empty());
+        assert!(node.locking);
+        assert_eq!(3, node.children().count());
+        let node = index
+            .find(None, &chords("super + c ; l")?)
+            .expect("terminal");
+        assert_eq!(vec![3], node.terminals);
+        assert!(index.find(None, &chords("super + d")?).is_none());
+        assert_eq!(1, index.path(None, &chords("super + c ; y")?).len());
+
+        let node = index
+            .find(Some("resize"), &chords("super + a")?)
+            .expect("mode");
+        assert_eq!(vec![5], node.terminals);
+
+        // The index follows bindings which are added and removed at runtime
+        let removed = config.delete_bindings(&UnbindCommand {
+            hotkey: "super + c".into(),
+            mode: Some("default".into()),
+            command: None,
+            conditions: vec![],
+        })?;
+        assert_eq!(3, removed.len());
+        assert!(config.index().find(None, &chords("super + c")?).is_none());
+        let bind = |hotkey: &str| bind_command(hotkey, "echo");
+        let result = config.add_bindings(&bind("super + a ; b"))?;
+        assert!(matches!(
+            result.errors[..],
+            [config::AddBindingError::WouldInterfere { .. }]
+        ));
+        let result = config.add_bindings(&bind("super + c ; b"))?;
+        assert!(result.errors.is_empty());
+        let node = config.index().find(None, &chords("super + c ; b")?);
+        assert_eq!(Some(&vec![3]), node.map(|n| &n.terminals));
+        assert_eq!("super + c ; b", config.get_hotkeys()[3].chain_repr());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cycle_state() -> Result<()> {
+        use std::time::Duration;
+        let dir = crate::TempDir::new("cycle_test")?;
+        let main = dir.join("rhkdrc");
+        std::fs::write(
+            &main,
+            "super + c\n  echo {a,b,c}\nsuper + d\n  echo {x,y}\n",
+        )?;
+        let mut config = config::load_config_with_drop_ins(main.to_str(), None, &DefaultKeymap)?;
+        let next = |config: &config::Config, chain: &str| -> Option<String> {
+            let chords = parse_chord_chain(chain, &DefaultKeymap).ok()?;
+            config
+                .get_hotkeys()
+                .iter()
+                .filter(|hk| hk.chain.len() == chords.len())
+                .filter(|hk| {
+                    hk.chain
+                        .iter()
+                        .zip(chords.iter())
+                        .all(|(a, b)| a.eq_relaxed(b))
+                })
+                .find(|hk| config.cycles().is_next(hk))
+                .map(|hk| hk.command.to_string())
+        };
+        assert_eq!(Some("echo a".into()), next(&config, "super + c"));
+
+        // Running a command moves its cycle on, and leaves the hotkeys alone
+        let hotkeys = config.get_hotkeys().clone();
+        config.cycles_mut().advance(&hotkeys[0]);
+        config.cycles_mut().advance(&hotkeys[1]);
+        assert_eq!(Some("echo c".into()), next(&config, "super + c"));
+        assert_eq!(Some("echo x".into()), next(&config, "super + d"));
+        assert_eq!(&hotkeys, config.get_hotkeys());
+        config.cycles_mut().advance(&hotkeys[2]);
+        assert_eq!(Some("echo a".into()), next(&config, "super + c"));
+
+        config.cycles_mut().advance(&hotkeys[3]);
+        assert_eq!(Some("echo y".into()), next(&config, "super + d"));
+        assert_eq!(2, config.find_cycles(Some("super + d"), None)?.len());
+        assert_eq!(5, config.find_cycles(None, Some("default"))?.len());
+        assert!(config.find_cycles(None, Some("resize"))?.is_empty());
+        config.cycles_mut().reset(&hotkeys[4]);
+        assert_eq!(Some("echo x".into()), next(&config, "super + d"));
+
+        // The position survives reloads, and bindings added over IPC
+        config.cycles_mut().advance(&hotkeys[0]);
+        config.cycles_mut().advance(&hotkeys[3]);
+        std::fs::write(
+            &main,
+            "super + c\n  echo {a,b,c}\nsuper + d\n  echo {x,y,z}\n",
+        )?;
+        let mut config = config.reload()?;
+        assert_eq!(Some("echo b".into()), next(&config, "super + c"));
+        assert_eq!(Some("echo y".into()), next(&config, "super + d"));
+        config.add_bindings(&bind_command("super + e", "echo e"))?;
+        assert_eq!(Some("echo b".into()), next(&config, "super + c"));
+
+        // A cycle with fewer commands wraps around
+        std::fs::write(&main, "super + c\n  echo {a,b}\n")?;
+        let b = config.get_hotkeys()[1].clone();
+        config.cycles_mut().advance(&b);
+        let mut config = config.reload()?;
+        assert_eq!(Some("echo a".into()), next(&config, "super + c"));
+        let a = config.get_hotkeys()[0].clone();
+        config.cycles_mut().advance(&a);
+        assert_eq!(Some("echo b".into()), next(&config, "super + c"));
+
+        // Cycles start over once they were idle for long enough
+        config.cycles_mut().set_idle_reset(Some(Duration::ZERO));
+        assert_eq!(Some("echo a".into()), next(&config, "super + c"));
+        Ok(())
+    }
+    #[test]
+    fn test_count() -> Result<()> {
+        let rule = b"#@ count
+super + w ; {h,l}
+  bspc node -f {west,east}
+
+#@ count: repeat
+super + w ; x
+  xdotool key Delete
+
+super + w ; Return
+  echo no count
+
+#@ count: twice
+super + w ; y
+  echo y
+";
+        let mut config = config::load_config_from_bytes(rule, &DefaultKeymap)?;
+        let counts: Vec<_> = config.get_hotkeys().iter().map(|hk| hk.count).collect();
+        assert_eq!(
+            vec![
+                Some(CountMode::Pass),
+                Some(CountMode::Pass),
+                Some(CountMode::Repeat),
+                None,
+                None
+            ],
+            counts
+        );
+        let messages: Vec<_> = config
+            .diagnostics()
+            .iter()
+            .map(|d| d.message.as_str())
+            .collect();
+        assert_eq!(
+            vec!["Invalid annotation: Expected 'repeat' or nothing after 'count'"],
+            messages
+        );
+
+        // The count annotation is written back, and bindings added over IPC can have it
+        let text = config.to_rhkdrc();
+        assert!(text.contains("#@ count\nsuper + w ; {h,l}\n"));
+        assert!(text.contains("#@ count: repeat\nsuper + w ; x\n"));
+        let result = config.add_bindings(&BindCommand {
+            count: Some(CountMode::Repeat),
+            ..bind_command("super + w ; k", "bspc node -f north")
+        })?;
+        assert_eq!(Some(CountMode::Repeat), result.added[0].count);
+
+        let rule = b"#@ count
+super + w
+";
+        let config = config::load_config_from_bytes(rule, &DefaultKeymap)?;
+        assert_eq!(
+            "Invalid annotation: Only 'title' applies to a binding without a command",
+            config.diagnostics()[0].message
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_timeout() -> Result<()> {
+        use std::time::Duration;
+        let rule = b"#@ timeout: 2.5
+super + r
+  ;rofi -show run
+
+#@ timeout: never
+super + e
+  echo e
+
+#@ timeout: 1e20
+super + f
+  echo f
+
+#@ timeout: 1e19
+super + g
+  echo g
+";
+        let config = config::load_config_from_bytes(rule, &DefaultKeymap)?;
+        let hotkeys = config.get_hotkeys();
+        assert_eq!(Some(Duration::from_millis(2500)), hotkeys[0].timeout);
+        assert!(hotkeys[0].sync);
+        assert_eq!(None, hotkeys[1].timeout);
+        // Timeouts which do not fit in a Duration are rejected
+        assert_eq!(None, hotkeys[2].timeout);
+        let messages: Vec<_> = config.diagnostics().iter().map(|d| &*d.message).collect();
+        assert_eq!(
+            vec!["Invalid annotation: Expected a number of seconds after 'timeout'"; 2],
+            messages
+        );
+        assert!(hotkeys[3].timeout.is_some());
+        assert!(config
+            .to_rhkdrc()
+            .contains("#@ timeout: 2.5\nsuper + r\n  ;rofi -show run\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_exec() -> Result<()> {
+        let rule = b"#@ exec
+super + {a,b}
+  notify-send {first,second}
+
+#@ exec: yes
+super + c
+  echo c
+";
+        let config = config::load_config_from_bytes(rule, &DefaultKeymap)?;
+        let exec: Vec<_> = config.get_hotkeys().iter().map(|hk| hk.exec).collect();
+        assert_eq!(vec![true, true, false], exec);
+        assert_eq!(
+            "Invalid annotation: 'exec' does not take a value",
+            config.diagnostics()[0].message
+        );
+        assert!(config
+            .to_rhkdrc()
+            .contains("#@ exec\nsuper + {a,b}\n  notify-send {first,second}\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_repeat() -> Result<()> {
+        use std::time::Duration;
+        let rule = b"#@ repeat: never
+super + Return
+  alacritty
+
+#@ repeat: 0.5
+XF86AudioRaiseVolume
+  pamixer -i 5
+
+#@ repeat
+XF86AudioLowerVolume
+  pamixer -d 5
+
+#@ repeat: sometimes
+super + r
+  rofi -show run
+";
+        let config = config::load_config_from_bytes(rule, &DefaultKeymap)?;
+        let repeats: Vec<_> = config.get_hotkeys().iter().map(|hk| hk.repeat).collect();
+        let delay = Duration::from_millis(500);
+        assert_eq!(
+            vec![
+                Some(RepeatPolicy::Never),
+                Some(RepeatPolicy::After(delay)),
+                Some(RepeatPolicy::Always),
+                None
+            ],
+            repeats
+        );
+        assert_eq!(
+            "Invalid annotation: Expected 'always', 'never' or a number of seconds after 'repeat'",
+            config.diagnostics()[0].message
+        );
+        let text = config.to_rhkdrc();
+        assert!(text.contains("#@ repeat: never\nsuper + Return\n"));
+        assert!(text.contains("#@ repeat: 0.5\nXF86AudioRaiseVolume\n"));
+
+        assert!(RepeatPolicy::default().allows(Duration::ZERO));
+        assert!(!RepeatPolicy::Never.allows(Duration::from_secs(10)));
+        assert!(!RepeatPolicy::After(delay).allows(Duration::from_millis(499)));
+        // Delays which do not fit in a Duration are rejected
+        assert!("1e20".parse::<RepeatPolicy>().is_err());
+        assert!("-1".parse::<RepeatPolicy>().is_err());
+        assert!(RepeatPolicy::After(delay).allows(delay));
+        Ok(())
+    }
+
+    #[test]
+    fn test_actions() -> Result<()> {
+        let rule = b"super + {r,g,Escape}
+  @rhkd {reload,toggle-grab,abort}
+
+super + u
+  @rhkd unbind super + {a,b} ; c
+
+super + n
+  @rhkd notify  Hello there
+
+super + x
+  @rhkd-script
+
+super + h
+  @rhkd send ctrl + Left ; Return
+
+super + t
+  @rhkd type Hello world
+";
+        let config = config::load_config_from_bytes(rule, &DefaultKeymap)?;
+        assert!(config.diagnostics().is_empty());
+        let actions: Vec<_> = config
+            .get_hotkeys()
+            .iter()
+            .map(|hk| hk.action.clone())
+            .collect();
+        assert_eq!(
+            vec![
+                Some(Action::Reload),
+                Some(Action::ToggleGrab),
+                Some(Action::Abort),
+                Some(Action::Unbind("super + a ; c".into())),
+                Some(Action::Unbind("super + b ; c".into())),
+                Some(Action::Notify("Hello there".into())),
+                None,
+                Some(Action::Send(
+                    parse_chord_chain("ctrl + Left ; Return", &DefaultKeymap)?.into()
+                )),
+                Some(Action::Type("Hello world".into())),
+            ],
+            actions
+        );
+
+        let rule = b"a
+  @rhkd reload now
+
+b
+  @rhkd unbind
+
+c
+  @rhkd unbind super + frob
+
+d
+  @rhkd notify
+
+e
+  @rhkd
+
+f
+  @rhkd send
+
+g
+  @rhkd send super + button1
+
+h
+  @rhkd type
+";
+        let config = config::load_config_from_bytes(rule, &DefaultKeymap)?;
+        let messages: Vec<_> = config
+            .diagnostics()
+            .iter()
+            .map(|d| d.message.as_str())
+            .collect();
+        assert_eq!(8, messages.len());
+        assert_eq!(
+            "Invalid action: 'reload' does not take arguments",
+            messages[0]
+        );
+        assert_eq!("Invalid action: Expected '@rhkd unbind CHAIN'", messages[1]);
+        assert!(messages[2].starts_with("Invalid action: Invalid chain 'super + frob': "));
+        assert_eq!("Invalid action: Expected '@rhkd notify TEXT'", messages[3]);
+        assert_eq!(
+            "Invalid action: Expected an action after '@rhkd'",
+            messages[4]
+        );
+        assert_eq!("Invalid action: Expected '@rhkd send CHORDS'", messages[5]);
+        assert_eq!(
+            "Invalid action: Pointer buttons cannot be sent",
+            messages[6]
+        );
+        assert_eq!("Invalid action: Expected '@rhkd type TEXT'", messages[7]);
+        assert!(config.get_hotkeys().is_empty());
+        Ok(())
+    }
+
     #[test]
     fn test_no_title_or_description() -> Result<()> {
         let rule = b"
@@ -607,7 +1890,7 @@ super + F6 ; a ; b
   echo 123
 ";
         let tokens = Scanner::scan(rule)?;
-        let tree = super::token_parser::Parser::build(rule, &tokens)?;
+        let tree = super::token_parser::Parser::build(rule, &tokens, &DefaultKeymap)?;
         let (hotkeys, errors) = tree.get_hotkeys();
         print_errors(errors, rule);
         assert_eq!(0, errors.len());
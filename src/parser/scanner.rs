@@ -98,7 +98,6 @@ impl<'a> Scanner<'a> {
         let mut tokens = vec![];
         while self.cursor < self.input.len() {
             match self.input[self.cursor] {
-                b'\\' => self.cursor += 2,
                 b'\n' => break,
                 b'{' => {
                     tokens.extend(self.parse_group(|range, context| {
@@ -190,8 +189,9 @@ impl<'a> Scanner<'a> {
             let tokens = self.parse_command()?;
             result.extend(tokens);
             // If the next line is also a line comment, append it to the current comment
+            // An annotation ends the comment
             match self.input.get(self.cursor + 1) {
-                Some(b'#') => {
+                Some(b'#') if self.input.get(self.cursor + 2) != Some(&b'@') => {
                     // Skip the newline and the '#'
                     self.cursor += 2;
                     result.push(Token::ContinueComment(self.cursor));
@@ -202,6 +202,79 @@ impl<'a> Scanner<'a> {
         Ok(result)
     }
 
+    // Returns true if the cursor is at the given keyword, followed by whitespace or the end of
+    // the input. The cursor is moved past the keyword and any following whitespace.
+    fn consume_keyword(&mut self, keyword: &[u8]) -> bool {
+        let rest = &self.input[self.cursor..];
+        if !rest.starts_with(keyword)
+            || !rest
+                .get(keyword.len())
+                .is_none_or(|c| c.is_ascii_whitespace())
+        {
+            return false;
+        }
+        self.cursor += keyword.len();
+        self.advance_while(|c| !c.eq(&b'\n') && c.is_ascii_whitespace());
+        true
+    }
+
+    // Advance to the end of the line, and return the range up to that point without trailing
+    // whitespace.
+    fn rest_of_line(&mut self) -> TokenRange {
+        let start = self.cursor;
+        self.advance_while(|c| !c.eq(&b'\n'));
+        let mut end = self.cursor;
+        while end > start && self.input[end - 1].is_ascii_whitespace() {
+            end -= 1;
+        }
+        start..end
+    }
+
+    // An include directive is the keyword 'include' at the start of a line, followed by a path or
+    // glob pattern. Returns the range of the pattern, which may be empty.
+    fn parse_include(&mut self) -> Option<TokenRange> {
+        if !self.consume_keyword(b"include") {
+            return None;
+        }
+        Some(self.rest_of_line())
+    }
+
+    // A variable definition has the form 'define NAME = value'. If the '=' is missing, the name
+    // covers the rest of the line and the value is empty.
+    fn parse_define(&mut self) -> Option<Token> {
+        if !self.consume_keyword(b"define") {
+            return None;
+        }
+        let start = self.cursor;
+        self.advance_while(|c| !c.is_ascii_whitespace() && *c != b'=');
+        let name = start..self.cursor;
+        self.advance_while(|c| !c.eq(&b'\n') && c.is_ascii_whitespace());
+        if self.input.get(self.cursor) != Some(&b'=') {
+            self.cursor = start;
+            let name = self.rest_of_line();
+            return Some(Token::Define(name.clone(), name.end..name.end));
+        }
+        self.cursor += 1;
+        self.advance_while(|c| !c.eq(&b'\n') && c.is_ascii_whitespace());
+        Some(Token::Define(name, self.rest_of_line()))
+    }
+
+    // A mode block starts with the keyword 'mode' and ends with a '}' on a line of its own. The
+    // header is checked by the parser.
+    fn parse_mode(&mut self) -> Option<Token> {
+        if self.consume_keyword(b"mode") {
+            return Some(Token::StartMode(self.rest_of_line()));
+        }
+        let rest = &self.input[self.cursor..];
+        let line = rest.split(|c| *c == b'\n').next().unwrap_or_default();
+        if line.trim_ascii() == b"}" {
+            let position = self.cursor;
+            self.rest_of_line();
+            return Some(Token::EndMode(position));
+        }
+        None
+    }
+
     fn get_token_stream(mut self) -> Result<Vec<Token>> {
         self.cursor = 0;
         let mut result = vec![];
@@ -214,7 +287,12 @@ impl<'a> Scanner<'a> {
                 }
                 c => {
                     self.advance_while(|c| !c.eq(&b'\n') && c.is_ascii_whitespace());
-                    if c == b'#' {
+                    if c == b'#' && self.input.get(self.cursor + 1) == Some(&b'@') {
+                        // Skip leading '#@'
+                        self.cursor += 2;
+                        self.advance_while(|c| !c.eq(&b'\n') && c.is_ascii_whitespace());
+                        result.push(Token::Annotation(self.rest_of_line()));
+                    } else if c == b'#' {
                         // Skip leading '#'
                         let start = self.cursor;
                         self.cursor += 1;
@@ -224,10 +302,18 @@ impl<'a> Scanner<'a> {
                         result.push(Token::EndComment(start..self.cursor));
                     } else if self.cursor == 0 || self.input[self.cursor - 1] == b'\n' {
                         let start = self.cursor;
-                        let binding_tokens = self.parse_binding()?;
-                        result.push(Token::StartBinding(start..self.cursor));
-                        result.extend(binding_tokens);
-                        result.push(Token::EndBinding(start..self.cursor));
+                        if let Some(include) = self.parse_include() {
+                            result.push(Token::Include(include));
+                        } else if let Some(define) = self.parse_define() {
+                            result.push(define);
+                        } else if let Some(mode) = self.parse_mode() {
+                            result.push(mode);
+                        } else {
+                            let binding_tokens = self.parse_binding()?;
+                            result.push(Token::StartBinding(start..self.cursor));
+                            result.extend(binding_tokens);
+                            result.push(Token::EndBinding(start..self.cursor));
+                        }
                     } else {
                         let start = self.cursor;
                         let inner = self.parse_command()?;
@@ -0,0 +1,387 @@
+use super::{
+    chain_repr, token_parser, variables::Variables, CountMode, Hotkey, Mode, Prefix, Scanner,
+};
+use crate::keyboard::Keymap;
+
+/// Write hotkeys as rhkdrc text.
+///
+/// Consecutive hotkeys which only differ in a single chord are collapsed into one binding with
+/// a brace group, e.g. `super + {h,j,k,l}`, and cycles are written as a single binding with a
+/// group in the command. Every collapsed binding is parsed again, and only kept if it expands to
+/// exactly the hotkeys it was made from. Otherwise, the hotkeys are written one at a time. A
+/// hotkey which does not parse back on its own is written as a comment.
+///
+/// Variables and include directives are not preserved, since hotkeys only store the result of
+/// expanding them. Hotkeys outside of any mode are written first, followed by a block for each
+/// mode. Prefix titles are written before the hotkeys of their mode.
+pub fn to_rhkdrc(
+    hotkeys: &[Hotkey],
+    modes: &[Mode],
+    prefixes: &[Prefix],
+    keymap: &dyn Keymap,
+) -> String {
+    let table = |mode: Option<&Mode>| -> Vec<String> {
+        let name = mode.map(|m| &*m.name);
+        let hotkeys: Vec<Hotkey> = hotkeys
+            .iter()
+            .filter(|hk| hk.mode.as_deref() == name)
+            .cloned()
+            .collect();
+        prefixes
+            .iter()
+            .filter(|p| p.mode.as_deref() == name)
+            .map(|p| format!("# {}\n{}\n", escape(&p.title, false), chain_repr(&p.chain)))
+            .chain(render_table(&hotkeys, keymap))
+            .collect()
+    };
+    let mut blocks = table(None);
+    for mode in modes {
+        let abort = mode
+            .abort
+            .as_ref()
+            .map_or(String::new(), |a| format!(" {}", a));
+        let body = table(Some(mode)).join("\n");
+        blocks.push(format!("mode {}{} {{\n{}}}\n", mode.name, abort, body));
+    }
+    blocks.join("\n")
+}
+
+// Write the hotkeys of one mode as a list of bindings
+fn render_table(hotkeys: &[Hotkey], keymap: &dyn Keymap) -> Vec<String> {
+    let mut blocks = vec![];
+    let mut idx = 0;
+    while idx < hotkeys.len() {
+        let len = run_length(hotkeys, idx);
+        let run = &hotkeys[idx..idx + len];
+        match render(run) {
+            Some(block) if reparses_to(&block, run, keymap) => blocks.push(block),
+            _ => blocks.extend(run.iter().map(|hk| render_single(hk, keymap))),
+        }
+        idx += len;
+    }
+    blocks
+}
+
+// Write a hotkey which could not be collapsed with its neighbors
+fn render_single(hotkey: &Hotkey, keymap: &dyn Keymap) -> String {
+    let hotkeys = std::slice::from_ref(hotkey);
+    match render(hotkeys) {
+        Some(block) if reparses_to(&block, hotkeys, keymap) => block,
+        block => {
+            let block = block.unwrap_or_else(|| format!("{}\n", hotkey.chain_repr()));
+            let mut result =
+                "# This binding cannot be written so that it parses back:\n".to_string();
+            for line in block.lines() {
+                result.push_str(&format!("# {}\n", line));
+            }
+            result
+        }
+    }
+}
+
+fn is_equivalent(a: &Hotkey, b: &Hotkey) -> bool {
+    a.chain == b.chain
+        && a.chain
+            .iter()
+            .zip(b.chain.iter())
+            .all(|(a, b)| a.repr == b.repr)
+        && a.command == b.command
+        && a.sync == b.sync
+        && a.title == b.title
+        && a.description == b.description
+        && a.conditions == b.conditions
+        && a.tags == b.tags
+        && a.icon == b.icon
+        && a.hidden == b.hidden
+        && a.count == b.count
+        && a.timeout == b.timeout
+        && a.exec == b.exec
+        && a.repeat == b.repeat
+        && a.cycle.as_ref().map(|c| c.period) == b.cycle.as_ref().map(|c| c.period)
+}
+
+fn reparses_to(text: &str, expected: &[Hotkey], keymap: &dyn Keymap) -> bool {
+    let bytes = text.as_bytes();
+    let Ok(tokens) = Scanner::scan(bytes) else {
+        return false;
+    };
+    let tree =
+        token_parser::Parser::build_with_variables(bytes, &tokens, Variables::default(), keymap);
+    let Ok(tree) = tree else {
+        return false;
+    };
+    let (hotkeys, errors) = tree.get_hotkeys();
+    errors.is_empty()
+        && hotkeys.len() == expected.len()
+        && hotkeys
+            .iter()
+            .zip(expected)
+            .all(|(a, b)| is_equivalent(a, b))
+}
+
+/// The index of the only chord which differs between two chains, if there is exactly one
+fn differing_chord(a: &Hotkey, b: &Hotkey) -> Option<usize> {
+    if a.chain.len() != b.chain.len() {
+        return None;
+    }
+    let mut differing = a
+        .chain
+        .iter()
+        .zip(b.chain.iter())
+        .enumerate()
+        .filter(|(_, (a, b))| a != b || a.repr != b.repr)
+        .map(|(i, _)| i);
+    let first = differing.next()?;
+    if differing.next().is_some() {
+        return None;
+    }
+    // The separator after the chord is not part of the group
+    let last = a.chain.len() - 1;
+    if first != last && a.chain[first].lock_chain != b.chain[first].lock_chain {
+        return None;
+    }
+    Some(first)
+}
+
+/// The number of hotkeys starting at `start` which can be written as one binding
+fn run_length(hotkeys: &[Hotkey], start: usize) -> usize {
+    let first = &hotkeys[start];
+    let compatible = |hk: &Hotkey| {
+        hk.title == first.title
+            && hk.conditions == first.conditions
+            && hk.tags == first.tags
+            && hk.icon == first.icon
+            && hk.hidden == first.hidden
+            && hk.count == first.count
+            && hk.timeout == first.timeout
+            && hk.exec == first.exec
+            && hk.repeat == first.repeat
+            && hk.description.is_some() == first.description.is_some()
+            && hk.sync == first.sync
+    };
+
+    if let Some(ref cycle) = first.cycle {
+        let period = cycle.period as usize;
+        let same_cycle = hotkeys[start..]
+            .iter()
+            .take(period)
+            .take_while(|hk| {
+                compatible(hk)
+                    && hk.cycle.as_ref().is_some_and(|c| c.period == cycle.period)
+                    && differing_chord(first, hk).is_none()
+                    && hk.chain == first.chain
+            })
+            .count();
+        return if same_cycle == period { period } else { 1 };
+    }
+
+    let Some(second) = hotkeys.get(start + 1) else {
+        return 1;
+    };
+    let Some(index) = differing_chord(first, second) else {
+        return 1;
+    };
+    let mut len = 1;
+    for hk in &hotkeys[start + 1..] {
+        let fits = hk.cycle.is_none()
+            && compatible(hk)
+            && hotkeys[start..start + len]
+                .iter()
+                .all(|other| differing_chord(other, hk) == Some(index));
+        if !fits {
+            break;
+        }
+        len += 1;
+    }
+    len
+}
+
+// Split text into units of either a single character, or an escape sequence. Groups can only be
+// split on unit boundaries, or the meaning of an escape sequence would change.
+fn units(text: &str) -> Vec<&str> {
+    let mut result = vec![];
+    let mut indices = text.char_indices().peekable();
+    while let Some((start, ch)) = indices.next() {
+        if ch == '\\' {
+            indices.next();
+        }
+        let end = indices.peek().map_or(text.len(), |(i, _)| *i);
+        result.push(&text[start..end]);
+    }
+    result
+}
+
+// Mark a command as synchronous with a leading ';'. The ';' of other commands is escaped.
+pub(super) fn command_text(command: String, sync: bool) -> String {
+    if sync {
+        format!(";{}", command)
+    } else if command.starts_with(';') {
+        format!("\\{}", command)
+    } else {
+        command
+    }
+}
+
+// Escape characters which have a special meaning in commands and comments
+pub(super) fn escape(text: &str, in_group: bool) -> String {
+    let mut result = String::new();
+    for unit in units(text) {
+        match unit {
+            "{" | "}" => result.push('\\'),
+            "," if in_group => result.push('\\'),
+            _ => {}
+        }
+        result.push_str(unit);
+    }
+    // A three character item with a dash in the middle would be read as a range
+    if in_group && result.len() == 3 && result.as_bytes()[1] == b'-' {
+        result.insert(1, '\\');
+    }
+    result
+}
+
+/// Write the variants of some text as a prefix, a group and a suffix, e.g. 'echo {a,b}'
+fn factor(variants: &[&str], separator: Option<&str>) -> String {
+    let split: Vec<Vec<&str>> = variants
+        .iter()
+        .map(|v| match separator {
+            Some(sep) => v.split(sep).collect(),
+            None => units(v),
+        })
+        .collect();
+
+    if variants.iter().all(|v| *v == variants[0]) {
+        // Identical variants cannot be factored, but they still need a group of the same size
+        let items: Vec<_> = variants.iter().map(|v| escape(v, true)).collect();
+        return format!("{{{}}}", items.join(","));
+    }
+
+    // Every variant needs to keep at least one unit
+    let shortest = split.iter().map(|v| v.len()).min().unwrap_or(0);
+    let prefix = (0..shortest)
+        .take_while(|i| split.iter().all(|v| v[*i] == split[0][*i]))
+        .count();
+    let suffix = (0..shortest - prefix)
+        .take_while(|i| {
+            let last = &split[0][split[0].len() - 1 - i];
+            split.iter().all(|v| &v[v.len() - 1 - i] == last)
+        })
+        .count();
+    let (mut prefix, mut suffix) = if prefix + suffix == shortest {
+        (prefix.min(shortest.saturating_sub(1)), 0)
+    } else {
+        (prefix, suffix)
+    };
+    // Only split text between words, so 'a{b,c}' is written as '{ab,ac}'
+    let first = &split[0];
+    if separator.is_none() {
+        let is_space = |unit: &str| unit.trim().is_empty();
+        while prefix > 0 && !is_space(first[prefix - 1]) {
+            prefix -= 1;
+        }
+        while suffix > 0 && !is_space(first[first.len() - suffix]) {
+            suffix -= 1;
+        }
+    }
+
+    let join = |units: &[&str]| match separator {
+        Some(sep) => units.join(sep),
+        None => units.concat(),
+    };
+    let mut result = escape(&join(&first[..prefix]), false);
+    let items: Vec<_> = split
+        .iter()
+        .map(|v| escape(&join(&v[prefix..v.len() - suffix]), true))
+        .collect();
+    if let (Some(sep), true) = (separator, prefix > 0) {
+        result.push_str(sep);
+    }
+    result.push_str(&format!("{{{}}}", items.join(",")));
+    if suffix > 0 {
+        if let Some(sep) = separator {
+            result.push_str(sep);
+        }
+        result.push_str(&escape(&join(&first[first.len() - suffix..]), false));
+    }
+    result
+}
+
+fn render(hotkeys: &[Hotkey]) -> Option<String> {
+    let first = hotkeys.first()?;
+    let grouped = hotkeys.len() > 1;
+    let mut result = String::new();
+
+    let descriptions: Option<Vec<&str>> =
+        hotkeys.iter().map(|hk| hk.description.as_deref()).collect();
+    let description = descriptions.map(|d| {
+        if grouped {
+            factor(&d, None)
+        } else {
+            escape(d[0], false)
+        }
+    });
+    if let Some(ref title) = first.title {
+        result.push_str(&format!("# {}\n", title));
+        // A comment with a single line is a description, so the title needs a second line
+        result.push_str(&format!(
+            "#{}\n",
+            description.map(|d| format!(" {}", d)).unwrap_or_default()
+        ));
+    } else if let Some(description) = description {
+        result.push_str(&format!("# {}\n", description));
+    }
+
+    if !first.conditions.is_empty() {
+        let conditions: Vec<_> = first.conditions.iter().map(|c| c.to_string()).collect();
+        result.push_str(&format!("#@ when {}\n", conditions.join(" ")));
+    }
+    if !first.tags.is_empty() {
+        result.push_str(&format!("#@ tags: {}\n", first.tags.join(", ")));
+    }
+    if let Some(ref icon) = first.icon {
+        result.push_str(&format!("#@ icon: {}\n", icon));
+    }
+    if first.hidden {
+        result.push_str("#@ hidden\n");
+    }
+    match first.count {
+        Some(CountMode::Pass) => result.push_str("#@ count\n"),
+        Some(CountMode::Repeat) => result.push_str("#@ count: repeat\n"),
+        None => {}
+    }
+    if let Some(timeout) = first.timeout {
+        result.push_str(&format!("#@ timeout: {}\n", timeout.as_secs_f64()));
+    }
+    if first.exec {
+        result.push_str("#@ exec\n");
+    }
+    if let Some(repeat) = first.repeat {
+        result.push_str(&format!("#@ repeat: {}\n", repeat));
+    }
+
+    let group_index = match hotkeys.get(1) {
+        Some(second) if first.cycle.is_none() => Some(differing_chord(first, second)?),
+        _ => None,
+    };
+    let last = first.chain.len() - 1;
+    for (i, chord) in first.chain.iter().enumerate() {
+        if Some(i) == group_index {
+            let reprs: Vec<&str> = hotkeys.iter().map(|hk| &*hk.chain[i].repr).collect();
+            result.push_str(&factor(&reprs, Some(" + ")));
+        } else {
+            result.push_str(&chord.repr);
+        }
+        if i != last {
+            result.push_str(if chord.is_locking() { " : " } else { " ; " });
+        }
+    }
+
+    let commands: Vec<&str> = hotkeys.iter().map(|hk| &*hk.command).collect();
+    let command = if grouped {
+        factor(&commands, None)
+    } else {
+        escape(commands[0], false)
+    };
+    result.push_str(&format!("\n  {}\n", command_text(command, first.sync)));
+    Some(result)
+}
@@ -1,30 +1,102 @@
 #![allow(unused)]
 use super::hotkey_parser::*;
+use super::variables::Variables;
 use super::{ChainMode, ConfigParseError, Token, TokenRange};
 use crate::{
-    keyboard,
-    parser::types::{Chord, Hotkey},
+    keyboard::{self, Keymap},
+    parser::diagnostic::Span,
+    parser::types::{
+        Chord, Condition, CountMode, Hotkey, Mode, Prefix, RepeatPolicy, Source, DEFAULT_MODE,
+    },
 };
 use anyhow::{anyhow, Result};
 use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::Duration;
 
 pub struct Parser<'a> {
     tokens: &'a [Token],
     context: &'a [u8],
     cursor: usize,
     hotkeys: Vec<Hotkey>,
+    prefixes: Vec<Prefix>,
+    includes: Vec<Include>,
+    modes: Vec<Mode>,
+    /// The mode block being parsed, and the token which opened it
+    mode: Option<(Arc<str>, Token)>,
+    /// The annotations for the next binding
+    annotations: Annotations,
+    variables: Variables,
+    keymap: &'a dyn Keymap,
     pub errors: Vec<anyhow::Error>,
 }
 
+/// The annotations above a binding, and the first annotation token
+#[derive(Default)]
+struct Annotations {
+    token: Option<Token>,
+    conditions: Vec<Condition>,
+    tags: Vec<Arc<str>>,
+    icon: Option<Arc<str>>,
+    title: Option<Arc<str>>,
+    hidden: bool,
+    count: Option<CountMode>,
+    timeout: Option<Duration>,
+    exec: bool,
+    repeat: Option<RepeatPolicy>,
+}
+
+impl Annotations {
+    fn apply(&self, hotkey: &mut Hotkey) {
+        hotkey.conditions = self.conditions.clone().into();
+        hotkey.tags = self.tags.clone().into();
+        hotkey.icon = self.icon.clone();
+        hotkey.hidden = self.hidden;
+        hotkey.count = self.count;
+        hotkey.timeout = self.timeout;
+        hotkey.exec = self.exec;
+        hotkey.repeat = self.repeat;
+        // A title annotation takes precedence over the title of the comment
+        if self.title.is_some() {
+            hotkey.title = self.title.clone();
+        }
+    }
+}
+
+/// An include directive, and the position in the hotkey list where the included hotkeys belong
+pub struct Include {
+    pub token: Token,
+    pub pattern: String,
+    pub index: usize,
+    /// The variables defined at the point of the include. These are visible in the included file.
+    pub variables: Variables,
+}
+
 type ConcreteCommand = String;
 
 impl<'a> Parser<'a> {
-    pub fn build(context: &'a [u8], tokens: &'a [Token]) -> Result<Self> {
+    pub fn build(context: &'a [u8], tokens: &'a [Token], keymap: &'a dyn Keymap) -> Result<Self> {
+        Self::build_with_variables(context, tokens, Variables::default(), keymap)
+    }
+
+    pub fn build_with_variables(
+        context: &'a [u8],
+        tokens: &'a [Token],
+        variables: Variables,
+        keymap: &'a dyn Keymap,
+    ) -> Result<Self> {
         let tree = Parser {
             context,
             tokens,
             cursor: Default::default(),
             hotkeys: vec![],
+            prefixes: vec![],
+            includes: vec![],
+            modes: vec![],
+            mode: None,
+            annotations: Default::default(),
+            variables,
+            keymap,
             errors: vec![],
         };
         tree.populate()
@@ -120,6 +192,150 @@ impl<'a> Parser<'a> {
         ))?
     }
 
+    // Parse an annotation of the form 'KEYWORD[:] [VALUE]'
+    fn annotate(&mut self, token: &Token) {
+        let text = token.get_string(self.context);
+        let end = text
+            .find(|c: char| c == ':' || c.is_whitespace())
+            .unwrap_or(text.len());
+        let (keyword, rest) = text.split_at(end);
+        let rest = rest.trim_start();
+        let value = rest.strip_prefix(':').unwrap_or(rest).trim();
+        let expected = |what: &str| Err(format!("Expected {} after '{}'", what, keyword));
+        let annotations = &mut self.annotations;
+        let result = match keyword {
+            "when" if value.is_empty() => expected("a condition"),
+            "when" => value
+                .split_whitespace()
+                .map(Condition::parse)
+                .collect::<Result<Vec<_>, _>>()
+                .map(|conditions| annotations.conditions.extend(conditions)),
+            "tags" => {
+                let tags: Vec<_> = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|t| !t.is_empty())
+                    .map(Arc::from)
+                    .collect();
+                if tags.is_empty() {
+                    expected("a list of tags")
+                } else {
+                    annotations.tags.extend(tags);
+                    Ok(())
+                }
+            }
+            "icon" | "title" if value.is_empty() => expected("a value"),
+            "icon" => {
+                annotations.icon = Some(value.into());
+                Ok(())
+            }
+            "title" => {
+                annotations.title = Some(value.into());
+                Ok(())
+            }
+            "hidden" if !value.is_empty() => Err("'hidden' does not take a value".into()),
+            "hidden" => {
+                annotations.hidden = true;
+                Ok(())
+            }
+            "count" => match value {
+                "" => {
+                    annotations.count = Some(CountMode::Pass);
+                    Ok(())
+                }
+                "repeat" => {
+                    annotations.count = Some(CountMode::Repeat);
+                    Ok(())
+                }
+                _ => expected("'repeat' or nothing"),
+            },
+            "timeout" => match value.parse().map(Duration::try_from_secs_f64) {
+                Ok(Ok(timeout)) if !timeout.is_zero() => {
+                    annotations.timeout = Some(timeout);
+                    Ok(())
+                }
+                _ => expected("a number of seconds"),
+            },
+            "repeat" => match value.parse::<RepeatPolicy>() {
+                Ok(repeat) => {
+                    annotations.repeat = Some(repeat);
+                    Ok(())
+                }
+                Err(_) => expected("'always', 'never' or a number of seconds"),
+            },
+            "exec" if !value.is_empty() => Err("'exec' does not take a value".into()),
+            "exec" => {
+                annotations.exec = true;
+                Ok(())
+            }
+            _ => Err(format!("Unknown annotation '{}'", keyword)),
+        };
+        match result {
+            Ok(()) => {
+                self.annotations.token.get_or_insert_with(|| token.clone());
+            }
+            Err(message) => self
+                .errors
+                .push(anyhow!(ConfigParseError::InvalidAnnotation(
+                    token.clone(),
+                    message
+                ))),
+        }
+    }
+
+    // Annotations only apply to the binding directly below them
+    fn discard_annotations(&mut self) {
+        if let Some(token) = std::mem::take(&mut self.annotations).token {
+            self.errors
+                .push(anyhow!(ConfigParseError::InvalidAnnotation(
+                    token,
+                    "Expected a binding after the annotation".into(),
+                )));
+        }
+    }
+
+    // A binding which is not followed by a command titles the start of a chain
+    fn end_binding(
+        &mut self,
+        shortcut: &mut Option<ShortcutNode>,
+        comment: &mut Option<CommentNode>,
+    ) {
+        let Some(shortcut) = shortcut.take() else {
+            return;
+        };
+        let comment = comment.take();
+        let annotations = std::mem::take(&mut self.annotations);
+        let only_title = annotations.conditions.is_empty()
+            && annotations.tags.is_empty()
+            && annotations.icon.is_none()
+            && !annotations.hidden
+            && annotations.count.is_none()
+            && annotations.timeout.is_none()
+            && !annotations.exec
+            && annotations.repeat.is_none();
+        if let (Some(token), false) = (annotations.token, only_title) {
+            self.errors
+                .push(anyhow!(ConfigParseError::InvalidAnnotation(
+                    token,
+                    "Only 'title' applies to a binding without a command".into(),
+                )));
+        }
+        let (prefixes, errors) = HotkeyParser::expand_prefix(
+            shortcut,
+            comment,
+            annotations.title,
+            &self.variables,
+            self.keymap,
+            self.context,
+        );
+        let mode = self.mode.as_ref().map(|(name, _)| name.clone());
+        self.prefixes.extend(prefixes.into_iter().map(|mut prefix| {
+            prefix.mode = mode.clone();
+            prefix
+        }));
+        self.errors.extend(errors);
+    }
+
     fn advance_cursor(&mut self) {
         self.cursor += 1;
     }
@@ -131,17 +347,91 @@ impl<'a> Parser<'a> {
             self.advance_cursor();
             match token {
                 Token::EmptyLine(_) => {
+                    self.end_binding(&mut shortcut, &mut comment);
                     comment = None;
-                    shortcut = None;
+                    self.discard_annotations();
+                }
+                Token::Include(_) => {
+                    self.end_binding(&mut shortcut, &mut comment);
+                    comment = None;
+                    self.discard_annotations();
+                    let pattern = token.get_string(self.context);
+                    if self.mode.is_some() {
+                        self.errors.push(anyhow!(ConfigParseError::InvalidInclude(
+                            token.clone(),
+                            "Files cannot be included inside a mode".into(),
+                        )));
+                    } else if pattern.is_empty() {
+                        self.errors.push(anyhow!(ConfigParseError::InvalidInclude(
+                            token.clone(),
+                            "Expected a path after 'include'".into(),
+                        )));
+                    } else {
+                        self.includes.push(Include {
+                            token: token.clone(),
+                            pattern,
+                            index: self.hotkeys.len(),
+                            variables: self.variables.clone(),
+                        });
+                    }
+                }
+                Token::Define(name, value) => {
+                    self.end_binding(&mut shortcut, &mut comment);
+                    comment = None;
+                    self.discard_annotations();
+                    // Without an '=', the value is an empty range at the end of the name
+                    let missing_value = value.start == name.end;
+                    let name = String::from_utf8_lossy(&self.context[name.clone()]).to_string();
+                    let value = String::from_utf8_lossy(&self.context[value.clone()]).to_string();
+                    let result = if missing_value || name.contains(char::is_whitespace) {
+                        Err("Expected 'define NAME = value'".to_string())
+                    } else {
+                        self.variables
+                            .define(&name, &value)
+                            .map_err(|e| e.to_string())
+                    };
+                    if let Err(message) = result {
+                        self.errors.push(anyhow!(ConfigParseError::InvalidVariable(
+                            token.clone(),
+                            message
+                        )));
+                    }
+                }
+                Token::StartMode(_) => {
+                    self.end_binding(&mut shortcut, &mut comment);
+                    comment = None;
+                    self.discard_annotations();
+                    self.start_mode(token);
+                }
+                Token::EndMode(_) => {
+                    self.end_binding(&mut shortcut, &mut comment);
+                    comment = None;
+                    self.discard_annotations();
+                    if self.mode.take().is_none() {
+                        self.errors.push(anyhow!(ConfigParseError::InvalidMode(
+                            token.clone(),
+                            "Unexpected '}' outside of a mode".into(),
+                        )));
+                    }
+                }
+                Token::StartComment(_) => {
+                    self.end_binding(&mut shortcut, &mut comment);
+                    match self.read_comment(token) {
+                        Ok(s) => comment = Some(s),
+                        Err(e) => self.errors.push(e),
+                    }
+                }
+                Token::StartBinding(_) => {
+                    self.end_binding(&mut shortcut, &mut comment);
+                    match self.read_binding(token) {
+                        Ok(s) => shortcut = Some(s),
+                        Err(e) => self.errors.push(e),
+                    }
+                }
+                Token::Annotation(_) => {
+                    self.end_binding(&mut shortcut, &mut comment);
+                    self.annotate(token);
                 }
-                Token::StartComment(_) => match self.read_comment(token) {
-                    Ok(s) => comment = Some(s),
-                    Err(e) => self.errors.push(e),
-                },
-                Token::StartBinding(_) => match self.read_binding(token) {
-                    Ok(s) => shortcut = Some(s),
-                    Err(e) => self.errors.push(e),
-                },
                 Token::StartCommand(_) => match shortcut.take() {
                     Some(shortcut) => match self.read_command(token) {
                         Ok(command) => {
@@ -149,15 +439,25 @@ impl<'a> Parser<'a> {
                                 shortcut,
                                 command,
                                 comment.take(),
+                                &self.variables,
+                                self.keymap,
                                 self.context,
                             );
-                            self.hotkeys.extend(hotkeys);
+                            let mode = self.mode.as_ref().map(|(name, _)| name.clone());
+                            let annotations = std::mem::take(&mut self.annotations);
+                            self.hotkeys.extend(hotkeys.into_iter().map(|mut hk| {
+                                hk.mode = mode.clone();
+                                annotations.apply(&mut hk);
+                                hk
+                            }));
                             self.errors.extend(errors);
                         }
                         Err(e) => self.errors.push(e),
                     },
                     None => {
+                        self.annotations = Default::default();
                         self.errors.push(anyhow!(ConfigParseError::SyntaxError(
+                            token.clone(),
                             "Encountered command without binding!".into(),
                         )));
                     }
@@ -171,12 +471,75 @@ impl<'a> Parser<'a> {
                 }
             }
         }
+        self.end_binding(&mut shortcut, &mut comment);
+        self.discard_annotations();
+        if let Some((_, token)) = self.mode.take() {
+            self.errors.push(anyhow!(ConfigParseError::InvalidMode(
+                token,
+                "Unterminated mode, expected '}'".into(),
+            )));
+        }
         Ok(self)
     }
 
+    // Parse a mode header of the form 'NAME [ABORT_KEYSYM] {'. The block is opened even if the
+    // header is invalid, so the bindings in it do not end up in the default mode.
+    fn start_mode(&mut self, token: &Token) {
+        let header = token.get_string(self.context);
+        let words: Vec<&str> = header.split_whitespace().collect();
+        let (name, abort) = match words[..] {
+            [name, "{"] => (name, None),
+            [name, abort, "{"] => (name, Some(abort)),
+            _ => ("", None),
+        };
+        let error = if let Some((open, _)) = self.mode.as_ref() {
+            Some(format!("Modes cannot be nested, '{}' is not closed", open))
+        } else if name.is_empty() {
+            Some("Expected 'mode NAME [ABORT_KEYSYM] {'".to_string())
+        } else if name == DEFAULT_MODE {
+            Some(format!("'{}' is reserved for leaving a mode", DEFAULT_MODE))
+        } else if self.modes.iter().any(|m| &*m.name == name) {
+            Some(format!("Mode '{}' is already defined", name))
+        } else {
+            abort
+                .and_then(|a| keyboard::symbol_from_string(a).err())
+                .map(|e| e.to_string())
+        };
+        if let Some(message) = error {
+            self.errors.push(anyhow!(ConfigParseError::InvalidMode(
+                token.clone(),
+                message
+            )));
+        } else {
+            self.modes.push(Mode {
+                name: name.into(),
+                abort: abort.map(|a| a.into()),
+                source: Some(Arc::new(Source {
+                    file: None,
+                    span: Span::new(token.get_range(), self.context),
+                })),
+            });
+        }
+        if self.mode.is_none() {
+            self.mode = Some((name.into(), token.clone()));
+        }
+    }
+
     pub fn get_hotkeys(&self) -> (&Vec<Hotkey>, &Vec<anyhow::Error>) {
         (&self.hotkeys, &self.errors)
     }
+
+    pub fn get_prefixes(&self) -> &[Prefix] {
+        &self.prefixes
+    }
+
+    pub fn get_includes(&self) -> &[Include] {
+        &self.includes
+    }
+
+    pub fn get_modes(&self) -> &[Mode] {
+        &self.modes
+    }
 }
 
 mod parser_tests {
@@ -188,6 +551,6 @@ mod parser_tests {
   echo {some, replacement} {1-3}
 ";
         let tokens = Scanner::scan(rule).unwrap();
-        let parser = Parser::build(rule, &tokens);
+        let parser = Parser::build(rule, &tokens, &crate::keyboard::DefaultKeymap);
     }
 }
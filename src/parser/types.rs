@@ -1,6 +1,9 @@
 #![allow(unused)]
 
-use std::{fmt::Display, sync::Arc};
+use std::{fmt::Display, path::PathBuf, sync::Arc, time::Duration};
+
+use super::diagnostic::Span;
+use crate::keyboard::{Keymap, WindowClass};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Cycle {
@@ -156,6 +159,25 @@ impl PartialEq for Chord {
     }
 }
 
+/// Where a hotkey was defined
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    /// The config file, or `None` for bindings which were not read from a file
+    pub file: Option<PathBuf>,
+    /// The binding the hotkey was expanded from
+    pub span: Span,
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let file = self
+            .file
+            .as_ref()
+            .map_or("<input>".to_string(), |f| f.display().to_string());
+        write!(f, "{}:{}:{}", file, self.span.line, self.span.column)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Hotkey {
     pub chain: Arc<[Chord]>,
@@ -164,6 +186,280 @@ pub struct Hotkey {
     pub cycle: Option<Cycle>,
     pub title: Option<Arc<str>>,
     pub description: Option<Arc<str>>,
+    pub source: Option<Arc<Source>>,
+    /// The mode the hotkey belongs to, or `None` for hotkeys outside of any mode block
+    pub mode: Option<Arc<str>>,
+    /// Set if the command is handled by rhkd instead of a shell
+    pub action: Option<Action>,
+    /// Conditions on the focused window which must hold for the hotkey to be triggered
+    pub conditions: Arc<[Condition]>,
+    /// Labels for grouping related hotkeys, set with `#@ tags: media, audio`
+    pub tags: Arc<[Arc<str>]>,
+    /// Shown next to the description, set with `#@ icon: ICON`
+    pub icon: Option<Arc<str>>,
+    /// Set with `#@ hidden` to leave the hotkey out of rhkd-whichkey
+    pub hidden: bool,
+    /// Set with `#@ count` to accept a count typed before the last chord of the chain
+    pub count: Option<CountMode>,
+    /// Set with `#@ timeout: SECONDS` to kill the command if it runs for longer
+    pub timeout: Option<Duration>,
+    /// Set with `#@ exec` to run the command without a shell. It is split into arguments with
+    /// the quoting rules of a shell.
+    pub exec: bool,
+    /// Set with `#@ repeat` to decide what happens while the key of the last chord is held
+    pub repeat: Option<RepeatPolicy>,
+}
+
+/// What a hotkey does with a count typed before the last chord of its chain, e.g. the 3 in
+/// `super + w ; 3 ; l`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CountMode {
+    /// The command gets the count in `RHKD_COUNT`. Written as `#@ count`.
+    Pass,
+    /// The command runs as many times as the count. Written as `#@ count: repeat`.
+    Repeat,
+}
+
+impl Display for CountMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CountMode::Pass => "pass",
+            CountMode::Repeat => "repeat",
+        })
+    }
+}
+
+/// What a hotkey does when X repeats the key of its last chord because it is held
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RepeatPolicy {
+    /// The command runs for every repeat, like in sxhkd. Written as `#@ repeat: always`.
+    #[default]
+    Always,
+    /// The command runs once per press. Written as `#@ repeat: never`.
+    Never,
+    /// The command runs for the repeats once the key was held this long. Written as
+    /// `#@ repeat: SECONDS`.
+    After(Duration),
+}
+
+impl RepeatPolicy {
+    /// Whether a repeat of a key which was held for `held` runs the command
+    pub fn allows(&self, held: Duration) -> bool {
+        match self {
+            RepeatPolicy::Always => true,
+            RepeatPolicy::Never => false,
+            RepeatPolicy::After(delay) => held >= *delay,
+        }
+    }
+}
+
+impl Display for RepeatPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RepeatPolicy::Always => f.write_str("always"),
+            RepeatPolicy::Never => f.write_str("never"),
+            RepeatPolicy::After(delay) => write!(f, "{}", delay.as_secs_f64()),
+        }
+    }
+}
+
+impl std::str::FromStr for RepeatPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" | "always" => Ok(RepeatPolicy::Always),
+            "never" => Ok(RepeatPolicy::Never),
+            _ => match s.parse().map(Duration::try_from_secs_f64) {
+                Ok(Ok(delay)) => Ok(RepeatPolicy::After(delay)),
+                _ => Err("Expected 'always', 'never' or a number of seconds".into()),
+            },
+        }
+    }
+}
+
+/// A property of the focused window which a condition can test
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WindowProperty {
+    /// The class part of WM_CLASS, e.g. 'firefox'
+    Class,
+    /// The instance part of WM_CLASS, e.g. 'Navigator'
+    Instance,
+}
+
+impl Display for WindowProperty {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            WindowProperty::Class => "class",
+            WindowProperty::Instance => "instance",
+        })
+    }
+}
+
+/// A condition on the focused window, written as `#@ when class=firefox` above a binding. While
+/// the condition does not hold, the key is passed on to the focused window.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Condition {
+    pub property: WindowProperty,
+    pub value: Arc<str>,
+    /// Set for `!=`, where the condition holds if the property has any other value
+    pub negated: bool,
+}
+
+impl Condition {
+    /// Parse a condition of the form `PROPERTY=VALUE` or `PROPERTY!=VALUE`
+    pub fn parse(text: &str) -> Result<Condition, String> {
+        let (property, value, negated) = match text.split_once('=') {
+            Some((property, value)) => match property.strip_suffix('!') {
+                Some(property) => (property, value, true),
+                None => (property, value, false),
+            },
+            None => return Err(format!("Expected 'PROPERTY=VALUE', found '{}'", text)),
+        };
+        let property = match property {
+            "class" => WindowProperty::Class,
+            "instance" => WindowProperty::Instance,
+            _ => {
+                return Err(format!(
+                    "Unknown window property '{}', expected 'class' or 'instance'",
+                    property
+                ))
+            }
+        };
+        if value.is_empty() {
+            return Err(format!("Expected a value after '{}'", property));
+        }
+        Ok(Condition {
+            property,
+            value: value.into(),
+            negated,
+        })
+    }
+
+    /// Test the condition against the focused window. A condition with `=` does not hold when
+    /// no window is focused.
+    pub fn matches(&self, window: Option<&WindowClass>) -> bool {
+        let actual = window.map(|w| match self.property {
+            WindowProperty::Class => w.class.as_str(),
+            WindowProperty::Instance => w.instance.as_str(),
+        });
+        (actual == Some(&*self.value)) != self.negated
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let op = if self.negated { "!=" } else { "=" };
+        write!(f, "{}{}{}", self.property, op, self.value)
+    }
+}
+
+/// The name used for leaving a mode, i.e. the bindings outside of any mode block
+pub const DEFAULT_MODE: &str = "default";
+
+/// A named table of bindings, declared with a `mode NAME [ABORT_KEYSYM] { ... }` block. While a
+/// mode is active, only its bindings are grabbed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mode {
+    pub name: Arc<str>,
+    /// The keysym which leaves the mode. The abort keysym of the daemon is used if none is set.
+    pub abort: Option<Arc<str>>,
+    pub source: Option<Arc<Source>>,
+}
+
+/// A title for the start of a chain, written as a comment above a binding line without a
+/// command. rhkd-whichkey shows it for the continuations of the prefix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prefix {
+    pub chain: Arc<[Chord]>,
+    pub title: Arc<str>,
+    /// The mode the prefix belongs to, or `None` for prefixes outside of any mode block
+    pub mode: Option<Arc<str>>,
+    pub source: Option<Arc<Source>>,
+}
+
+impl Prefix {
+    pub fn chain_repr(&self) -> String {
+        chain_repr(&self.chain)
+    }
+
+    /// Returns `true` if this prefix titles exactly `chain`
+    pub fn titles(&self, chain: &[Chord]) -> bool {
+        self.chain.len() == chain.len()
+            && self.chain.iter().zip(chain).all(|(a, b)| a.eq_relaxed(b))
+    }
+}
+
+/// A command which is run by rhkd itself instead of a shell, written as `@rhkd ACTION [ARGS]`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Switch to the named mode. Switching to 'default' leaves the current mode.
+    Mode(Arc<str>),
+    /// Load the config again, like SIGUSR1
+    Reload,
+    /// Release every grabbed key, or grab them again, like SIGUSR2
+    ToggleGrab,
+    /// End the active chain
+    Abort,
+    /// Remove every binding starting with the chain, like `rhkc unbind`
+    Unbind(Arc<str>),
+    /// Send the text to clients subscribed to notifications
+    Notify(Arc<str>),
+    /// Press and release the chords in turn, as if they were typed on the keyboard
+    Send(Arc<[Chord]>),
+    /// Type the text, as if it was typed on the keyboard
+    Type(Arc<str>),
+}
+
+impl Action {
+    pub const PREFIX: &'static str = "@rhkd";
+
+    /// Parse the command of a binding. Returns `None` if the command is not an action. The
+    /// chain of an unbind action is parsed against `keymap`, so it is known to be valid.
+    pub fn parse(command: &str, keymap: &dyn Keymap) -> Result<Option<Action>, String> {
+        let Some(rest) = command.strip_prefix(Self::PREFIX) else {
+            return Ok(None);
+        };
+        if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+            return Ok(None);
+        }
+        let rest = rest.trim();
+        let (action, args) = rest
+            .split_once(char::is_whitespace)
+            .map_or((rest, ""), |(action, args)| (action, args.trim()));
+        let action = match (action, args) {
+            ("mode", name) if !name.is_empty() && !name.contains(char::is_whitespace) => {
+                Action::Mode(name.into())
+            }
+            ("mode", _) => return Err("Expected '@rhkd mode NAME'".into()),
+            ("reload" | "toggle-grab" | "abort", args) if !args.is_empty() => {
+                return Err(format!("'{}' does not take arguments", action))
+            }
+            ("reload", _) => Action::Reload,
+            ("toggle-grab", _) => Action::ToggleGrab,
+            ("abort", _) => Action::Abort,
+            ("unbind", "") => return Err("Expected '@rhkd unbind CHAIN'".into()),
+            ("unbind", chain) => match super::parse_chord_chain(chain, keymap) {
+                Ok(_) => Action::Unbind(chain.into()),
+                Err(e) => return Err(format!("Invalid chain '{}': {}", chain, e)),
+            },
+            ("notify", "") => return Err("Expected '@rhkd notify TEXT'".into()),
+            ("notify", text) => Action::Notify(text.into()),
+            ("send", "") => return Err("Expected '@rhkd send CHORDS'".into()),
+            ("send", chords) => match super::parse_chord_chain(chords, keymap) {
+                Ok(chords) if chords.iter().any(|c| c.button != 0) => {
+                    return Err("Pointer buttons cannot be sent".into())
+                }
+                Ok(chords) => Action::Send(chords.into()),
+                Err(e) => return Err(format!("Invalid chords '{}': {}", chords, e)),
+            },
+            ("type", "") => return Err("Expected '@rhkd type TEXT'".into()),
+            ("type", text) => Action::Type(text.into()),
+            ("", _) => return Err("Expected an action after '@rhkd'".into()),
+            (action, _) => return Err(format!("Unknown action '{}'", action)),
+        };
+        Ok(Some(action))
+    }
 }
 
 impl Hotkey {
@@ -174,19 +470,24 @@ impl Hotkey {
             .unwrap_or_else(|| self.command.trim_matches(matches).into())
     }
     pub fn chain_repr(&self) -> String {
-        let mut s = String::new();
-        if let Some((last, rest)) = self.chain.split_last() {
-            for item in rest {
-                s.push_str(&format!(
-                    "{} {} ",
-                    item.repr,
-                    if item.is_locking() { ":" } else { ";" }
-                ));
-            }
-            s.push_str(&last.repr);
+        chain_repr(&self.chain)
+    }
+}
+
+/// Format a chain the way it is written in a config, e.g. 'super + a : b'
+pub fn chain_repr(chain: &[Chord]) -> String {
+    let mut s = String::new();
+    if let Some((last, rest)) = chain.split_last() {
+        for item in rest {
+            s.push_str(&format!(
+                "{} {} ",
+                item.repr,
+                if item.is_locking() { ":" } else { ";" }
+            ));
         }
-        s
+        s.push_str(&last.repr);
     }
+    s
 }
 
 impl Display for Hotkey {
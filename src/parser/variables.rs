@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+/// Variables declared with `define NAME = value`.
+///
+/// References have the form `$NAME`, or `${NAME:-default}` in the value of a definition. In
+/// bindings and definitions, a reference is resolved from the defined variables, then from the
+/// environment, and finally from the default. An unresolved reference is an error.
+///
+/// Commands and descriptions are passed to the shell, which has its own variables. There, only
+/// references to defined variables are substituted, and everything else is left untouched.
+#[derive(Debug, Default, Clone)]
+pub struct Variables {
+    values: HashMap<String, String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VariableError {
+    Undefined(String),
+    InvalidName(String),
+}
+
+impl std::fmt::Display for VariableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VariableError::Undefined(name) => write!(f, "Undefined variable '{}'", name),
+            VariableError::InvalidName(name) => write!(f, "Invalid variable name '{}'", name),
+        }
+    }
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(is_name_start) && chars.all(is_name_char)
+}
+
+impl Variables {
+    pub fn define(&mut self, name: &str, value: &str) -> Result<(), VariableError> {
+        if !is_valid_name(name) {
+            return Err(VariableError::InvalidName(name.to_string()));
+        }
+        let value = self.expand(value, true)?;
+        self.values.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    fn resolve(&self, name: &str, default: Option<&str>) -> Result<String, VariableError> {
+        if let Some(value) = self.values.get(name) {
+            return Ok(value.clone());
+        }
+        if let Ok(value) = std::env::var(name) {
+            return Ok(value);
+        }
+        default
+            .map(|d| d.to_string())
+            .ok_or_else(|| VariableError::Undefined(name.to_string()))
+    }
+
+    /// Substitute variable references in `text`. When `strict` is set, every reference must
+    /// resolve. Otherwise, only references to defined variables are substituted.
+    pub fn expand(&self, text: &str, strict: bool) -> Result<String, VariableError> {
+        let mut result = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(idx) = rest.find(['$', '\\']) {
+            result.push_str(&rest[..idx]);
+            rest = &rest[idx..];
+            // Keep escaped characters as they are
+            if rest.starts_with('\\') {
+                let len = rest[1..].chars().next().map_or(1, |c| c.len_utf8() + 1);
+                result.push_str(&rest[..len]);
+                rest = &rest[len..];
+                continue;
+            }
+
+            let after = &rest[1..];
+            if let Some(braced) = after.strip_prefix('{') {
+                match braced.find('}') {
+                    Some(end) if strict => {
+                        let inner = &braced[..end];
+                        let (name, default) = match inner.split_once(":-") {
+                            Some((name, default)) => (name, Some(default)),
+                            None => (inner, None),
+                        };
+                        if !is_valid_name(name) {
+                            return Err(VariableError::InvalidName(name.to_string()));
+                        }
+                        result.push_str(&self.resolve(name, default)?);
+                        rest = &braced[end + 1..];
+                    }
+                    _ => {
+                        result.push('$');
+                        rest = after;
+                    }
+                }
+                continue;
+            }
+
+            let len = if after.starts_with(is_name_start) {
+                after.find(|c| !is_name_char(c)).unwrap_or(after.len())
+            } else {
+                0
+            };
+            let name = &after[..len];
+            if name.is_empty() {
+                result.push('$');
+            } else if strict {
+                result.push_str(&self.resolve(name, None)?);
+            } else if let Some(value) = self.values.get(name) {
+                result.push_str(value);
+            } else {
+                result.push('$');
+                result.push_str(name);
+            }
+            rest = &after[len..];
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
+}
+
+mod variable_tests {
+    #[allow(unused)]
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let mut vars = Variables::default();
+        vars.define("TERM", "alacritty").unwrap();
+        vars.define("MOD", "super + shift").unwrap();
+        vars.define("RUN", "$TERM -e").unwrap();
+        vars.define("DIR", "${RHKD_TEST_UNSET_VARIABLE:-~/scripts}")
+            .unwrap();
+
+        assert_eq!(vars.expand("$MOD + a", true).unwrap(), "super + shift + a");
+        assert_eq!(
+            vars.expand("$RUN htop", false).unwrap(),
+            "alacritty -e htop"
+        );
+        assert_eq!(vars.expand("$DIR/x.sh", false).unwrap(), "~/scripts/x.sh");
+        // Shell variables are left alone in commands
+        assert_eq!(
+            vars.expand("echo $HOME ${X} \\$TERM $", false).unwrap(),
+            "echo $HOME ${X} \\$TERM $"
+        );
+        assert_eq!(
+            vars.expand("$RHKD_TEST_UNSET_VARIABLE", true),
+            Err(VariableError::Undefined("RHKD_TEST_UNSET_VARIABLE".into()))
+        );
+        assert_eq!(
+            vars.define("1ABC", "x"),
+            Err(VariableError::InvalidName("1ABC".into()))
+        );
+    }
+}
@@ -5,6 +5,8 @@ use std::{os::unix::net::UnixListener, path::PathBuf};
 use clap::{arg, Args, Subcommand, ValueEnum};
 use thiserror::Error;
 
+use crate::parser::{CountMode, RepeatPolicy};
+
 pub fn get_socket_path() -> String {
     std::env::var("RHKD_SOCKET_PATH").unwrap_or(format!(
         "/tmp/rhkd_socket_{}",
@@ -20,6 +22,14 @@ pub enum Commands {
     Bind(BindCommand),
     /// Remove all bindings in a given group
     Unbind(UnbindCommand),
+    /// Print the current bindings, including ones added with 'bind', as rhkdrc text
+    Dump,
+    /// Show which command of each cycle runs next
+    Cycles(CyclesCommand),
+    /// Show the commands started by hotkeys which are still running
+    Running,
+    /// Show the end of the output of the last command which failed
+    Output(OutputCommand),
 }
 
 #[derive(Args, Debug)]
@@ -55,6 +65,16 @@ impl Drop for DroppableListener {
 #[derive(Args, Debug, Clone)]
 pub struct UnbindCommand {
     pub hotkey: String,
+    /// Only remove bindings in this mode. Use 'default' for bindings outside of any mode
+    #[arg(short, long)]
+    pub mode: Option<String>,
+    /// Only remove the binding with exactly this hotkey, which runs this command
+    #[arg(short, long)]
+    pub command: Option<String>,
+    /// The window conditions of the binding given with --command, e.g. 'class=firefox'. Can be
+    /// given several times
+    #[arg(long = "when", requires = "command")]
+    pub conditions: Vec<String>,
 }
 #[derive(Args, Debug, Clone)]
 pub struct BindCommand {
@@ -73,6 +93,56 @@ pub struct BindCommand {
     /// hotkeys
     #[arg(short, long)]
     pub description: Option<String>,
+    /// Tag for grouping this binding in rhkd-whichkey. Can be given several times
+    #[arg(long = "tag")]
+    pub tags: Vec<String>,
+    /// Icon shown next to the description
+    #[arg(long)]
+    pub icon: Option<String>,
+    /// Leave this binding out of rhkd-whichkey
+    #[arg(long, default_value_t = false)]
+    pub hidden: bool,
+    /// Add the binding to this mode. The mode is created if it does not exist
+    #[arg(short, long)]
+    pub mode: Option<String>,
+    /// Only trigger the binding for some windows, e.g. 'class=firefox'. Can be given several
+    /// times
+    #[arg(long = "when")]
+    pub conditions: Vec<String>,
+    /// Accept a count typed before the last chord, and pass it to the command or repeat it
+    #[arg(long, value_enum)]
+    pub count: Option<CountMode>,
+    /// Kill the command if it runs for longer than this many seconds
+    #[arg(long)]
+    pub timeout: Option<f64>,
+    /// Run the command without a shell
+    #[arg(long, default_value_t = false)]
+    pub exec: bool,
+    /// Whether the command runs again while the key is held: 'always', 'never', or after this
+    /// many seconds
+    #[arg(long)]
+    pub repeat: Option<RepeatPolicy>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct CyclesCommand {
+    /// Only show cycles whose hotkey starts with this
+    pub hotkey: Option<String>,
+    /// Only show cycles in this mode. Use 'default' for bindings outside of any mode
+    #[arg(short, long)]
+    pub mode: Option<String>,
+    /// Start the cycles over at their first command
+    #[arg(short, long, default_value_t = false)]
+    pub reset: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct OutputCommand {
+    /// Only show commands of hotkeys which start with this
+    pub hotkey: Option<String>,
+    /// Show every failed command which is remembered instead of the last one
+    #[arg(short, long, default_value_t = false)]
+    pub all: bool,
 }
 
 #[derive(ValueEnum, Debug, Clone, PartialEq, Copy)]
@@ -85,6 +155,9 @@ pub enum SubscribeEventMask {
     Hotkey = 32,
     Command = 64,
     Change = 128,
+    /// A command started by a hotkey has exited. Every bit of the mask byte is taken, so it is
+    /// sent in a separate field.
+    Exit = 256,
     All = 255,
 }
 
@@ -128,6 +201,10 @@ pub enum IpcCommand {
     Bind(BindCommand),
     Unbind(UnbindCommand),
     Subscribe(SubscribeCommand),
+    Dump,
+    Cycles(CyclesCommand),
+    Running,
+    Output(OutputCommand),
 }
 
 #[derive(Error, Debug)]
@@ -171,6 +248,25 @@ impl TryFrom<&[u8]> for IpcCommand {
                 let hotkey = &buckets[3];
                 let command = &buckets[4];
                 let overwrite = buckets.get(5);
+                // Annotations were added later, so they are optional
+                let tags = buckets.get(6).map_or(vec![], |t| {
+                    String::from_utf8_lossy(t)
+                        .split(',')
+                        .filter(|t| !t.is_empty())
+                        .map(|t| t.to_string())
+                        .collect()
+                });
+                let icon = optional_string(buckets.get(7));
+                let hidden = buckets.get(8).is_some_and(|h| h.first() == Some(&b't'));
+                let mode = optional_string(buckets.get(9));
+                let conditions = optional_string(buckets.get(10)).map_or(vec![], |c| {
+                    c.split_whitespace().map(|c| c.to_string()).collect()
+                });
+                let count = optional_string(buckets.get(11))
+                    .and_then(|c| CountMode::from_str(&c, true).ok());
+                let timeout = optional_string(buckets.get(12)).and_then(|t| t.parse().ok());
+                let exec = buckets.get(13).is_some_and(|e| e.first() == Some(&b't'));
+                let repeat = optional_string(buckets.get(14)).and_then(|r| r.parse().ok());
                 let title = if !title.is_empty() { Some(title) } else { None };
                 let description = if !description.is_empty() {
                     Some(description)
@@ -190,27 +286,61 @@ impl TryFrom<&[u8]> for IpcCommand {
                         .and_then(|o| o.first().cloned())
                         .map(|c| c == b't')
                         .unwrap_or(false),
+                    tags,
+                    icon,
+                    hidden,
+                    mode,
+                    conditions,
+                    count,
+                    timeout,
+                    exec,
+                    repeat,
                 }))
             }
             [b'U'] => {
                 // parse Unbinding
                 let hotkey = String::from_utf8_lossy(&buckets[1]).to_string();
-                Ok(IpcCommand::Unbind(UnbindCommand { hotkey }))
+                Ok(IpcCommand::Unbind(UnbindCommand {
+                    hotkey,
+                    mode: optional_string(buckets.get(2)),
+                    command: optional_string(buckets.get(3)),
+                    conditions: optional_string(buckets.get(4)).map_or(vec![], |c| {
+                        c.split_whitespace().map(|c| c.to_string()).collect()
+                    }),
+                }))
             }
+            [b'D'] => Ok(IpcCommand::Dump),
+            [b'R'] => Ok(IpcCommand::Running),
+            [b'O'] => Ok(IpcCommand::Output(OutputCommand {
+                hotkey: optional_string(buckets.get(1)),
+                all: buckets.get(2).is_some_and(|a| a.first() == Some(&b't')),
+            })),
+            [b'C'] => Ok(IpcCommand::Cycles(CyclesCommand {
+                hotkey: optional_string(buckets.get(1)),
+                mode: optional_string(buckets.get(2)),
+                reset: buckets.get(3).is_some_and(|r| r.first() == Some(&b't')),
+            })),
             [b'S'] => {
-                // parse Subscription
+                // parse Subscription. Exit events are requested in an optional second field,
+                // and the mask byte is left empty if they are the only events.
                 let flags = &buckets[1];
-                if flags.len() > 1 {
+                if flags.len() > 1 || buckets.len() > 3 {
                     return Err(IpcCommandError::TrailingGarbage);
                 }
+                let exit = buckets.get(2).is_some_and(|e| e.first() == Some(&b't'));
 
-                if flags.len() != 1 {
+                if flags.is_empty() && !exit {
                     return Err(IpcCommandError::ParseError(
                         "Trailing garbage in event flags".to_string(),
                     ));
                 }
 
-                let mask = SubscribeEventMask::vec_from_u8(flags[0]);
+                let mut mask = flags
+                    .first()
+                    .map_or(vec![], |f| SubscribeEventMask::vec_from_u8(*f));
+                if exit && !mask.contains(&SubscribeEventMask::All) {
+                    mask.push(SubscribeEventMask::Exit);
+                }
                 if mask.is_empty() {
                     return Err(IpcCommandError::NoEvents);
                 }
@@ -224,6 +354,13 @@ impl TryFrom<&[u8]> for IpcCommand {
     }
 }
 
+// Fields which were added later are optional, and an empty field means the value is not set
+fn optional_string(bucket: Option<&Vec<u8>>) -> Option<String> {
+    bucket
+        .filter(|b| !b.is_empty())
+        .map(|b| String::from_utf8_lossy(b).to_string())
+}
+
 pub trait TryFromReader<T>
 where
     Self: Sized,
@@ -271,24 +408,133 @@ impl From<IpcCommand> for Vec<u8> {
                 result.push(0);
                 result.push(if b.overwrite { b't' } else { b'f' });
                 result.push(0);
+                result.extend_from_slice(b.tags.join(",").as_bytes());
+                result.push(0);
+                if let Some(i) = b.icon {
+                    result.extend_from_slice(i.as_bytes());
+                }
+                result.push(0);
+                result.push(if b.hidden { b't' } else { b'f' });
+                result.push(0);
+                if let Some(m) = b.mode {
+                    result.extend_from_slice(m.as_bytes());
+                }
+                result.push(0);
+                result.extend_from_slice(b.conditions.join(" ").as_bytes());
+                result.push(0);
+                if let Some(c) = b.count {
+                    result.extend_from_slice(c.to_string().as_bytes());
+                }
+                result.push(0);
+                if let Some(t) = b.timeout {
+                    result.extend_from_slice(t.to_string().as_bytes());
+                }
+                result.push(0);
+                result.push(if b.exec { b't' } else { b'f' });
+                result.push(0);
+                if let Some(r) = b.repeat {
+                    result.extend_from_slice(r.to_string().as_bytes());
+                }
+                result.push(0);
             }
             IpcCommand::Unbind(u) => {
                 result.push(b'U');
                 result.push(0);
                 result.extend_from_slice(u.hotkey.as_bytes());
                 result.push(0);
+                if let Some(m) = u.mode {
+                    result.extend_from_slice(m.as_bytes());
+                }
+                result.push(0);
+                if let Some(c) = u.command {
+                    result.extend_from_slice(c.as_bytes());
+                }
+                result.push(0);
+                result.extend_from_slice(u.conditions.join(" ").as_bytes());
+                result.push(0);
             }
             IpcCommand::Subscribe(s) => {
                 let sub = s.events;
                 result.push(b'S');
                 result.push(0);
                 let mut mask: u8 = 0;
+                let mut exit = false;
                 for item in sub {
-                    mask |= item as u8;
+                    match item {
+                        SubscribeEventMask::Exit => exit = true,
+                        item => mask |= item as u8,
+                    }
+                }
+                // A zero byte would be read as a separator
+                if mask != 0 {
+                    result.push(mask);
+                }
+                if exit {
+                    result.push(0);
+                    result.push(b't');
+                }
+            }
+            IpcCommand::Dump => {
+                result.push(b'D');
+                result.push(0);
+            }
+            IpcCommand::Running => {
+                result.push(b'R');
+                result.push(0);
+            }
+            IpcCommand::Output(o) => {
+                result.push(b'O');
+                result.push(0);
+                if let Some(h) = o.hotkey {
+                    result.extend_from_slice(h.as_bytes());
                 }
-                result.push(mask);
+                result.push(0);
+                result.push(if o.all { b't' } else { b'f' });
+                result.push(0);
+            }
+            IpcCommand::Cycles(c) => {
+                result.push(b'C');
+                result.push(0);
+                if let Some(h) = c.hotkey {
+                    result.extend_from_slice(h.as_bytes());
+                }
+                result.push(0);
+                if let Some(m) = c.mode {
+                    result.extend_from_slice(m.as_bytes());
+                }
+                result.push(0);
+                result.push(if c.reset { b't' } else { b'f' });
+                result.push(0);
             }
         }
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{IpcCommand, SubscribeCommand, SubscribeEventMask};
+
+    #[test]
+    fn test_subscribe() {
+        use SubscribeEventMask::*;
+        let subscribe = |events: Vec<SubscribeEventMask>| -> Vec<u8> {
+            IpcCommand::Subscribe(SubscribeCommand { events }).into()
+        };
+        let parse = |bytes: &[u8]| match IpcCommand::try_from(bytes) {
+            Ok(IpcCommand::Subscribe(s)) => s.events,
+            _ => panic!("Expected a subscription"),
+        };
+        // The mask is a single byte, as before exit events were added
+        assert_eq!(b"S\0\xff", &subscribe(vec![All])[..]);
+        assert_eq!(vec![All], parse(b"S\0\xff"));
+        assert_eq!(b"S\0\x42", &subscribe(vec![Reload, Command])[..]);
+        assert_eq!(vec![Reload, Command], parse(b"S\0\x42"));
+
+        assert_eq!(b"S\0\x40\0t", &subscribe(vec![Command, Exit])[..]);
+        assert_eq!(vec![Command, Exit], parse(b"S\0\x40\0t"));
+        assert_eq!(b"S\0\0t", &subscribe(vec![Exit])[..]);
+        assert_eq!(vec![Exit], parse(b"S\0\0t"));
+        assert!(IpcCommand::try_from(&b"S\0"[..]).is_err());
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, ExitStatus};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use nix::sys::signal::{killpg, SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::unistd::Pid;
+
+use super::output::{Capture, CommandLog};
+use crate::parser::{Chord, Hotkey};
+
+/// How many failed commands keep their output
+const FAILURES: usize = 16;
+
+/// A command started by a hotkey which has not been reaped yet
+pub struct Running {
+    pub pid: u32,
+    pub command: Arc<str>,
+    /// The chain of the hotkey which started the command
+    pub chain: String,
+    pub started: Instant,
+    /// When the command is killed, if it has a timeout. A timeout which is too long to be
+    /// represented never expires.
+    deadline: Option<Instant>,
+    chords: Arc<[Chord]>,
+    child: Child,
+}
+
+/// A command started by a hotkey which has exited
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exit {
+    pub pid: u32,
+    pub command: Arc<str>,
+    pub status: ExitCode,
+    pub duration: Duration,
+}
+
+/// A command which exited with an error, and the end of its output
+pub struct Failure {
+    pub exit: Exit,
+    /// The chain of the hotkey which started the command
+    pub chain: Arc<[Chord]>,
+    pub output: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitCode {
+    Code(i32),
+    /// The command was killed by this signal
+    Signal(i32),
+}
+
+impl From<ExitStatus> for ExitCode {
+    fn from(status: ExitStatus) -> Self {
+        match status.code() {
+            Some(code) => ExitCode::Code(code),
+            None => ExitCode::Signal(status.signal().unwrap_or(0)),
+        }
+    }
+}
+
+impl Display for ExitCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitCode::Code(code) => write!(f, "{}", code),
+            ExitCode::Signal(signal) => write!(f, "signal {}", signal),
+        }
+    }
+}
+
+impl ExitCode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.strip_prefix("signal ") {
+            Some(signal) => signal.parse().ok().map(ExitCode::Signal),
+            None => s.parse().ok().map(ExitCode::Code),
+        }
+    }
+}
+
+/// Keeps track of the commands started by hotkeys, so they are reaped when they exit. SIGCHLD
+/// is read from a signalfd, which wakes up the main loop when a command exits.
+pub struct Children {
+    signals: Option<SignalFd>,
+    running: Vec<Running>,
+    /// Where the output of commands is written, if it is captured
+    log: Option<CommandLog>,
+    /// How many lines of output are kept for each failed command
+    lines: usize,
+    captures: Vec<Capture>,
+    /// The most recent failed commands, oldest first
+    failures: VecDeque<Failure>,
+}
+
+impl Children {
+    pub fn new() -> Self {
+        let mut mask = SigSet::empty();
+        mask.add(Signal::SIGCHLD);
+        // A signal has to be blocked to be read from a signalfd. Spawned commands start with an
+        // empty signal mask, so this does not leak into them.
+        let signals = mask.thread_block().and_then(|_| {
+            SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)
+        });
+        let signals = match signals {
+            Ok(signals) => Some(signals),
+            Err(e) => {
+                eprintln!("Exited commands are only reaped after other events: {}", e);
+                None
+            }
+        };
+        Children {
+            signals,
+            running: vec![],
+            log: None,
+            lines: 0,
+            captures: vec![],
+            failures: VecDeque::new(),
+        }
+    }
+
+    /// Write the output of commands to `log`, and keep the last `lines` lines of the commands
+    /// which fail. Commands have to be spawned with piped stdout and stderr from now on.
+    pub fn capture_output(&mut self, log: CommandLog, lines: usize) {
+        self.log = Some(log);
+        self.lines = lines;
+    }
+
+    pub fn captures_output(&self) -> bool {
+        self.log.is_some()
+    }
+
+    pub fn track(&mut self, mut child: Child, hotkey: &Hotkey) {
+        let started = Instant::now();
+        if self.log.is_some() {
+            let capture = Capture::new(&mut child, hotkey.chain_repr(), self.lines);
+            self.captures.push(capture);
+        }
+        self.running.push(Running {
+            pid: child.id(),
+            command: hotkey.command.clone(),
+            chain: hotkey.chain_repr(),
+            started,
+            deadline: hotkey
+                .timeout
+                .and_then(|timeout| started.checked_add(timeout)),
+            chords: hotkey.chain.clone(),
+            child,
+        });
+    }
+
+    /// The time left until the next command times out
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.running
+            .iter()
+            .filter_map(|running| running.deadline)
+            .min()
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Kill the commands which ran for longer than their timeout, along with the processes they
+    /// started. Commands with a timeout lead their own process group, see `Executor::command`.
+    /// They are reaped like any other command which exits.
+    pub fn kill_expired(&mut self) -> Vec<&Running> {
+        let now = Instant::now();
+        let mut killed = vec![];
+        for running in self.running.iter_mut() {
+            if running.deadline.is_some_and(|deadline| deadline <= now) {
+                running.deadline = None;
+                let group = Pid::from_raw(running.pid as i32);
+                if let Err(e) = killpg(group, Signal::SIGKILL) {
+                    eprintln!("Failed to kill '{}': {}", running.command, e);
+                }
+                killed.push(&*running);
+            }
+        }
+        killed
+    }
+
+    /// Read the output of the commands, and reap the commands which have exited since the last
+    /// call
+    pub fn reap(&mut self) -> Vec<Exit> {
+        if let Some(ref mut signals) = self.signals {
+            while let Ok(Some(_)) = signals.read_signal() {}
+        }
+        let mut exits = vec![];
+        let mut failed = vec![];
+        self.running.retain_mut(|running| {
+            let status = match running.child.try_wait() {
+                Ok(Some(status)) => status,
+                Ok(None) => return true,
+                Err(e) => {
+                    eprintln!("Failed to wait for '{}': {}", running.command, e);
+                    return false;
+                }
+            };
+            let exit = Exit {
+                pid: running.pid,
+                command: running.command.clone(),
+                status: status.into(),
+                duration: running.started.elapsed(),
+            };
+            if exit.status != ExitCode::Code(0) {
+                failed.push((exit.clone(), running.chords.clone()));
+            }
+            exits.push(exit);
+            false
+        });
+        // Output written right before a command exited is read after it was reaped, so it is
+        // kept with the failure
+        if let Some(ref mut log) = self.log {
+            for capture in self.captures.iter_mut() {
+                capture.read(log);
+            }
+            for (exit, chain) in failed {
+                let output = self
+                    .captures
+                    .iter()
+                    .find(|capture| capture.pid == exit.pid)
+                    .map_or(vec![], |capture| capture.tail());
+                if self.failures.len() == FAILURES {
+                    self.failures.pop_front();
+                }
+                self.failures.push_back(Failure {
+                    exit,
+                    chain,
+                    output,
+                });
+            }
+            self.captures.retain(|capture| !capture.is_closed());
+        }
+        exits
+    }
+
+    /// The most recent failed commands, oldest first. Only kept while output is captured.
+    pub fn failures(&self) -> &VecDeque<Failure> {
+        &self.failures
+    }
+
+    /// The commands which are still running, in the order they were started
+    pub fn running(&self) -> &[Running] {
+        &self.running
+    }
+
+    /// The fds which become readable when a command exits or writes output
+    pub fn fds(&self) -> Vec<BorrowedFd<'_>> {
+        self.signals
+            .iter()
+            .map(|s| s.as_fd())
+            .chain(self.captures.iter().flat_map(|capture| capture.fds()))
+            .collect()
+    }
+}
+
+impl Default for Children {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::DefaultKeymap;
+    use crate::parser::config;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::process::CommandExt;
+
+    #[test]
+    fn test_reap() -> anyhow::Result<()> {
+        let config = config::load_config_from_bytes(b"a\n  exit 3\n", &DefaultKeymap)?;
+        let hotkey = &config.get_hotkeys()[0];
+        let mut children = Children::new();
+        let child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(&*hotkey.command)
+            .spawn()?;
+        let pid = child.id();
+        children.track(child, hotkey);
+        assert_eq!(1, children.running().len());
+
+        let start = Instant::now();
+        let mut exits = vec![];
+        while exits.is_empty() && start.elapsed() < Duration::from_secs(5) {
+            exits = children.reap();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(1, exits.len());
+        assert_eq!(pid, exits[0].pid);
+        assert_eq!(ExitCode::Code(3), exits[0].status);
+        assert_eq!("exit 3", &*exits[0].command);
+        assert!(children.running().is_empty());
+
+        // Commands which time out are killed
+        let config =
+            config::load_config_from_bytes(b"#@ timeout: 0.1\nb\n  sleep 10\n", &DefaultKeymap)?;
+        let hotkey = &config.get_hotkeys()[0];
+        // The processes started by the command are killed with it
+        let mut child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg("sleep 10 & echo $!; wait")
+            .stdout(std::process::Stdio::piped())
+            .process_group(0)
+            .spawn()?;
+        let mut sleep = String::new();
+        BufReader::new(child.stdout.take().expect("stdout")).read_line(&mut sleep)?;
+        let sleep = format!("/proc/{}/stat", sleep.trim());
+        children.track(child, hotkey);
+        assert!(children.kill_expired().is_empty());
+        assert!(children.next_deadline().is_some());
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(1, children.kill_expired().len());
+        assert_eq!(None, children.next_deadline());
+        let mut exits = vec![];
+        while exits.is_empty() && start.elapsed() < Duration::from_secs(5) {
+            exits = children.reap();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(ExitCode::Signal(9), exits[0].status);
+        // The orphaned sleep is a zombie until init reaps it
+        let killed = || std::fs::read_to_string(&sleep).map_or(true, |s| s.contains(") Z "));
+        let start = Instant::now();
+        while !killed() && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(killed());
+
+        // A timeout which ends too far in the future never expires
+        let config =
+            config::load_config_from_bytes(b"#@ timeout: 1e19\nb\n  sleep 10\n", &DefaultKeymap)?;
+        let hotkey = &config.get_hotkeys()[0];
+        let child = std::process::Command::new("sleep").arg("10").spawn()?;
+        let pid = Pid::from_raw(child.id() as i32);
+        children.track(child, hotkey);
+        assert_eq!(None, children.next_deadline());
+        nix::sys::signal::kill(pid, Signal::SIGKILL)?;
+        let start = Instant::now();
+        while !children.running().is_empty() && start.elapsed() < Duration::from_secs(5) {
+            children.reap();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(Some(ExitCode::Signal(9)), ExitCode::parse("signal 9"));
+        assert_eq!(Some(ExitCode::Code(1)), ExitCode::parse("1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_capture_output() -> anyhow::Result<()> {
+        let dir = crate::TempDir::new("output_test")?;
+        let config = config::load_config_from_bytes(
+            b"super + a\n  printf 'one\\ntwo\\nthree'; exit 1\n",
+            &DefaultKeymap,
+        )?;
+        let hotkey = &config.get_hotkeys()[0];
+        let mut children = Children::new();
+        // Small enough that every line starts a new log
+        children.capture_output(CommandLog::open(&dir, 40)?, 2);
+        let child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(&*hotkey.command)
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+        children.track(child, hotkey);
+
+        let start = Instant::now();
+        let mut exits = vec![];
+        while exits.is_empty() && start.elapsed() < Duration::from_secs(5) {
+            exits = children.reap();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(ExitCode::Code(1), exits[0].status);
+        let failure = &children.failures()[0];
+        assert_eq!("super + a", crate::parser::chain_repr(&failure.chain));
+        assert_eq!(vec!["two", "three"], failure.output);
+
+        let log = std::fs::read_to_string(dir.join("commands.log"))?;
+        assert!(log.ends_with("[super + a] stdout: three\n"));
+        let rotated = std::fs::read_to_string(dir.join("commands.log.1"))?;
+        assert!(rotated.ends_with("[super + a] stdout: two\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_capture_long_lines() -> anyhow::Result<()> {
+        let dir = crate::TempDir::new("long_output_test")?;
+        let config = config::load_config_from_bytes(
+            b"super + a\n  head -c 10000 /dev/zero | tr '\\0' x; exit 1\n",
+            &DefaultKeymap,
+        )?;
+        let hotkey = &config.get_hotkeys()[0];
+        let mut children = Children::new();
+        children.capture_output(CommandLog::open(&dir, 1024 * 1024)?, 5);
+        let child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(&*hotkey.command)
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+        children.track(child, hotkey);
+
+        let start = Instant::now();
+        let mut exits = vec![];
+        while exits.is_empty() && start.elapsed() < Duration::from_secs(5) {
+            exits = children.reap();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // Output without newlines is cut into lines
+        let lengths: Vec<_> = children.failures()[0]
+            .output
+            .iter()
+            .map(|line| line.len())
+            .collect();
+        assert_eq!(vec![4096, 4096, 1808], lengths);
+        Ok(())
+    }
+}
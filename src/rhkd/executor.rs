@@ -1,22 +1,51 @@
-use std::os::fd::{AsRawFd, FromRawFd, RawFd};
-use std::process::Stdio;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Stdio};
 
-use crate::parser::types::Hotkey;
-use anyhow::Result;
+use super::children::{Children, Exit};
+use super::output::CommandLog;
+use crate::keyboard;
+use crate::parser::types::{CountMode, Hotkey};
+use crate::CliArguments;
+use anyhow::{anyhow, bail, Context, Result};
+
+/// The event which triggered a command
+#[derive(Clone, Copy)]
+pub struct Trigger {
+    /// The count typed before the last chord of the chain
+    pub count: Option<u32>,
+    /// The X server time of the event, in milliseconds
+    pub time: u32,
+}
 
 pub struct Executor {
-    #[allow(unused)]
-    // We keep a reference to the file in addition to the filedescriptor so the file gets closed
-    // when this is dropped
-    file_handle: Option<std::fs::File>,
-    redir_fd: Option<RawFd>,
+    /// The file the output of every command is written to, unless it is captured
+    redir_file: Option<File>,
     shell: String,
+    /// The arguments of the shell before the command
+    shell_args: Vec<String>,
+    children: Children,
+    /// Sync commands which wait for the running sync command to exit, in the order they were run,
+    /// and how many times each of them still has to run
+    queue: VecDeque<(Hotkey, Trigger, u32)>,
+    /// The pid of the sync command which is running
+    sync_pid: Option<u32>,
 }
 
 impl Executor {
-    pub fn new(redir_file: Option<String>) -> Self {
-        let shell = std::env::var("SHELL").unwrap_or("bash".to_string());
-        let redir_fd = redir_file
+    pub fn new(cli: &CliArguments) -> Self {
+        let shell = cli
+            .shell
+            .clone()
+            .or_else(|| std::env::var("SHELL").ok())
+            .unwrap_or("bash".to_string());
+        // The arguments were already checked when they were parsed from the command line
+        let shell_args = split_words(&cli.shell_args).unwrap_or_default();
+        let redir_file = cli
+            .redir_file
+            .as_ref()
             .map(|r| {
                 std::fs::OpenOptions::new()
                     .read(true)
@@ -27,29 +56,353 @@ impl Executor {
                     .ok()
             })
             .unwrap_or(None);
-        let raw_fd = redir_fd.as_ref().map(|file| file.as_raw_fd());
+        let mut children = Children::new();
+        if let Some(ref dir) = cli.log_dir {
+            match CommandLog::open(Path::new(dir), cli.log_size * 1024) {
+                Ok(log) => children.capture_output(log, cli.output_lines),
+                Err(e) => eprintln!("Failed to open the command log in {}: {}", dir, e),
+            }
+        }
         Self {
-            file_handle: redir_fd,
-            redir_fd: raw_fd,
+            redir_file,
             shell,
+            shell_args,
+            children,
+            queue: VecDeque::new(),
+            sync_pid: None,
+        }
+    }
+
+    pub fn run(&mut self, hk: &Hotkey, trigger: &Trigger) -> Result<()> {
+        let repeat = match hk.count {
+            Some(CountMode::Repeat) => trigger.count.unwrap_or(1),
+            _ => 1,
+        };
+        if !hk.sync {
+            // A command which cannot be started is not repeated
+            for _ in 0..repeat {
+                let cmd = self.spawn(hk, trigger)?;
+                self.children.track(cmd, hk);
+            }
+            return Ok(());
         }
+        // Sync commands, and their repetitions, run one after another. They are started from the
+        // main loop when the previous one exits, so keys are still handled in the meantime.
+        self.queue.push_back((hk.clone(), *trigger, repeat));
+        self.run_queued()
     }
 
-    pub fn run(&self, hk: &Hotkey) -> Result<()> {
-        let mut cmd = std::process::Command::new(self.shell.as_str());
-        let mut cmd = cmd.arg("-c").arg(hk.command.to_string()).stdin(Stdio::null());
-        if let Some(fd) = self.redir_fd {
-            unsafe {
-                cmd = cmd
-                    .stdout(Stdio::from_raw_fd(fd))
-                    .stderr(Stdio::from_raw_fd(fd))
+    /// Start the queued commands, until one of them has to wait for a sync command to exit
+    pub fn run_queued(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        while self.sync_pid.is_none() {
+            let Some((hk, trigger, repeat)) = self.queue.pop_front() else {
+                break;
+            };
+            match self.spawn(&hk, &trigger) {
+                Ok(cmd) => {
+                    self.sync_pid = Some(cmd.id());
+                    self.children.track(cmd, &hk);
+                    // The remaining repetitions wait for this one
+                    if repeat > 1 {
+                        self.queue.push_front((hk, trigger, repeat - 1));
+                    }
+                }
+                // A command which cannot be started is not repeated
+                Err(e) => result = result.and(Err(e)),
             }
         }
-        let mut cmd = cmd.spawn()?;
-        // .spawn()?;
-        if hk.sync {
-            cmd.wait()?;
+        result
+    }
+
+    /// Reap the commands which have exited. Queued commands can run once the sync command
+    /// exits, see `run_queued`.
+    pub fn reap(&mut self) -> Vec<Exit> {
+        let exits = self.children.reap();
+        // The sync command is also dropped if waiting for it failed
+        let running = self.children.running();
+        if self
+            .sync_pid
+            .is_some_and(|pid| !running.iter().any(|r| r.pid == pid))
+        {
+            self.sync_pid = None;
+        }
+        exits
+    }
+
+    pub fn children(&self) -> &Children {
+        &self.children
+    }
+
+    pub fn children_mut(&mut self) -> &mut Children {
+        &mut self.children
+    }
+
+    fn spawn(&self, hk: &Hotkey, trigger: &Trigger) -> Result<Child> {
+        self.command(hk, trigger)
+            .and_then(|mut cmd| Ok(cmd.spawn()?))
+            .with_context(|| {
+                format!(
+                    "Error running command of {}: {}",
+                    hk.chain_repr(),
+                    hk.command
+                )
+            })
+    }
+
+    fn command(&self, hk: &Hotkey, trigger: &Trigger) -> Result<std::process::Command> {
+        let mut cmd = if hk.exec {
+            let words = split_words(&hk.command)?;
+            let (program, args) = words
+                .split_first()
+                .ok_or_else(|| anyhow!("The command is empty"))?;
+            let mut cmd = std::process::Command::new(program);
+            cmd.args(args);
+            cmd
+        } else {
+            let mut cmd = std::process::Command::new(self.shell.as_str());
+            cmd.args(&self.shell_args).arg(hk.command.to_string());
+            cmd
+        };
+        cmd.stdin(Stdio::null());
+        // A command which times out is killed with everything it started
+        if hk.timeout.is_some() {
+            cmd.process_group(0);
+        }
+        // Variables which do not apply to this hotkey must not leak in from rhkd's environment
+        for name in VARIABLES {
+            cmd.env_remove(name);
         }
+        cmd.envs(environment(hk, trigger));
+        if self.children.captures_output() {
+            cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        } else if let Some(ref file) = self.redir_file {
+            // Each command owns its copies of the file, which are closed when it is spawned
+            cmd.stdout(file.try_clone()?).stderr(file.try_clone()?);
+        }
+        Ok(cmd)
+    }
+}
+
+/// Split a command into arguments with the quoting rules of a POSIX shell. Nothing is expanded:
+/// single and double quotes group words, and a backslash escapes the next character.
+pub fn split_words(command: &str) -> Result<Vec<String>> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => bail!("Unterminated single quote"),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        // Inside double quotes, a backslash only escapes characters which are
+                        // special there
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some('\n') => {}
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => bail!("Unterminated double quote"),
+                        },
+                        Some(c) => word.push(c),
+                        None => bail!("Unterminated double quote"),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some('\n') => {}
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => bail!("Nothing to escape after the trailing backslash"),
+            },
+            c if c.is_whitespace() => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+const VARIABLES: [&str; 9] = [
+    "RHKD_CHAIN",
+    "RHKD_TIME",
+    "RHKD_KEYSYM",
+    "RHKD_BUTTON",
+    "RHKD_MODE",
+    "RHKD_TITLE",
+    "RHKD_DESCRIPTION",
+    "RHKD_CYCLE_INDEX",
+    "RHKD_COUNT",
+];
+
+// Tell the command what triggered it, so one script can serve several bindings
+fn environment(hk: &Hotkey, trigger: &Trigger) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("RHKD_CHAIN", hk.chain_repr()),
+        ("RHKD_TIME", trigger.time.to_string()),
+    ];
+    let last = hk.chain.last();
+    match last.map(|chord| chord.button) {
+        Some(0) => {
+            let keysym = last.and_then(|chord| keyboard::string_from_symbol(chord.keysym));
+            if let Some(keysym) = keysym {
+                env.push(("RHKD_KEYSYM", keysym.to_string()));
+            }
+        }
+        Some(button) => env.push(("RHKD_BUTTON", button.to_string())),
+        None => {}
+    }
+    if let Some(ref mode) = hk.mode {
+        env.push(("RHKD_MODE", mode.to_string()));
+    }
+    if let Some(ref title) = hk.title {
+        env.push(("RHKD_TITLE", title.to_string()));
+    }
+    if let Some(ref description) = hk.description {
+        env.push(("RHKD_DESCRIPTION", description.to_string()));
+    }
+    if let Some(ref cycle) = hk.cycle {
+        env.push(("RHKD_CYCLE_INDEX", cycle.delay.to_string()));
+    }
+    if hk.count.is_some() {
+        env.push(("RHKD_COUNT", trigger.count.unwrap_or(1).to_string()));
+    }
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_words() -> Result<()> {
+        assert_eq!(
+            vec!["notify-send", "two words", "it's", "a\\b", "$HOME", ""],
+            split_words(r#"notify-send "two words" it\'s 'a\b' "\$HOME" ''"#)?
+        );
+        assert_eq!(vec!["a", "b"], split_words("  a\t b \n")?);
+        assert!(split_words("").unwrap().is_empty());
+        assert!(split_words("echo 'a").is_err());
+        assert!(split_words("echo \"a").is_err());
+        assert!(split_words("echo a\\").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_environment() -> Result<()> {
+        use crate::keyboard::DefaultKeymap;
+        use crate::parser::config;
+        use clap::Parser;
+
+        let config = config::load_config_from_bytes(
+            b"mode resize {\n#@ count\nsuper + w ; Return\n  echo {a,b}\n}\n",
+            &DefaultKeymap,
+        )?;
+        let hotkey = &config.get_hotkeys()[1];
+        let trigger = Trigger {
+            count: Some(4),
+            time: 1234,
+        };
+        let env = environment(hotkey, &trigger);
+        let value = |name: &str| {
+            env.iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(Some("super + w ; Return"), value("RHKD_CHAIN"));
+        assert_eq!(Some("Return"), value("RHKD_KEYSYM"));
+        assert_eq!(Some("1234"), value("RHKD_TIME"));
+        assert_eq!(Some("resize"), value("RHKD_MODE"));
+        assert_eq!(Some("1"), value("RHKD_CYCLE_INDEX"));
+        assert_eq!(Some("4"), value("RHKD_COUNT"));
+        assert_eq!(None, value("RHKD_BUTTON"));
+        assert_eq!(None, value("RHKD_TITLE"));
+
+        // Variables which do not apply are removed, in case rhkd was started with them
+        let executor = Executor::new(&CliArguments::parse_from(["rhkd", "--shell", "sh"]));
+        let cmd = executor.command(hotkey, &trigger)?;
+        let envs: Vec<_> = cmd.get_envs().collect();
+        assert!(envs.contains(&("RHKD_BUTTON".as_ref(), None)));
+        assert!(envs.contains(&("RHKD_TITLE".as_ref(), None)));
+        assert!(envs.contains(&("RHKD_CHAIN".as_ref(), Some("super + w ; Return".as_ref()))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_repeat() -> Result<()> {
+        use crate::keyboard::DefaultKeymap;
+        use crate::parser::config;
+        use clap::Parser;
+        use std::time::{Duration, Instant};
+
+        let config = config::load_config_from_bytes(
+            b"#@ count: repeat\nsuper + a ; b\n  true\n#@ count: repeat\nsuper + a ; c\n  ;true\n",
+            &DefaultKeymap,
+        )?;
+        let mut executor = Executor::new(&CliArguments::parse_from(["rhkd", "--shell", "sh"]));
+        let trigger = Trigger {
+            count: Some(3),
+            time: 0,
+        };
+        // Repetitions of an async command start right away
+        executor.run(&config.get_hotkeys()[0], &trigger)?;
+        assert_eq!(3, executor.children().running().len());
+        assert!(executor.queue.is_empty());
+
+        // The repetitions of a sync command wait for the first run as one entry
+        executor.run(&config.get_hotkeys()[1], &trigger)?;
+        assert_eq!(1, executor.queue.len());
+        assert_eq!(2, executor.queue[0].2);
+
+        let start = Instant::now();
+        let mut runs = 0;
+        while runs < 6 && start.elapsed() < Duration::from_secs(5) {
+            runs += executor.reap().len();
+            executor.run_queued()?;
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(6, runs);
+        assert!(executor.queue.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_wait_failed() -> Result<()> {
+        use crate::keyboard::DefaultKeymap;
+        use crate::parser::config;
+        use clap::Parser;
+
+        let config = config::load_config_from_bytes(
+            b"super + a\n  ;true\nsuper + b\n  ;true\n",
+            &DefaultKeymap,
+        )?;
+        let mut executor = Executor::new(&CliArguments::parse_from(["rhkd", "--shell", "sh"]));
+        let trigger = Trigger {
+            count: None,
+            time: 0,
+        };
+        executor.run(&config.get_hotkeys()[0], &trigger)?;
+        executor.run(&config.get_hotkeys()[1], &trigger)?;
+        let pid = executor.sync_pid.unwrap();
+        // Reap the command behind the executor's back, so waiting for it fails
+        unsafe { nix::libc::waitpid(pid as i32, std::ptr::null_mut(), 0) };
+        assert!(executor.reap().is_empty());
+        assert_eq!(None, executor.sync_pid);
+        executor.run_queued()?;
+        assert!(executor.queue.is_empty());
         Ok(())
     }
 }
@@ -60,7 +60,7 @@ impl Fifo {
             IpcMessage::BeginChain => "BBegin chain".to_string().into(),
             IpcMessage::EndChain => "EEnd chain".to_string().into(),
             IpcMessage::Timeout => "TTimeout reached".to_string().into(),
-            IpcMessage::Hotkey(hk) => format!("H{}", hk).into(),
+            IpcMessage::Hotkey(hk) => format!("H{}", hk.chain).into(),
             IpcMessage::Command(c) => format!("C{}", c).into(),
             // The fifo should only implement the messages supported by SXHKD.
             // Sockets support a wider range of messages and are preferred
@@ -1,20 +1,26 @@
 use crate::keyboard::kbd;
 use crate::parser::config::AddBindingError;
-use crate::parser::Hotkey;
-use crate::rhkc::ipc::{BindCommand, SubscribeEventMask, UnbindCommand};
+use crate::parser::diff::{self, HotkeyDiff, ModeDiff, PrefixDiff};
+use crate::parser::index::Node;
+use crate::parser::{chain_repr, parse_chord_chain, Action, Hotkey, Mode, DEFAULT_MODE};
+use crate::rhkc::ipc::{
+    BindCommand, CyclesCommand, OutputCommand, SubscribeEventMask, UnbindCommand,
+};
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::io::Write;
 
 use super::fifo::{Fifo, FifoError};
 use super::*;
 
-use super::executor::Executor;
+use super::children::Children;
+use super::executor::{Executor, Trigger};
 use super::keyboard;
 use xcb::x::ModMask;
 
 use anyhow::{bail, Result};
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 struct AbortKeysym {
     keycodes: Vec<u8>,
 }
@@ -23,12 +29,27 @@ pub struct HotkeyHandler {
     cli: CliArguments,
     config: Config,
     chain: Vec<ChainItem>,
+    /// The active mode, or `None` if the bindings outside of any mode are active
+    mode: Option<Mode>,
+    /// The key which leaves the active mode
+    mode_abort: AbortKeysym,
+    /// A count typed inside the active chain, for hotkeys with `#@ count`
+    count: Option<u32>,
     abort: AbortKeysym,
     backspace: AbortKeysym,
     grab: bool,
     fifo: Option<Fifo>,
     executor: Executor,
     subscribers: RefCell<Vec<Client>>,
+    requests: Requests,
+}
+
+/// Things bindings asked for which are done by the main loop once the key is handled, like it
+/// does for the signals
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Requests {
+    pub reload: bool,
+    pub toggle_grab: bool,
 }
 
 struct Client {
@@ -42,6 +63,13 @@ struct ChainItem {
     locking: bool,
 }
 
+/// A key or button grabbed on the root window, with its modifiers
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Grab {
+    Key(u8, u32),
+    Button(u8, u32),
+}
+
 impl HotkeyHandler {
     pub fn toggle_grab(&mut self) -> Result<()> {
         if self.grab {
@@ -56,9 +84,42 @@ impl HotkeyHandler {
     pub fn reload(&mut self) -> Result<()> {
         match self.config.reload() {
             Ok(new) => {
-                self.config = new;
-                self.ungrab_all()?;
-                self.grab_index_0()?;
+                print_diagnostics(new.diagnostics(), self.cli.diagnostic_format);
+                // A config which is being edited often contains errors. Keep the old bindings
+                // until it is fixed, instead of dropping the broken ones.
+                let errors: Vec<_> = new
+                    .diagnostics()
+                    .iter()
+                    .filter(|d| d.severity == Severity::Error)
+                    .collect();
+                if !errors.is_empty() {
+                    for error in errors {
+                        self.publish(&IpcMessage::Error(
+                            format!("Config reload failed: {}", error.to_line()).into(),
+                        ));
+                    }
+                    return Ok(());
+                }
+                let old_chords = self.first_chords();
+                let old = std::mem::replace(&mut self.config, new);
+                let diff = diff::diff(old.get_hotkeys(), self.config.get_hotkeys());
+                let prefixes = diff::diff_prefixes(old.prefixes(), self.config.prefixes());
+                let modes = diff::diff_modes(old.modes(), self.config.modes());
+                // Stay in the active mode if it still exists
+                let name = self.mode.as_ref().map(|m| m.name.clone());
+                let mode = name
+                    .as_deref()
+                    .and_then(|n| self.config.get_mode(n))
+                    .cloned();
+                let same_mode = mode.as_ref().map(|m| (&m.name, &m.abort))
+                    == self.mode.as_ref().map(|m| (&m.name, &m.abort));
+                if same_mode && self.grab && self.chain.is_empty() {
+                    self.mode = mode;
+                    self.regrab(&old_chords);
+                } else {
+                    self.set_mode(name.as_deref().unwrap_or(DEFAULT_MODE));
+                }
+                self.publish_diff(&diff, &modes, &prefixes);
                 self.publish(&IpcMessage::ConfigReloaded);
             }
             Err(e) => self.publish(&IpcMessage::Error(
@@ -68,6 +129,29 @@ impl HotkeyHandler {
         Ok(())
     }
 
+    /// Tell subscribers how the bindings changed, so they can update their copy of the config.
+    /// Modes are sent before the bindings, since a binding can switch to a mode which is new.
+    fn publish_diff(&self, diff: &HotkeyDiff, modes: &ModeDiff, prefixes: &PrefixDiff) {
+        for unbind in diff.unbind_commands() {
+            self.publish(&IpcMessage::BindingRemoved(unbind));
+        }
+        for mode in &modes.removed {
+            self.publish(&IpcMessage::ModeRemoved(mode.into()));
+        }
+        for mode in &modes.added {
+            self.publish(&IpcMessage::ModeAdded(mode.into()));
+        }
+        for bind in diff.bind_commands() {
+            self.publish(&IpcMessage::BindingAdded(bind));
+        }
+        for prefix in &prefixes.removed {
+            self.publish(&IpcMessage::PrefixRemoved(prefix.into()));
+        }
+        for prefix in &prefixes.added {
+            self.publish(&IpcMessage::PrefixAdded(prefix.into()));
+        }
+    }
+
     pub fn publish(&self, message: &IpcMessage) {
         fn is_interested(mask: &[SubscribeEventMask], message: &IpcMessage) -> bool {
             use SubscribeEventMask::*;
@@ -77,14 +161,20 @@ impl HotkeyHandler {
             match message {
                 IpcMessage::Notify(_) => mask.contains(&Notifications),
                 IpcMessage::ConfigReloaded => mask.contains(&Reload),
-                IpcMessage::BeginChain | IpcMessage::EndChain => mask.contains(&Chain),
+                IpcMessage::BeginChain | IpcMessage::EndChain | IpcMessage::ModeChanged(_) => {
+                    mask.contains(&Chain)
+                }
                 IpcMessage::Timeout => mask.contains(&Timeout),
-                IpcMessage::Hotkey(_) => mask.contains(&Hotkey),
+                IpcMessage::Hotkey(..) => mask.contains(&Hotkey),
                 IpcMessage::Command(_) => mask.contains(&Command),
+                IpcMessage::CommandExited(_) => mask.contains(&Exit),
                 IpcMessage::Error(_) => mask.contains(&Errors),
-                IpcMessage::BindingRemoved(_) | IpcMessage::BindingAdded(_) => {
-                    mask.contains(&Change)
-                }
+                IpcMessage::BindingRemoved(_)
+                | IpcMessage::BindingAdded(_)
+                | IpcMessage::PrefixRemoved(_)
+                | IpcMessage::PrefixAdded(_)
+                | IpcMessage::ModeRemoved(_)
+                | IpcMessage::ModeAdded(_) => mask.contains(&Change),
             }
         }
         if let Some(ref fifo) = self.fifo {
@@ -122,7 +212,9 @@ impl HotkeyHandler {
             Key {
                 modfield: 0,
                 is_press: true,
+                button: 0,
                 symbol: s,
+                ..
             } => target.keycodes.contains(s),
             _ => false,
         }
@@ -133,9 +225,120 @@ impl HotkeyHandler {
     fn is_abort(&self, key: &Key) -> bool {
         Self::is_key(key, &self.abort)
     }
+    fn is_mode_abort(&self, key: &Key) -> bool {
+        self.mode.is_some() && Self::is_key(key, &self.mode_abort)
+    }
+
+    /// Switch to the mode with the given name, or leave the active mode for 'default'. If there
+    /// is no such mode, the active mode is left.
+    fn set_mode(&mut self, name: &str) {
+        let mode = match self.config.get_mode(name) {
+            Some(mode) => Some(mode.clone()),
+            None if name == DEFAULT_MODE => None,
+            None => {
+                self.report_error(format!("No mode named '{}'", name));
+                None
+            }
+        };
+        let abort = mode
+            .as_ref()
+            .and_then(|m| m.abort.as_deref())
+            .map(Self::make_abort);
+        self.mode_abort = match abort {
+            Some(Ok(abort)) => abort,
+            Some(Err(e)) => {
+                self.report_error(format!("Invalid abort key for mode '{}': {}", name, e));
+                self.abort.clone()
+            }
+            None => self.abort.clone(),
+        };
+        let changed = self.mode.as_ref().map(|m| &m.name) != mode.as_ref().map(|m| &m.name);
+        self.mode = mode;
+        self.update_grabset();
+        if changed {
+            let name = self
+                .mode
+                .as_ref()
+                .map_or(DEFAULT_MODE.into(), |m| m.name.clone());
+            self.publish(&IpcMessage::ModeChanged(name));
+        }
+    }
+
+    fn run_action(&mut self, action: &Action) {
+        match action {
+            Action::Mode(name) => {
+                self.chain.clear();
+                self.set_mode(name);
+            }
+            Action::Reload => self.requests.reload = true,
+            Action::ToggleGrab => self.requests.toggle_grab = true,
+            // The chain is ended once the action ran
+            Action::Abort => self.chain.clear(),
+            Action::Unbind(chain) => {
+                let unbind = UnbindCommand {
+                    hotkey: chain.to_string(),
+                    mode: None,
+                    command: None,
+                    conditions: vec![],
+                };
+                if let Err(e) = self.unbind(unbind) {
+                    self.report_error(format!("Failed to unbind '{}': {}", chain, e));
+                }
+            }
+            Action::Notify(text) => self.publish(&IpcMessage::Notify(text.clone())),
+            Action::Send(chords) => {
+                let keys: Vec<_> = chords
+                    .iter()
+                    .map(|c| (c.keysym, ModMask::from(c.modfield)))
+                    .collect();
+                self.send_keys(&keys);
+            }
+            Action::Type(text) => {
+                let keys: Vec<_> = text
+                    .chars()
+                    .map(|c| (keyboard::keysym_from_char(c), ModMask::empty()))
+                    .collect();
+                self.send_keys(&keys);
+            }
+        }
+    }
+
+    fn send_keys(&mut self, keys: &[(u32, ModMask)]) {
+        // Nothing is grabbed while the keys are sent, so they reach the focused window instead
+        // of running bindings, which could send them again
+        let grabbed = self.grab;
+        let _ = self.sync();
+        let _ = self.ungrab_all();
+        if let Err(e) = kbd().send_keys(keys) {
+            self.report_error(format!("Failed to send keys: {:#}", e));
+        }
+        if grabbed {
+            self.update_grabset();
+        }
+    }
+
+    /// Take what bindings asked the main loop to do since the last call
+    pub fn take_requests(&mut self) -> Requests {
+        std::mem::take(&mut self.requests)
+    }
+
+    fn mode_name(&self) -> Option<&str> {
+        self.mode.as_ref().map(|m| &*m.name)
+    }
+
+    /// The first chords of the chains of the active mode
+    fn first_chords(&self) -> Vec<Chord> {
+        self.config
+            .index()
+            .root(self.mode_name())
+            .map_or(vec![], |root| {
+                root.children().map(|node| node.chord.clone()).collect()
+            })
+    }
 
     fn end_chain(&mut self) -> Result<()> {
         self.chain.clear();
+        self.count = None;
         self.update_grabset();
         self.publish(&IpcMessage::EndChain);
         Ok(())
@@ -150,28 +353,81 @@ impl HotkeyHandler {
         }
     }
 
-    fn hotkey_matches(hk: &Hotkey, chain: &[ChainItem]) -> bool {
-        for (i, c) in chain.iter().enumerate() {
-            match hk.chain.get(i) {
-                Some(v) if &c.key == v => {
-                    continue;
-                }
-                _ => {
-                    return false;
-                }
-            }
+    /// The nodes of the hotkey index which the keys of `chain` lead to. A key can lead to
+    /// several nodes if its keysyms are bound separately.
+    fn find_nodes(&self, chain: &[ChainItem]) -> Vec<&Node> {
+        let mut nodes: Vec<&Node> = self
+            .config
+            .index()
+            .root(self.mode_name())
+            .into_iter()
+            .collect();
+        for item in chain {
+            let keys = item.key.chord_keys();
+            nodes = nodes
+                .into_iter()
+                .flat_map(|node| keys.iter().filter_map(move |key| node.child(key)))
+                .collect();
         }
-        true
+        nodes
+    }
+
+    /// Whether a hotkey which continues the active chain accepts a count
+    fn accepts_count(&self) -> bool {
+        let hotkeys = self.config.get_hotkeys();
+        self.find_nodes(&self.chain)
+            .into_iter()
+            .flat_map(|node| node.hotkeys.iter())
+            .any(|&i| hotkeys[i].count.is_some() && hotkeys[i].chain.len() > self.chain.len())
     }
 
+    /// The digit `key` adds to the count, unless it is bound as the next chord of the chain
+    fn count_digit(&self, key: &Key) -> Option<u32> {
+        if self.chain.is_empty() || !key.is_press || key.is_button() || key.modfield != 0 {
+            return None;
+        }
+        let keys = key.chord_keys();
+        let bound = self
+            .find_nodes(&self.chain)
+            .into_iter()
+            .any(|node| keys.iter().any(|k| node.child(k).is_some()));
+        if bound || !self.accepts_count() {
+            return None;
+        }
+        keys.iter()
+            .find_map(|k| char::from_u32(k.keysym)?.to_digit(10))
+    }
+
+    /// The hotkeys whose chain starts with `chain`, in the order of the config
     fn find_hotkey(&self, chain: &[ChainItem]) -> Vec<Hotkey> {
-        let mut result = vec![];
-        for hk in self.config.get_hotkeys() {
-            if Self::hotkey_matches(hk, chain) {
-                result.push(hk.clone());
-            }
+        let mut positions: Vec<usize> = self
+            .find_nodes(chain)
+            .into_iter()
+            .flat_map(|node| node.hotkeys.iter().copied())
+            .collect();
+        positions.sort_unstable();
+        positions.dedup();
+        let hotkeys = self.config.get_hotkeys();
+        positions.into_iter().map(|i| hotkeys[i].clone()).collect()
+    }
+
+    /// Drop the hotkeys whose conditions do not hold for the focused window. A hotkey with
+    /// conditions takes precedence over a hotkey with the same chain and no conditions, so the
+    /// latter acts as a fallback.
+    fn applicable(&self, hotkeys: Vec<Hotkey>) -> Vec<Hotkey> {
+        if hotkeys.iter().all(|hk| hk.conditions.is_empty()) {
+            return hotkeys;
         }
-        result.into_iter().collect()
+        let window = keyboard::kbd().focused_window_class();
+        let (conditional, fallback): (Vec<_>, Vec<_>) = hotkeys
+            .into_iter()
+            .filter(|hk| hk.conditions.iter().all(|c| c.matches(window.as_ref())))
+            .partition(|hk| !hk.conditions.is_empty());
+        let fallback: Vec<_> = fallback
+            .into_iter()
+            .filter(|hk| !conditional.iter().any(|c| c.chain == hk.chain))
+            .collect();
+        conditional.into_iter().chain(fallback).collect()
     }
 
     fn align_locks(&mut self, hotkeys: &[Hotkey]) {
@@ -198,7 +454,17 @@ impl HotkeyHandler {
         }
         let last = &chain.chain[self.chain.len() - 1];
         hotkey_string.push_str(&last.repr);
-        self.publish(&IpcMessage::Hotkey(hotkey_string.into()));
+        let mut event = HotkeyEvent {
+            chain: hotkey_string.into(),
+            count: self.count,
+            ..Default::default()
+        };
+        if self.chain.len() == chain.chain.len() {
+            event.tags = chain.tags.clone();
+            event.icon = chain.icon.clone();
+            event.hidden = chain.hidden;
+        }
+        self.publish(&IpcMessage::Hotkey(event));
         Ok(())
     }
 
@@ -226,9 +492,19 @@ impl HotkeyHandler {
             self.end_chain()?;
             return Ok(());
         }
+        if !chained && self.is_mode_abort(&key) {
+            self.set_mode(DEFAULT_MODE);
+            return Ok(());
+        }
 
         if chained && self.is_backspace(&key) {
-            self.chain.pop();
+            // The digits of a count are removed before the chords of the chain
+            match self.count {
+                Some(count) => self.count = Some(count / 10).filter(|c| *c != 0),
+                None => {
+                    self.chain.pop();
+                }
+            }
             if self.chain.is_empty() {
                 self.end_chain()?;
             } else if let Some(hk) = self.find_hotkey(&self.chain).get(0) {
@@ -238,21 +514,47 @@ impl HotkeyHandler {
             return Ok(());
         }
 
+        // Holding the key of a chord which continues the chain does not add it again
+        if key.is_repeat
+            && self
+                .chain
+                .last()
+                .is_some_and(|c| c.key.symbol == key.symbol)
+        {
+            self.sync()?;
+            self.schedule_timeout();
+            return Ok(());
+        }
+
+        if let Some(digit) = self.count_digit(&key) {
+            let count = self.count.unwrap_or(0);
+            self.count = Some((count * 10 + digit).min(MAX_COUNT));
+            self.sync()?;
+            if let Some(hk) = self.find_hotkey(&self.chain).first() {
+                self.publish_hotkey(hk)?;
+            }
+            self.schedule_timeout();
+            return Ok(());
+        }
+
         // Push the current key onto the stack
         self.chain.push(ChainItem {
             key,
             locking: false,
         });
 
-        // Find all hotkeys matching the current chain
+        // Find all hotkeys matching the current chain. Keys which are only bound for other windows
+        // are passed on to the focused window.
         let mut matching = self.find_hotkey(&self.chain);
+        let mut skipped = !matching.is_empty();
+        matching = self.applicable(matching);
         // If there are no matches for the current chain, and it isn't locked, check if another binding starts with this key.
         if chained && !locked && matching.is_empty() {
             let new_chain = ChainItem {
                 key,
                 locking: false,
             };
-            matching = self.find_hotkey(&[new_chain.clone()]);
+            matching = self.applicable(self.find_hotkey(&[new_chain.clone()]));
             // If we started a new chain, we should abort the previous chain
             if !matching.is_empty() {
                 self.end_chain()?;
@@ -261,9 +563,16 @@ impl HotkeyHandler {
             }
         }
 
+        skipped &= matching.is_empty();
+
         if matching.is_empty() {
             self.chain.pop();
-            self.sync()?;
+            if key.is_button() || skipped {
+                // Let unmatched clicks through to the window under the pointer
+                self.replay(&key)?;
+            } else {
+                self.sync()?;
+            }
             self.schedule_timeout();
             return Ok(());
         }
@@ -280,7 +589,9 @@ impl HotkeyHandler {
 
         // We should be nice X citizens and replay / sync as early as possible
         if replay {
-            self.replay()?;
+            self.replay(&key)?;
+        } else if key.is_button() {
+            keyboard::kbd().sync_pointer()?;
         } else {
             self.sync()?;
         }
@@ -297,18 +608,16 @@ impl HotkeyHandler {
             ));
         }
 
-        if let Some(hotkey) = terminals.get(0) {
-            self.publish(&IpcMessage::Command(hotkey.command.clone()));
-            match self.executor.run(hotkey) {
-                Ok(_) => {}
-                Err(e) => {
-                    self.report_error(format!("Error running command {}: {}", hotkey.command, e))
-                }
-            }
-            if hotkey.cycle.is_some() {
-                if let Err(e) = self.config.cycle_hotkey(hotkey) {
-                    self.report_error(format!("Error cycling hotkey: {}", e));
-                }
+        // Of the commands of a cycle, only the next one runs
+        let next = terminals
+            .iter()
+            .position(|t| self.config.cycles().is_next(t))
+            .unwrap_or(0);
+        if let Some(hotkey) = terminals.get(next) {
+            // Held keys only run the command again if the binding allows it. Otherwise the repeat
+            // is swallowed, and the chain moves on as if the command ran.
+            if !key.is_repeat || hotkey.repeat.unwrap_or_default().allows(key.held()) {
+                self.run_hotkey(hotkey, &key);
             }
             let popped = self.pop_non_locking();
             if self.chain.is_empty() && chained {
@@ -329,6 +638,24 @@ impl HotkeyHandler {
         Ok(())
     }
 
+    fn run_hotkey(&mut self, hotkey: &Hotkey, key: &Key) {
+        self.publish(&IpcMessage::Command(hotkey.command.clone()));
+        if let Some(ref action) = hotkey.action {
+            self.run_action(action);
+        } else if let Err(e) = self.executor.run(
+            hotkey,
+            &Trigger {
+                count: self.count,
+                time: key.time,
+            },
+        ) {
+            self.report_error(format!("{:#}", e))
+        }
+        self.config.cycles_mut().advance(hotkey);
+        // A count applies to a single command
+        self.count = None;
+    }
+
     fn pop_non_locking(&mut self) -> bool {
         // Keep popping the chain until a lock is encountered
         let mut popped = false;
@@ -354,18 +681,25 @@ impl HotkeyHandler {
         Ok(())
     }
 
-    pub fn new(cli: CliArguments, config: Config) -> Self {
-        let redir_file = cli.redir_file.clone();
+    pub fn new(cli: CliArguments, mut config: Config) -> Self {
+        let executor = Executor::new(&cli);
+        config
+            .cycles_mut()
+            .set_idle_reset(cli.cycle_reset.map(Duration::from_secs));
         Self {
             cli,
             config,
             chain: vec![],
+            mode: None,
+            mode_abort: Default::default(),
+            count: None,
             abort: Default::default(),
             backspace: Default::default(),
             grab: false,
             fifo: None,
-            executor: Executor::new(redir_file),
+            executor,
             subscribers: RefCell::new(vec![]),
+            requests: Default::default(),
         }
     }
 
@@ -390,6 +724,7 @@ impl HotkeyHandler {
         let escape_keysym = self.cli.abort_keysym.as_deref().unwrap_or("Escape");
 
         self.abort = Self::make_abort(escape_keysym)?;
+        self.mode_abort = self.abort.clone();
         self.backspace = Self::make_abort("Backspace")?;
 
         self.ungrab_all()?;
@@ -399,13 +734,21 @@ impl HotkeyHandler {
     }
 
     fn grab_abort(&self) {
+        let keycodes: Vec<u8> = self
+            .abort
+            .keycodes
+            .iter()
+            .chain(self.backspace.keycodes.iter())
+            .copied()
+            .collect();
+        Self::grab_keycodes(&keycodes, "abort keysym");
+    }
+
+    fn grab_keycodes(keycodes: &[u8], name: &str) {
         keyboard::kbd()
             .grab_many(
-                &self
-                    .abort
-                    .keycodes
+                &keycodes
                     .iter()
-                    .chain(self.backspace.keycodes.iter())
                     .copied()
                     .map(|k| (k, ModMask::from_bits_truncate(0)))
                     .collect::<Vec<_>>(),
@@ -414,10 +757,7 @@ impl HotkeyHandler {
             .enumerate()
             .for_each(|(i, e)| {
                 if let Err(e) = e {
-                    eprintln!(
-                        "Failed to grab abort keysym: {}: {}",
-                        self.abort.keycodes[i], e
-                    );
+                    eprintln!("Failed to grab {}: {}: {}", name, keycodes[i], e);
                 }
             });
     }
@@ -427,19 +767,22 @@ impl HotkeyHandler {
         self.publish(&IpcMessage::Error(error.into()));
     }
 
-    fn grab_index(hotkeys: &[Hotkey], index: usize) {
+    fn grab_chords(chords: &[Chord]) {
         let kbd = keyboard::kbd();
         let mut chain_lookup = vec![];
+        let mut button_lookup = vec![];
+        let mut button_set = vec![];
 
         // Generate a vector of everything we want to grab so it can be used in a batching
         // operation. I measured this to be ~15 times faster than doing every request sequentially
-        // TODO: Update cycle implementation in hotkey struct to avoid attempting to grab each cycle
-        let grab_set: Vec<_> = hotkeys
+        let grab_set: Vec<_> = chords
             .iter()
-            .flat_map(|a| {
-                let Some(chain) = a.chain.get(index) else {
+            .flat_map(|chain| {
+                if chain.button != 0 {
+                    button_lookup.push(chain);
+                    button_set.push((chain.button, xcb::x::ModMask::from(chain.modfield)));
                     return vec![];
-                };
+                }
                 kbd.get_keycodes(chain.keysym)
                     .unwrap_or(vec![])
                     .iter()
@@ -451,7 +794,62 @@ impl HotkeyHandler {
                     .collect::<Vec<_>>()
             })
             .collect();
-        kbd.grab_many(&grab_set)
+        Self::report_grab_errors(kbd.grab_many(&grab_set), &chain_lookup);
+        Self::report_grab_errors(kbd.grab_buttons(&button_set), &button_lookup);
+    }
+
+    // The keys and buttons which are grabbed for `chords`
+    fn first_grabs(chords: &[Chord]) -> HashMap<Grab, Arc<str>> {
+        let kbd = keyboard::kbd();
+        let mut grabs = HashMap::new();
+        for chord in chords {
+            let modfield = chord.modfield.bits();
+            if chord.button != 0 {
+                grabs.insert(Grab::Button(chord.button, modfield), chord.repr.clone());
+                continue;
+            }
+            for keycode in kbd.get_keycodes(chord.keysym).unwrap_or_default() {
+                grabs.insert(Grab::Key(keycode, modfield), chord.repr.clone());
+            }
+        }
+        grabs
+    }
+
+    /// Update the grabs after the first chords changed from `old`, without releasing the keys
+    /// which stay bound. Only valid while no chain is active.
+    fn regrab(&mut self, old: &[Chord]) {
+        let kbd = keyboard::kbd();
+        let _ = self.sync();
+        let old = Self::first_grabs(old);
+        let new = Self::first_grabs(&self.first_chords());
+        for grab in old.keys().filter(|g| !new.contains_key(g)) {
+            let result = match *grab {
+                Grab::Key(key, modfield) => kbd.ungrab(key, ModMask::from_bits_truncate(modfield)),
+                Grab::Button(button, modfield) => {
+                    kbd.ungrab_button(button, ModMask::from_bits_truncate(modfield))
+                }
+            };
+            if let Err(e) = result {
+                eprintln!("Failed to ungrab '{}': {}", old[grab], e);
+            }
+        }
+        for (grab, repr) in new.iter().filter(|(g, _)| !old.contains_key(g)) {
+            let result = match *grab {
+                Grab::Key(key, modfield) => {
+                    kbd.grab_many(&[(key, ModMask::from_bits_truncate(modfield))])
+                }
+                Grab::Button(button, modfield) => {
+                    kbd.grab_buttons(&[(button, ModMask::from_bits_truncate(modfield))])
+                }
+            };
+            if let Some(Err(e)) = result.into_iter().next() {
+                eprintln!("'{}' could not be grabbed: {}", repr, e);
+            }
+        }
+    }
+
+    fn report_grab_errors(results: Vec<xcb::ProtocolResult<()>>, lookup: &[&Chord]) {
+        results
             .into_iter()
             .enumerate()
             .filter_map(|(i, e)| Some((i, e.err()?)))
@@ -460,18 +858,19 @@ impl HotkeyHandler {
                     xcb::x::Error::Access(_) => {
                         eprintln!(
                             "'{}' could not be grabbed. Is it grabbed by another program?",
-                            chain_lookup[i].repr
+                            lookup[i].repr
                         );
                     }
                     _ => {
                         eprintln!("Unhandled error during grab: {}", e);
                     }
                 },
+                _ => eprintln!("Unhandled error during grab: {}", e),
             });
     }
 
     fn grab_index_0(&mut self) -> Result<()> {
-        Self::grab_index(self.config.get_hotkeys(), 0);
+        Self::grab_chords(&self.first_chords());
         self.grab = true;
         Ok(())
     }
@@ -486,8 +885,12 @@ impl HotkeyHandler {
         };
         Fifo::new(status_fifo)
     }
-    fn replay(&self) -> Result<()> {
-        keyboard::kbd().replay_keyboard()?;
+    fn replay(&self, key: &Key) -> Result<()> {
+        if key.is_button() {
+            keyboard::kbd().replay_pointer()?;
+        } else {
+            keyboard::kbd().replay_keyboard()?;
+        }
         Ok(())
     }
     fn sync(&self) -> Result<()> {
@@ -506,30 +909,170 @@ impl HotkeyHandler {
         let _ = self.sync();
         let _ = self.ungrab_all();
         if !self.chain_locked() {
-            Self::grab_index(self.config.get_hotkeys(), 0);
+            Self::grab_chords(&self.first_chords());
+        }
+        if self.mode.is_some() && self.chain.is_empty() {
+            Self::grab_keycodes(&self.mode_abort.keycodes, "mode abort keysym");
         }
         if !self.chain.is_empty() {
             self.grab_abort();
-            let matching = self.find_hotkey(&self.chain);
-            Self::grab_index(&matching, self.chain.len());
+            let mut next: Vec<Chord> = self
+                .find_nodes(&self.chain)
+                .into_iter()
+                .flat_map(|node| node.children().map(|child| child.chord.clone()))
+                .collect();
+            if self.accepts_count() {
+                next.extend(digit_chords());
+            }
+            Self::grab_chords(&next);
         }
         self.grab = true;
     }
 
     pub fn delete_bindings(&mut self, mut client: UnixStream, unbind: UnbindCommand) {
-        match self.config.delete_bindings(&unbind) {
-            Ok(removed) => {
-                if !removed.is_empty() {
-                    self.update_grabset();
-                }
-                self.publish(&IpcMessage::BindingRemoved(unbind.clone()));
-            }
+        if let Err(e) = self.unbind(unbind) {
+            let _ = write!(client, "Failed to parse input: {}", e);
+        }
+    }
+
+    fn unbind(&mut self, unbind: UnbindCommand) -> Result<()> {
+        let removed = self.config.delete_bindings(&unbind)?;
+        if !removed.is_empty() {
+            self.update_grabset();
+        }
+        self.publish(&IpcMessage::BindingRemoved(unbind));
+        Ok(())
+    }
+
+    pub fn dump(&self, mut client: UnixStream) {
+        let _ = client.write_all(self.config.to_rhkdrc().as_bytes());
+    }
+
+    /// List the command which runs next for each matching cycle, after resetting them if asked
+    pub fn cycles(&mut self, mut client: UnixStream, command: CyclesCommand) {
+        let found = self
+            .config
+            .find_cycles(command.hotkey.as_deref(), command.mode.as_deref());
+        let found: Vec<Hotkey> = match found {
+            Ok(found) => found.into_iter().cloned().collect(),
             Err(e) => {
-                let _ = write!(client, "Failed to parse input: {}", e);
+                let _ = writeln!(client, "Failed to parse input: {}", e);
+                return;
+            }
+        };
+        if command.reset {
+            for hotkey in found.iter() {
+                self.config.cycles_mut().reset(hotkey);
+            }
+        }
+        let cycles = self.config.cycles();
+        for hotkey in found.iter().filter(|hk| cycles.is_next(hk)) {
+            let Some(ref cycle) = hotkey.cycle else {
+                continue;
+            };
+            let mode = hotkey
+                .mode
+                .as_ref()
+                .map_or(String::new(), |m| format!("[{}] ", m));
+            let _ = writeln!(
+                client,
+                "{}{}  {}/{}  {}",
+                mode,
+                hotkey.chain_repr(),
+                cycle.delay + 1,
+                cycle.period,
+                hotkey.command
+            );
+        }
+    }
+
+    pub fn children(&self) -> &Children {
+        self.executor.children()
+    }
+
+    /// Kill the commands which timed out, reap the commands which have exited, and tell
+    /// subscribers about them. Queued sync commands are started once the previous one exits.
+    pub fn reap_children(&mut self) {
+        let killed: Vec<String> = self
+            .executor
+            .children_mut()
+            .kill_expired()
+            .iter()
+            .map(|running| format!("{} ({})", running.command, running.chain))
+            .collect();
+        for command in killed {
+            self.report_error(format!("Killed command after its timeout: {}", command));
+        }
+        for exit in self.executor.reap() {
+            self.publish(&IpcMessage::CommandExited(exit));
+        }
+        if let Err(e) = self.executor.run_queued() {
+            self.report_error(format!("{:#}", e));
+        }
+    }
+
+    /// List the commands started by hotkeys which are still running
+    pub fn running(&self, mut client: UnixStream) {
+        for running in self.children().running() {
+            let _ = writeln!(
+                client,
+                "{}  {:.1}s  {}  {}",
+                running.pid,
+                running.started.elapsed().as_secs_f32(),
+                running.chain,
+                running.command
+            );
+        }
+    }
+
+    /// Write the end of the output of the last failed command, or of every failed command which
+    /// is remembered, to the client
+    pub fn output(&self, mut client: UnixStream, command: OutputCommand) {
+        if !self.children().captures_output() {
+            let _ = writeln!(
+                client,
+                "Output is only kept when rhkd is started with --log-dir"
+            );
+            return;
+        }
+        let chords = match command.hotkey {
+            Some(ref hotkey) => match parse_chord_chain(hotkey, self.config.keymap()) {
+                Ok(chords) => chords,
+                Err(e) => {
+                    let _ = writeln!(client, "Failed to parse input: {}", e);
+                    return;
+                }
+            },
+            None => vec![],
+        };
+        let failures = self.children().failures().iter().rev().filter(|failure| {
+            failure.chain.len() >= chords.len()
+                && chords
+                    .iter()
+                    .zip(failure.chain.iter())
+                    .all(|(a, b)| a.eq_relaxed(b))
+        });
+        let count = if command.all { usize::MAX } else { 1 };
+        for failure in failures.take(count) {
+            let exit = &failure.exit;
+            let _ = writeln!(
+                client,
+                "{}  {}  {}  {}",
+                exit.pid,
+                exit.status,
+                chain_repr(&failure.chain),
+                exit.command
+            );
+            for line in failure.output.iter() {
+                let _ = writeln!(client, "  {}", line);
             }
         }
     }
 
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
     pub fn clone_hotkeys(&self) -> Vec<Hotkey> {
         self.config.get_hotkeys().clone()
     }
@@ -550,6 +1093,9 @@ impl HotkeyHandler {
                                 current, new
                             );
                         }
+                        AddBindingError::Invalid(diagnostic) => {
+                            let _ = write!(client, "{}", diagnostic);
+                        }
                     }
                 }
             }
@@ -559,3 +1105,14 @@ impl HotkeyHandler {
         }
     }
 }
+
+/// The digit keys, which are grabbed while a count can be typed
+fn digit_chords() -> Vec<Chord> {
+    ('0'..='9')
+        .map(|digit| Chord {
+            repr: digit.to_string().into(),
+            keysym: digit as u32,
+            ..Default::default()
+        })
+        .collect()
+}
@@ -1,4 +1,7 @@
-use crate::parser::{config::Config, Chord};
+use crate::parser::diagnostic::{print_diagnostics, Diagnostic, DiagnosticFormat, Severity};
+use crate::parser::{
+    chain_repr, config::Config, index::ChordKey, Chord, Mode, Prefix, DEFAULT_MODE,
+};
 use crate::rhkc::ipc::{self, BindCommand, IpcCommand, TryFromReader, UnbindCommand};
 use crate::CliArguments;
 use std::time::Duration;
@@ -6,6 +9,7 @@ use std::time::Duration;
 use super::keyboard;
 use super::parser::config;
 use nix::sys::select::FdSet;
+use nix::sys::time::{TimeVal, TimeValLike};
 use thiserror::Error;
 use xcb::x::Event;
 
@@ -16,10 +20,16 @@ use std::os::unix::net::UnixStream;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
+pub mod children;
 mod executor;
 mod fifo;
 pub mod hotkey_handler;
+pub mod output;
+mod watcher;
+use children::{Exit, ExitCode};
+pub use executor::split_words;
 use hotkey_handler::*;
+use watcher::ConfigWatcher;
 
 #[derive(Debug)]
 pub enum IpcMessage {
@@ -28,11 +38,24 @@ pub enum IpcMessage {
     BeginChain,
     EndChain,
     Timeout,
-    Hotkey(Arc<str>),
+    /// The active chain
+    Hotkey(HotkeyEvent),
     Command(Arc<str>),
     Error(Arc<str>),
     BindingRemoved(UnbindCommand),
     BindingAdded(BindCommand),
+    /// The name of the mode which became active, or 'default' when a mode is left
+    ModeChanged(Arc<str>),
+    /// A command started by a hotkey has exited
+    CommandExited(Exit),
+    /// A reload removed a prefix title
+    PrefixRemoved(PrefixEvent),
+    /// A reload added a prefix title
+    PrefixAdded(PrefixEvent),
+    /// A reload removed a mode, or changed its abort keysym
+    ModeRemoved(ModeEvent),
+    /// A reload added a mode, or changed its abort keysym
+    ModeAdded(ModeEvent),
 }
 
 #[derive(Error, Debug)]
@@ -56,9 +79,26 @@ impl TryFrom<String> for IpcMessage {
             'E' => IpcMessage::EndChain,
             'T' => IpcMessage::Timeout,
             'C' => IpcMessage::Command(value.into()),
-            'H' => IpcMessage::Hotkey(value.into()),
+            'H' => IpcMessage::Hotkey(HotkeyEvent::parse(&value)),
             'R' => IpcMessage::ConfigReloaded,
             'N' => IpcMessage::Notify(value.into()),
+            'M' => IpcMessage::ModeChanged(value.into()),
+            'X' => {
+                let mut fields = value.splitn(4, '\t');
+                let mut next = || fields.next().unwrap_or_default();
+                let (pid, status, duration, command) = (next(), next(), next(), next());
+                let exit = Exit {
+                    pid: pid.parse().unwrap_or(0),
+                    status: ExitCode::parse(status).unwrap_or(ExitCode::Code(0)),
+                    duration: Duration::from_millis(duration.parse().unwrap_or(0)),
+                    command: command.into(),
+                };
+                IpcMessage::CommandExited(exit)
+            }
+            'P' => IpcMessage::PrefixAdded(PrefixEvent::parse(&value)),
+            'Q' => IpcMessage::PrefixRemoved(PrefixEvent::parse(&value)),
+            'O' => IpcMessage::ModeAdded(ModeEvent::parse(&value)),
+            'K' => IpcMessage::ModeRemoved(ModeEvent::parse(&value)),
             '?' => IpcMessage::Error(value.into()),
             _ => return Err(IpcMessageParseError::UnknownPrefix(start)),
         };
@@ -66,6 +106,134 @@ impl TryFrom<String> for IpcMessage {
     }
 }
 
+/// The active chain, and the count typed inside it, if any. When the chain is a complete
+/// binding, the annotations of the binding are included.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HotkeyEvent {
+    pub chain: Arc<str>,
+    pub count: Option<u32>,
+    pub tags: Arc<[Arc<str>]>,
+    pub icon: Option<Arc<str>>,
+    pub hidden: bool,
+}
+
+impl HotkeyEvent {
+    /// Parse the fields which follow the chain after tabs, e.g. 'super + a\t3\ttags=media'
+    pub fn parse(message: &str) -> HotkeyEvent {
+        let mut fields = message.split('\t');
+        let mut event = HotkeyEvent {
+            chain: fields.next().unwrap_or_default().into(),
+            ..Default::default()
+        };
+        for field in fields {
+            if let Ok(count) = field.parse() {
+                event.count = Some(count);
+            } else if let Some(tags) = field.strip_prefix("tags=") {
+                event.tags = tags.split(',').map(|t| t.into()).collect();
+            } else if let Some(icon) = field.strip_prefix("icon=") {
+                event.icon = Some(icon.into());
+            } else if field == "hidden" {
+                event.hidden = true;
+            }
+        }
+        event
+    }
+}
+
+impl Display for HotkeyEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.chain)?;
+        if let Some(count) = self.count {
+            write!(f, "\t{}", count)?;
+        }
+        if !self.tags.is_empty() {
+            write!(f, "\ttags={}", self.tags.join(","))?;
+        }
+        if let Some(ref icon) = self.icon {
+            write!(f, "\ticon={}", icon)?;
+        }
+        if self.hidden {
+            write!(f, "\thidden")?;
+        }
+        Ok(())
+    }
+}
+
+/// The title of a prefix, sent when a reload adds or removes it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PrefixEvent {
+    /// The mode of the prefix, or 'default' for prefixes outside of any mode
+    pub mode: Arc<str>,
+    pub chain: Arc<str>,
+    pub title: Arc<str>,
+}
+
+impl PrefixEvent {
+    /// Parse the mode, chain and title separated by tabs, e.g. 'default\tsuper + b\tBrightness'
+    pub fn parse(message: &str) -> PrefixEvent {
+        let mut fields = message.splitn(3, '\t');
+        let mut next = || fields.next().unwrap_or_default().into();
+        PrefixEvent {
+            mode: next(),
+            chain: next(),
+            title: next(),
+        }
+    }
+}
+
+impl From<&Prefix> for PrefixEvent {
+    fn from(prefix: &Prefix) -> Self {
+        PrefixEvent {
+            mode: prefix.mode.clone().unwrap_or_else(|| DEFAULT_MODE.into()),
+            chain: prefix.chain_repr().into(),
+            title: prefix.title.clone(),
+        }
+    }
+}
+
+impl Display for PrefixEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}\t{}\t{}", self.mode, self.chain, self.title)
+    }
+}
+
+/// A mode and its abort keysym, sent when a reload adds or removes it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModeEvent {
+    pub name: Arc<str>,
+    pub abort: Option<Arc<str>>,
+}
+
+impl ModeEvent {
+    /// Parse the name and the abort keysym separated by a tab, e.g. 'resize\tEscape'
+    pub fn parse(message: &str) -> ModeEvent {
+        let mut fields = message.splitn(2, '\t');
+        ModeEvent {
+            name: fields.next().unwrap_or_default().into(),
+            abort: fields.next().map(|a| a.into()),
+        }
+    }
+}
+
+impl From<&Mode> for ModeEvent {
+    fn from(mode: &Mode) -> Self {
+        ModeEvent {
+            name: mode.name.clone(),
+            abort: mode.abort.clone(),
+        }
+    }
+}
+
+impl Display for ModeEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(ref abort) = self.abort {
+            write!(f, "\t{}", abort)?;
+        }
+        Ok(())
+    }
+}
+
 impl Display for IpcMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -79,6 +247,19 @@ impl Display for IpcMessage {
             IpcMessage::Error(e) => write!(f, "?{}", e),
             IpcMessage::BindingRemoved(r) => write!(f, "D{}", r.hotkey),
             IpcMessage::BindingAdded(a) => write!(f, "A{}", a.hotkey),
+            IpcMessage::ModeChanged(m) => write!(f, "M{}", m),
+            IpcMessage::CommandExited(e) => write!(
+                f,
+                "X{}\t{}\t{}\t{}",
+                e.pid,
+                e.status,
+                e.duration.as_millis(),
+                e.command
+            ),
+            IpcMessage::PrefixAdded(p) => write!(f, "P{}", p),
+            IpcMessage::PrefixRemoved(p) => write!(f, "Q{}", p),
+            IpcMessage::ModeAdded(m) => write!(f, "O{}", m),
+            IpcMessage::ModeRemoved(m) => write!(f, "K{}", m),
         }
     }
 }
@@ -95,6 +276,64 @@ lazy_static! {
     static ref LOCK_MASK: u32 = !get_lockfields() & 255;
 }
 
+/// The longest time between the presses of a held key. X waits 660 ms before it starts
+/// repeating by default.
+const MAX_REPEAT_GAP: u32 = 1000;
+
+/// The largest count which can be typed in a chain. Further digits keep it at this value, so a
+/// held digit key cannot make a repeated command run for hours.
+const MAX_COUNT: u32 = 999;
+
+/// The last key which was pressed. Only that key is repeated by X while it is held.
+#[derive(Clone, Copy)]
+struct Held {
+    symbol: u8,
+    /// The time of the press which was not a repeat
+    pressed: u32,
+    /// The time of the last press
+    last: u32,
+    released: Option<u32>,
+}
+
+/// Tells the presses X sends while a key is held apart from fresh presses. With detectable
+/// autorepeat they are presses without a release in between. Otherwise each repeat is a release
+/// followed by a press at the same time. The release of a key can be missed while the keyboard is
+/// not grabbed, so presses long after the last one are never repeats.
+#[derive(Default)]
+struct AutoRepeat {
+    held: Option<Held>,
+}
+
+impl AutoRepeat {
+    fn mark(&mut self, mut key: Key) -> Key {
+        if key.is_button() {
+            return key;
+        }
+        if !key.is_press {
+            if let Some(held) = self.held.as_mut().filter(|h| h.symbol == key.symbol) {
+                held.released = Some(key.time);
+            }
+            return key;
+        }
+        let repeat = self.held.filter(|h| {
+            h.symbol == key.symbol
+                && h.released.is_none_or(|time| time == key.time)
+                && key.time.wrapping_sub(h.last) <= MAX_REPEAT_GAP
+        });
+        if let Some(held) = repeat {
+            key.is_repeat = true;
+            key.pressed = held.pressed;
+        }
+        self.held = Some(Held {
+            symbol: key.symbol,
+            pressed: key.pressed,
+            last: key.time,
+            released: None,
+        });
+        key
+    }
+}
+
 fn as_key(event: &xcb::Event) -> Option<Key> {
     if let Ok(mut key) = Key::try_from(event) {
         key.modfield &= *LOCK_MASK;
@@ -104,9 +343,71 @@ fn as_key(event: &xcb::Event) -> Option<Key> {
     }
 }
 
+/// Load the configuration and report every error, without grabbing any keys. Returns `true` if
+/// the configuration is free of errors.
+pub fn check(settings: &CliArguments) -> bool {
+    let format = settings.diagnostic_format;
+    let cfg = match config::load_config(settings.config_path.as_deref(), keyboard::keymap()) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            print_diagnostics(&[Diagnostic::from_fatal(&e)], format);
+            return false;
+        }
+    };
+    if cfg.files().is_empty() {
+        let missing = Diagnostic::new(Severity::Error, "No config file found".into());
+        print_diagnostics(&[missing], format);
+        return false;
+    }
+
+    let mut diagnostics = cfg.diagnostics().to_vec();
+    diagnostics.extend(cfg.analyze().iter().map(|c| c.to_diagnostic()));
+    print_diagnostics(&diagnostics, format);
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count();
+    if format == DiagnosticFormat::Human {
+        let hotkeys = cfg.get_hotkeys();
+        // Hotkeys in a mode are prefixed with the name of the mode
+        let chain = |mode: &Option<Arc<str>>, chain: &[Chord]| match mode {
+            Some(ref mode) => format!("[{}] {}", mode, chain_repr(chain)),
+            None => chain_repr(chain),
+        };
+        let prefixes = cfg.prefixes();
+        let width = hotkeys
+            .iter()
+            .map(|hk| chain(&hk.mode, &hk.chain).len())
+            .chain(prefixes.iter().map(|p| chain(&p.mode, &p.chain).len()))
+            .max()
+            .unwrap_or(0);
+        // Prefix titles are listed like the comments they are written as
+        for prefix in prefixes {
+            let repr = chain(&prefix.mode, &prefix.chain);
+            println!("{:width$}  # {}", repr, prefix.title);
+        }
+        for hotkey in hotkeys {
+            let repr = chain(&hotkey.mode, &hotkey.chain);
+            println!("{:width$}  {}", repr, hotkey.command);
+        }
+        println!(
+            "{} hotkeys in {} files, {} errors, {} warnings",
+            hotkeys.len(),
+            cfg.files().len(),
+            errors,
+            diagnostics.len() - errors
+        );
+    }
+    errors == 0
+}
+
 pub fn start(settings: CliArguments) -> Result<()> {
     let mut hotkey_handler = {
-        let cfg = config::load_config(settings.config_path.as_deref())?;
+        let cfg = config::load_config(settings.config_path.as_deref(), keyboard::keymap())
+            .inspect_err(|e| {
+                print_diagnostics(&[Diagnostic::from_fatal(e)], settings.diagnostic_format)
+            })?;
+        print_diagnostics(cfg.diagnostics(), settings.diagnostic_format);
         HotkeyHandler::new(settings, cfg)
     };
     hotkey_handler.setup()?;
@@ -131,18 +432,50 @@ pub fn start(settings: CliArguments) -> Result<()> {
     signal_hook::flag::register(SIGALRM, Arc::clone(&timeout))?;
     signal_hook::flag::register(SIGINT, Arc::clone(&terminate))?;
     signal_hook::flag::register(SIGTERM, Arc::clone(&terminate))?;
+    // The config is reloaded when one of its files changes
+    let mut watcher = match ConfigWatcher::new() {
+        Ok(mut watcher) => {
+            watcher.watch(hotkey_handler.config());
+            Some(watcher)
+        }
+        Err(e) => {
+            eprintln!("Config files are not watched for changes: {}", e);
+            None
+        }
+    };
+
     let kbd = keyboard::kbd();
+    let mut autorepeat = AutoRepeat::default();
     loop {
         let mut fd_list = FdSet::new();
         fd_list.insert(&keyboard_fd);
         fd_list.insert(&socket_fd);
-        match nix::sys::select::select(None, &mut fd_list, None, None, None) {
+        if let Some(ref watcher) = watcher {
+            fd_list.insert(watcher);
+        }
+        let child_fds = hotkey_handler.children().fds();
+        for fd in child_fds.iter() {
+            fd_list.insert(fd);
+        }
+        // Wake up when a pending reload is due, or a command times out
+        let mut wakeup = watcher
+            .as_ref()
+            .and_then(|w| w.pending())
+            .into_iter()
+            .chain(hotkey_handler.children().next_deadline())
+            .min()
+            .map(|left| TimeVal::microseconds(left.as_micros() as i64));
+        match nix::sys::select::select(None, &mut fd_list, None, None, wakeup.as_mut()) {
             // Select returned because one of the fd's are ready for reading
             Ok(_) => {
                 // Handle all pending keyboard events
                 while let Some(evt) = kbd.poll_event()? {
                     if let Some(key) = as_key(&evt) {
+                        let key = autorepeat.mark(key);
                         hotkey_handler.handle_key(key)?;
+                        if key.is_button() {
+                            kbd.sync_pointer()?;
+                        }
                     }
                     kbd.sync_keyboard()?;
                 }
@@ -169,6 +502,10 @@ pub fn start(settings: CliArguments) -> Result<()> {
                             IpcCommand::Subscribe(subscribe) => {
                                 hotkey_handler.add_subscriber(client, subscribe.events)
                             }
+                            IpcCommand::Dump => hotkey_handler.dump(client),
+                            IpcCommand::Cycles(cycles) => hotkey_handler.cycles(client, cycles),
+                            IpcCommand::Running => hotkey_handler.running(client),
+                            IpcCommand::Output(output) => hotkey_handler.output(client, output),
                         },
                         Err(e) => eprintln!("Failed to parse command: {}", e),
                     }
@@ -185,12 +522,33 @@ pub fn start(settings: CliArguments) -> Result<()> {
                 }
                 if reload_config.swap(false, Ordering::Relaxed) {
                     hotkey_handler.reload()?;
+                    if let Some(ref mut watcher) = watcher {
+                        watcher.watch(hotkey_handler.config());
+                    }
                 }
                 if toggle_grab.swap(false, Ordering::Relaxed) {
                     hotkey_handler.toggle_grab()?;
                 }
             }
         }
+
+        hotkey_handler.reap_children();
+
+        let requests = hotkey_handler.take_requests();
+        if requests.toggle_grab {
+            hotkey_handler.toggle_grab()?;
+        }
+        let mut reload = requests.reload;
+        if let Some(ref mut watcher) = watcher {
+            watcher.read_events();
+            reload |= watcher.take_reload();
+        }
+        if reload {
+            hotkey_handler.reload()?;
+            if let Some(ref mut watcher) = watcher {
+                watcher.watch(hotkey_handler.config());
+            }
+        }
     }
 }
 
@@ -200,6 +558,45 @@ pub struct Key {
     #[allow(unused)]
     modfield: u32,
     is_press: bool,
+    /// The pointer button of a button event, or 0 for key events
+    button: u8,
+    /// The X server time of the event, in milliseconds
+    time: u32,
+    /// Set for the presses X sends while the key is held
+    is_repeat: bool,
+    /// The time the key was pressed, which is earlier than `time` for repeats
+    pressed: u32,
+}
+
+impl Key {
+    /// Returns `true` if this is a pointer button event.
+    #[must_use]
+    pub fn is_button(&self) -> bool {
+        self.button != 0
+    }
+
+    /// How long the key was held before this event
+    pub fn held(&self) -> Duration {
+        Duration::from_millis(self.time.wrapping_sub(self.pressed) as u64)
+    }
+
+    /// The chords this event can match, one for each keysym of the key
+    pub fn chord_keys(&self) -> Vec<ChordKey> {
+        let keysyms = if self.is_button() {
+            &[0][..]
+        } else {
+            keyboard::kbd().keysyms_from_keycode(self.symbol)
+        };
+        keysyms
+            .iter()
+            .map(|&keysym| ChordKey {
+                keysym,
+                button: self.button,
+                modfield: self.modfield,
+                is_press: self.is_press,
+            })
+            .collect()
+    }
 }
 
 impl Display for Key {
@@ -209,19 +606,11 @@ impl Display for Key {
         if !self.is_press {
             f.write_str("@")?;
         }
-        f.write_str(&format!("{}", self.symbol))
-    }
-}
-
-impl PartialEq<Chord> for Key {
-    fn eq(&self, other: &Chord) -> bool {
-        self.modfield == other.modfield.bits()
-            && self.is_press == other.event_type.is_key_press()
-            && {
-                keyboard::kbd()
-                    .get_keycodes(other.keysym)
-                    .is_some_and(|i| i.contains(&self.symbol))
-            }
+        if self.is_button() {
+            f.write_str(&format!("button{}", self.button))
+        } else {
+            f.write_str(&format!("{}", self.symbol))
+        }
     }
 }
 
@@ -242,15 +631,104 @@ impl TryFrom<&xcb::Event> for Key {
                     symbol: x.detail(),
                     modfield: x.state().bits(),
                     is_press: true,
+                    button: 0,
+                    time: x.time(),
+                    is_repeat: false,
+                    pressed: x.time(),
                 }),
                 Event::KeyRelease(x) => Ok(Key {
                     symbol: x.detail(),
                     modfield: x.state().bits(),
                     is_press: false,
+                    button: 0,
+                    time: x.time(),
+                    is_repeat: false,
+                    pressed: x.time(),
+                }),
+                Event::ButtonPress(x) => Ok(Key {
+                    symbol: 0,
+                    modfield: x.state().bits(),
+                    is_press: true,
+                    button: x.detail(),
+                    time: x.time(),
+                    is_repeat: false,
+                    pressed: x.time(),
+                }),
+                Event::ButtonRelease(x) => Ok(Key {
+                    symbol: 0,
+                    modfield: x.state().bits(),
+                    is_press: false,
+                    button: x.detail(),
+                    time: x.time(),
+                    is_repeat: false,
+                    pressed: x.time(),
                 }),
                 _ => bail!("Not a key event"),
             },
-            xcb::Event::Unknown(_) => Err(anyhow!("Not a key event")),
+            _ => Err(anyhow!("Not a key event")),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hotkey_event() {
+        let event = HotkeyEvent {
+            chain: "super + w ; l".into(),
+            count: Some(3),
+            tags: vec!["media".into(), "audio".into()].into(),
+            icon: Some("󰕾".into()),
+            hidden: true,
+        };
+        let message = IpcMessage::Hotkey(event.clone()).to_string();
+        assert_eq!(
+            "Hsuper + w ; l\t3\ttags=media,audio\ticon=󰕾\thidden",
+            message
+        );
+        match IpcMessage::try_from(message) {
+            Ok(IpcMessage::Hotkey(parsed)) => assert_eq!(event, parsed),
+            other => panic!("Expected a hotkey event, got {:?}", other),
+        }
+
+        let event = HotkeyEvent::parse("super + w");
+        assert_eq!("super + w", &*event.chain);
+        assert_eq!(None, event.count);
+        assert!(event.tags.is_empty());
+    }
+
+    #[test]
+    fn test_prefix_event() {
+        let event = PrefixEvent {
+            mode: "default".into(),
+            chain: "super + b".into(),
+            title: "Brightness\tand contrast".into(),
+        };
+        let message = IpcMessage::PrefixRemoved(event.clone()).to_string();
+        assert_eq!("Qdefault\tsuper + b\tBrightness\tand contrast", message);
+        match IpcMessage::try_from(message) {
+            Ok(IpcMessage::PrefixRemoved(parsed)) => assert_eq!(event, parsed),
+            other => panic!("Expected a prefix event, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_mode_event() {
+        let event = ModeEvent {
+            name: "resize".into(),
+            abort: Some("Escape".into()),
+        };
+        let message = IpcMessage::ModeAdded(event.clone()).to_string();
+        assert_eq!("Oresize\tEscape", message);
+        match IpcMessage::try_from(message) {
+            Ok(IpcMessage::ModeAdded(parsed)) => assert_eq!(event, parsed),
+            other => panic!("Expected a mode event, got {:?}", other),
+        }
+
+        let event = ModeEvent::parse("launch");
+        assert_eq!("launch", &*event.name);
+        assert_eq!(None, event.abort);
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+
+const LOG_NAME: &str = "commands.log";
+/// How many rotated logs are kept next to the current one, as commands.log.1 and so on
+const ROTATED_LOGS: usize = 4;
+/// Output which goes on for this many bytes without a newline is logged as a line of its own,
+/// so a command cannot fill up the memory of rhkd
+const MAX_LINE: usize = 4096;
+
+/// The stream a command wrote a line to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Display for Stream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stream::Stdout => write!(f, "stdout"),
+            Stream::Stderr => write!(f, "stderr"),
+        }
+    }
+}
+
+/// The output of every command, in a directory of logs. Once the log grows past its size it is
+/// moved aside, and the oldest log is removed.
+pub struct CommandLog {
+    dir: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+impl CommandLog {
+    pub fn open(dir: &Path, max_size: u64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(dir.join(LOG_NAME))?;
+        let size = file.metadata()?.len();
+        Ok(CommandLog {
+            dir: dir.to_path_buf(),
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    /// Write a line of a command, tagged with the time, the pid and the chain which started it
+    pub fn write(&mut self, pid: u32, chain: &str, stream: Stream, line: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!(
+            "{}.{:03} {} [{}] {}: {}\n",
+            now.as_secs(),
+            now.subsec_millis(),
+            pid,
+            chain,
+            stream,
+            line
+        );
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            if let Err(e) = self.rotate() {
+                eprintln!("Failed to rotate the command log: {}", e);
+            }
+        }
+        match self.file.write_all(line.as_bytes()) {
+            Ok(()) => self.size += line.len() as u64,
+            Err(e) => eprintln!("Failed to write to the command log: {}", e),
+        }
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let path = |i: usize| match i {
+            0 => self.dir.join(LOG_NAME),
+            i => self.dir.join(format!("{}.{}", LOG_NAME, i)),
+        };
+        for i in (0..ROTATED_LOGS).rev() {
+            match std::fs::rename(path(i), path(i + 1)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        self.file = OpenOptions::new().append(true).create(true).open(path(0))?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+struct Pipe {
+    stream: Stream,
+    file: File,
+    /// The end of the output which is not a whole line yet
+    partial: Vec<u8>,
+}
+
+impl Pipe {
+    // Move the whole lines, and the pieces of lines which are too long, out of `partial`
+    fn take_lines(&mut self, lines: &mut Vec<(Stream, Vec<u8>)>) {
+        loop {
+            let newline = self.partial.iter().take(MAX_LINE).position(|b| *b == b'\n');
+            let line = match newline {
+                Some(end) => {
+                    let line: Vec<u8> = self.partial.drain(..=end).collect();
+                    line[..end].to_vec()
+                }
+                None if self.partial.len() >= MAX_LINE => self.partial.drain(..MAX_LINE).collect(),
+                None => break,
+            };
+            lines.push((self.stream, line));
+        }
+    }
+}
+
+/// The output of a command, which is read without blocking until the command closes its pipes.
+/// Commands which start something in the background pass their pipes on to it, so the output is
+/// read for as long as the pipes are open, even after the command exited.
+pub struct Capture {
+    pub pid: u32,
+    chain: String,
+    pipes: Vec<Pipe>,
+    /// The last lines of the output
+    tail: VecDeque<String>,
+    lines: usize,
+}
+
+impl Capture {
+    /// Take the pipes of a command which was spawned with piped stdout and stderr, and keep
+    /// the last `lines` lines of its output
+    pub fn new(child: &mut Child, chain: String, lines: usize) -> Self {
+        let stdout = child
+            .stdout
+            .take()
+            .map(|s| (Stream::Stdout, OwnedFd::from(s)));
+        let stderr = child
+            .stderr
+            .take()
+            .map(|s| (Stream::Stderr, OwnedFd::from(s)));
+        let pipes = stdout
+            .into_iter()
+            .chain(stderr)
+            .filter_map(|(stream, fd)| {
+                let flags = fcntl(fd.as_raw_fd(), FcntlArg::F_GETFL).ok()?;
+                let flags = OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK;
+                fcntl(fd.as_raw_fd(), FcntlArg::F_SETFL(flags)).ok()?;
+                Some(Pipe {
+                    stream,
+                    file: File::from(fd),
+                    partial: vec![],
+                })
+            })
+            .collect();
+        Capture {
+            pid: child.id(),
+            chain,
+            pipes,
+            tail: VecDeque::new(),
+            lines,
+        }
+    }
+
+    /// Read the output which is available, and write its whole lines to the log
+    pub fn read(&mut self, log: &mut CommandLog) {
+        let mut buf = [0; 4096];
+        let mut lines = vec![];
+        self.pipes.retain_mut(|pipe| {
+            let open = loop {
+                match pipe.file.read(&mut buf) {
+                    Ok(0) => break false,
+                    Ok(n) => {
+                        pipe.partial.extend_from_slice(&buf[..n]);
+                        pipe.take_lines(&mut lines);
+                    }
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break true,
+                    Err(_) => break false,
+                }
+            };
+            // The last line does not have to end with a newline
+            if !open && !pipe.partial.is_empty() {
+                lines.push((pipe.stream, std::mem::take(&mut pipe.partial)));
+            }
+            open
+        });
+        for (stream, line) in lines {
+            let line = String::from_utf8_lossy(&line).into_owned();
+            log.write(self.pid, &self.chain, stream, &line);
+            if self.tail.len() == self.lines {
+                self.tail.pop_front();
+            }
+            if self.lines > 0 {
+                self.tail.push_back(line);
+            }
+        }
+    }
+
+    /// Whether the command and everything it started closed their output
+    pub fn is_closed(&self) -> bool {
+        self.pipes.is_empty()
+    }
+
+    /// The last lines of the output read so far
+    pub fn tail(&self) -> Vec<String> {
+        self.tail.iter().cloned().collect()
+    }
+
+    /// The pipes which become readable when the command writes something
+    pub fn fds(&self) -> impl Iterator<Item = BorrowedFd<'_>> {
+        self.pipes.iter().map(|pipe| pipe.file.as_fd())
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+
+use crate::parser::config::Config;
+
+/// How long the config has to be left alone before it is reloaded. Editors often save a file in
+/// several steps, e.g. by renaming the old file, and writing a new one in its place.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// The names a watched directory is interesting for
+struct Directory {
+    path: PathBuf,
+    names: Vec<OsString>,
+    /// Set for the drop-in directory, where any '*.rc' file belongs to the config
+    drop_ins: bool,
+}
+
+impl Directory {
+    fn contains(&self, name: &OsString) -> bool {
+        self.names.contains(name) || (self.drop_ins && name.to_string_lossy().ends_with(".rc"))
+    }
+}
+
+/// Watches the files of a config with inotify, so the config can be reloaded when they change.
+/// The directories containing the files are watched instead of the files themselves, because a
+/// file which is replaced by a rename is a different file.
+pub struct ConfigWatcher {
+    inotify: Inotify,
+    directories: HashMap<WatchDescriptor, Directory>,
+    /// When the last change was seen, if the config has not been reloaded since
+    changed: Option<Instant>,
+}
+
+impl ConfigWatcher {
+    pub fn new() -> nix::Result<Self> {
+        Ok(ConfigWatcher {
+            inotify: Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?,
+            directories: HashMap::new(),
+            changed: None,
+        })
+    }
+
+    /// Watch the files of `config` instead of the files watched so far
+    pub fn watch(&mut self, config: &Config) {
+        for (wd, _) in self.directories.drain() {
+            let _ = self.inotify.rm_watch(wd);
+        }
+
+        let mut directories: Vec<Directory> = vec![];
+        let mut add = |path: PathBuf, name: Option<OsString>, drop_ins: bool| {
+            let directory = match directories.iter_mut().find(|d| d.path == path) {
+                Some(directory) => directory,
+                None => {
+                    directories.push(Directory {
+                        path,
+                        names: vec![],
+                        drop_ins: false,
+                    });
+                    directories.last_mut().unwrap()
+                }
+            };
+            directory.names.extend(name);
+            directory.drop_ins |= drop_ins;
+        };
+        let files = config.files().iter().cloned();
+        // The drop-in directory is also watched from its parent, in case it is created later
+        for file in files.chain(config.drop_in_dir()) {
+            if let (Some(parent), Some(name)) = (file.parent(), file.file_name()) {
+                add(parent.to_path_buf(), Some(name.to_os_string()), false);
+            }
+        }
+        if let Some(dir) = config.drop_in_dir().filter(|d| d.is_dir()) {
+            add(dir, None, true);
+        }
+
+        let flags = AddWatchFlags::IN_CLOSE_WRITE
+            | AddWatchFlags::IN_CREATE
+            | AddWatchFlags::IN_DELETE
+            | AddWatchFlags::IN_MOVED_FROM
+            | AddWatchFlags::IN_MOVED_TO;
+        for directory in directories {
+            match self.inotify.add_watch(&directory.path, flags) {
+                Ok(wd) => {
+                    self.directories.insert(wd, directory);
+                }
+                Err(e) => eprintln!("Failed to watch {}: {}", directory.path.display(), e),
+            }
+        }
+    }
+
+    /// Read the pending inotify events, and note if any of them touched the config
+    pub fn read_events(&mut self) {
+        let Ok(events) = self.inotify.read_events() else {
+            return;
+        };
+        let touched = events.iter().any(|event| {
+            let directory = self.directories.get(&event.wd);
+            matches!((directory, &event.name), (Some(d), Some(name)) if d.contains(name))
+        });
+        if touched {
+            self.changed = Some(Instant::now());
+        }
+    }
+
+    /// The time left until a pending reload is due
+    pub fn pending(&self) -> Option<Duration> {
+        self.changed
+            .map(|changed| DEBOUNCE.saturating_sub(changed.elapsed()))
+    }
+
+    /// Returns `true` once the config has been left alone for long enough after a change
+    pub fn take_reload(&mut self) -> bool {
+        if self.pending().is_some_and(|left| left.is_zero()) {
+            self.changed = None;
+            return true;
+        }
+        false
+    }
+}
+
+impl AsFd for ConfigWatcher {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inotify.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::DefaultKeymap;
+    use crate::parser::config;
+
+    #[test]
+    fn test_watch_config() -> anyhow::Result<()> {
+        let dir = crate::TempDir::new("watch_test")?;
+        let main = dir.join("rhkdrc");
+        std::fs::write(&main, "include extra.rc\n")?;
+        std::fs::write(dir.join("extra.rc"), "a\n  echo a\n")?;
+        let drop_ins = dir.join("rhkdrc.d");
+        let config = config::load_config_with_drop_ins(
+            main.to_str(),
+            Some(drop_ins.clone()),
+            &DefaultKeymap,
+        )?;
+
+        let mut watcher = ConfigWatcher::new()?;
+        watcher.watch(&config);
+        std::fs::write(dir.join("unrelated.txt"), "")?;
+        watcher.read_events();
+        assert_eq!(None, watcher.pending());
+
+        // Replace an included file the way editors do
+        std::fs::write(dir.join("extra.rc.tmp"), "b\n  echo b\n")?;
+        std::fs::rename(dir.join("extra.rc.tmp"), dir.join("extra.rc"))?;
+        watcher.read_events();
+        assert!(watcher.pending().is_some());
+        assert!(!watcher.take_reload());
+        std::thread::sleep(DEBOUNCE);
+        assert!(watcher.take_reload());
+        assert_eq!(None, watcher.pending());
+
+        // Drop-in files are picked up, even if the directory did not exist
+        std::fs::create_dir(&drop_ins)?;
+        watcher.read_events();
+        assert!(watcher.pending().is_some());
+        Ok(())
+    }
+}